@@ -0,0 +1,341 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path as UrlPath, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use crate::{
+    folder::Folder, history::parse_heads, CrdtOperation, DocKind, Error, Manager, Path,
+    PeerPermission, Update,
+};
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        let status = match self {
+            Error::NotFound(_) => StatusCode::NOT_FOUND,
+            Error::PermissionDenied(_) => StatusCode::FORBIDDEN,
+            Error::Unsupported(_) | Error::InvalidInput(_) | Error::Folder(_) => {
+                StatusCode::BAD_REQUEST
+            }
+            Error::Truncated(_) => StatusCode::CONFLICT,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        (status, self.to_string()).into_response()
+    }
+}
+
+type ApiResult = Result<Json<Value>, Error>;
+
+#[derive(Debug, Deserialize)]
+pub struct CreateRequest {
+    pub kind: DocKind,
+    pub name: String,
+    #[serde(default)]
+    pub path: String,
+    #[serde(default)]
+    pub description: String,
+}
+
+// 只修改传了的字段
+#[derive(Debug, Deserialize)]
+pub struct UpdateRequest {
+    // 文件夹里的条目，不传就是修改 doc 自己
+    pub item: Option<Uuid>,
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub path: Option<String>,
+}
+
+// 描述的文本编辑，并发的编辑都会保留
+#[derive(Debug, Deserialize)]
+pub struct SpliceRequest {
+    pub item: Option<Uuid>,
+    pub index: usize,
+    #[serde(default)]
+    pub delete: usize,
+    #[serde(default)]
+    pub insert: String,
+}
+
+// 删除文件夹里的条目，不传就是删除 doc 自己
+#[derive(Debug, Deserialize)]
+pub struct DeleteRequest {
+    pub item: Option<Uuid>,
+}
+
+// 逗号分隔的 change hash
+#[derive(Debug, Deserialize)]
+pub struct HeadsQuery {
+    #[serde(default)]
+    pub heads: String,
+}
+
+// 不传 after 就是和当前版本比较
+#[derive(Debug, Deserialize)]
+pub struct DiffQuery {
+    #[serde(default)]
+    pub before: String,
+    pub after: Option<String>,
+}
+
+// 撤销指定的 change，或者本地最近的 last 个 change
+#[derive(Debug, Deserialize)]
+pub struct RevertRequest {
+    pub change: Option<String>,
+    pub last: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResolveRequest {
+    pub field: String,
+    // 选第几个候选值
+    pub choice: usize,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ShareRequest {
+    pub peer: String,
+    #[serde(default)]
+    pub permission: PeerPermission,
+}
+
+// http 接口，doc 都用 uuid 访问
+pub fn router(manager: Arc<Manager>) -> Router {
+    Router::new()
+        .route("/docs", get(list).post(create))
+        .route("/docs/:id", get(show))
+        .route("/docs/:id/update", post(update))
+        .route("/docs/:id/history", get(history))
+        .route("/docs/:id/at", get(at))
+        .route("/docs/:id/diff", get(diff))
+        .route("/docs/:id/revert", post(revert))
+        .route("/docs/:id/conflicts", get(conflicts))
+        .route("/docs/:id/collisions", get(collisions))
+        .route("/docs/:id/resolve", post(resolve))
+        .route("/docs/:id/splice", post(splice))
+        .route("/docs/:id/delete", post(delete))
+        .route("/docs/:id/purge", post(purge))
+        .route("/docs/:id/share", post(share))
+        .route("/docs/:id/sync", post(sync))
+        .route("/docs/:id/compact", post(compact))
+        .route("/docs/:id/status", get(status))
+        .route("/peers", get(peers))
+        .route("/stats", get(stats))
+        .route("/status", get(statuses))
+        .route("/check", get(check))
+        .route("/reindex", post(reindex))
+        .with_state(manager)
+}
+
+async fn list(State(manager): State<Arc<Manager>>) -> ApiResult {
+    Ok(Json(json!(manager.list())))
+}
+
+async fn create(State(manager): State<Arc<Manager>>, Json(req): Json<CreateRequest>) -> ApiResult {
+    let id = match req.kind {
+        DocKind::Path => manager.create_path(&req.name, &req.path, &req.description)?,
+        DocKind::Folder => manager.create_folder(&Folder::new(&req.name, &req.description))?,
+        // 记录的字段由类型决定，不能这样新建
+        DocKind::Record => return Err(Error::InvalidInput("kind Record".to_string())),
+    };
+    Ok(Json(json!({ "id": id })))
+}
+
+async fn show(State(manager): State<Arc<Manager>>, UrlPath(id): UrlPath<Uuid>) -> ApiResult {
+    match manager.kind(&id)? {
+        DocKind::Path => Ok(Json(json!(manager.path(&id)?))),
+        DocKind::Folder => Ok(Json(json!(manager.folder(&id)?))),
+        DocKind::Record => Ok(Json(manager.record_json(&id)?)),
+    }
+}
+
+async fn update(
+    State(manager): State<Arc<Manager>>,
+    UrlPath(id): UrlPath<Uuid>,
+    Json(req): Json<UpdateRequest>,
+) -> ApiResult {
+    let item = req.item.unwrap_or(id);
+    let ops: Vec<_> = [
+        req.name.map(Update::Name),
+        req.description.map(Update::Description),
+        req.path.map(Update::Path),
+    ]
+    .into_iter()
+    .flatten()
+    .map(|update| CrdtOperation::Update(item, update))
+    .collect();
+    // 几个字段合成一个 change，失败的字段丢掉，其他的照样提交
+    let failed = manager.update_all(&id, &ops)?;
+    manager.sync(&id).await?;
+    if let Some(e) = failed.into_iter().next() {
+        return Err(e);
+    }
+    show(State(manager), UrlPath(id)).await
+}
+
+// 本地修改之后马上发给分享过的 peer
+async fn synced(manager: Arc<Manager>, id: Uuid) -> ApiResult {
+    manager.sync(&id).await?;
+    show(State(manager), UrlPath(id)).await
+}
+
+async fn splice(
+    State(manager): State<Arc<Manager>>,
+    UrlPath(id): UrlPath<Uuid>,
+    Json(req): Json<SpliceRequest>,
+) -> ApiResult {
+    let update = Update::SpliceDescription {
+        index: req.index,
+        delete: req.delete,
+        insert: req.insert,
+    };
+    manager.update(&id, CrdtOperation::Update(req.item.unwrap_or(id), update))?;
+    synced(manager, id).await
+}
+
+// 删除后保留 tombstone，doc 还在
+async fn delete(
+    State(manager): State<Arc<Manager>>,
+    UrlPath(id): UrlPath<Uuid>,
+    Json(req): Json<DeleteRequest>,
+) -> ApiResult {
+    manager.update(&id, CrdtOperation::Delete(req.item.unwrap_or(id)))?;
+    synced(manager, id).await
+}
+
+// 清除分享过的 peer 都收到了的删除记录
+async fn purge(State(manager): State<Arc<Manager>>, UrlPath(id): UrlPath<Uuid>) -> ApiResult {
+    let purged = manager.purge(&id)?;
+    Ok(Json(json!({ "purged": purged })))
+}
+
+async fn history(State(manager): State<Arc<Manager>>, UrlPath(id): UrlPath<Uuid>) -> ApiResult {
+    Ok(Json(json!(manager.history(&id)?)))
+}
+
+async fn at(
+    State(manager): State<Arc<Manager>>,
+    UrlPath(id): UrlPath<Uuid>,
+    Query(query): Query<HeadsQuery>,
+) -> ApiResult {
+    let heads = parse_heads(&query.heads)?;
+    match manager.kind(&id)? {
+        DocKind::Path => Ok(Json(json!(manager.hydrate_at::<Path>(&id, &heads)?))),
+        DocKind::Folder => Ok(Json(json!(manager.folder_at(&id, &heads)?))),
+        DocKind::Record => Err(Error::Unsupported(id)),
+    }
+}
+
+async fn diff(
+    State(manager): State<Arc<Manager>>,
+    UrlPath(id): UrlPath<Uuid>,
+    Query(query): Query<DiffQuery>,
+) -> ApiResult {
+    let before = parse_heads(&query.before)?;
+    let after = match query.after {
+        Some(after) => parse_heads(&after)?,
+        None => manager.heads(&id)?,
+    };
+    Ok(Json(json!(manager.diff(&id, &before, &after)?)))
+}
+
+async fn revert(
+    State(manager): State<Arc<Manager>>,
+    UrlPath(id): UrlPath<Uuid>,
+    Json(req): Json<RevertRequest>,
+) -> ApiResult {
+    match (req.change, req.last) {
+        (Some(change), None) => manager.revert(&id, &parse_heads(&change)?)?,
+        (None, Some(last)) => manager.undo(&id, last)?,
+        _ => return Err(Error::InvalidInput("one of change or last".to_string())),
+    };
+    synced(manager, id).await
+}
+
+async fn conflicts(State(manager): State<Arc<Manager>>, UrlPath(id): UrlPath<Uuid>) -> ApiResult {
+    Ok(Json(json!(manager.conflicts(&id)?)))
+}
+
+// 文件夹里重名、读出来时改了名字的条目
+async fn collisions(State(manager): State<Arc<Manager>>, UrlPath(id): UrlPath<Uuid>) -> ApiResult {
+    Ok(Json(json!(manager.collisions(&id)?)))
+}
+
+async fn resolve(
+    State(manager): State<Arc<Manager>>,
+    UrlPath(id): UrlPath<Uuid>,
+    Json(req): Json<ResolveRequest>,
+) -> ApiResult {
+    manager.resolve(&id, &req.field, req.choice)?;
+    synced(manager, id).await
+}
+
+async fn share(
+    State(manager): State<Arc<Manager>>,
+    UrlPath(id): UrlPath<Uuid>,
+    Json(req): Json<ShareRequest>,
+) -> ApiResult {
+    let peer = req
+        .peer
+        .parse()
+        .map_err(|_| Error::InvalidInput(format!("peer id {}", req.peer)))?;
+    manager.share(&id, peer, req.permission)?;
+    let sent = manager.sync(&id).await?;
+    Ok(Json(json!({ "sent": sent })))
+}
+
+async fn sync(State(manager): State<Arc<Manager>>, UrlPath(id): UrlPath<Uuid>) -> ApiResult {
+    let sent = manager.sync(&id).await?;
+    Ok(Json(json!({ "sent": sent })))
+}
+
+// 截断了历史的话，马上发给分享过的 peer
+async fn compact(State(manager): State<Arc<Manager>>, UrlPath(id): UrlPath<Uuid>) -> ApiResult {
+    let truncated = manager.compact(&id)?;
+    let sent = if truncated {
+        manager.sync(&id).await?
+    } else {
+        0
+    };
+    Ok(Json(json!({ "truncated": truncated, "sent": sent })))
+}
+
+// 每个分享过的 peer 是不是已经有了全部的修改
+async fn status(State(manager): State<Arc<Manager>>, UrlPath(id): UrlPath<Uuid>) -> ApiResult {
+    Ok(Json(json!(manager.status(&id)?)))
+}
+
+async fn statuses(State(manager): State<Arc<Manager>>) -> ApiResult {
+    Ok(Json(json!(manager.statuses())))
+}
+
+async fn peers(State(manager): State<Arc<Manager>>) -> ApiResult {
+    let peers: Vec<String> = manager
+        .known_peers()
+        .iter()
+        .map(|p| p.to_string())
+        .collect();
+    Ok(Json(json!(peers)))
+}
+
+// 收到的 doc 排队和合并的情况
+async fn stats(State(manager): State<Arc<Manager>>) -> ApiResult {
+    Ok(Json(json!(manager.incoming_stats())))
+}
+
+// 数据库里和 doc 不一致的行
+async fn check(State(manager): State<Arc<Manager>>) -> ApiResult {
+    Ok(Json(json!(manager.check()?)))
+}
+
+async fn reindex(State(manager): State<Arc<Manager>>) -> ApiResult {
+    let docs = manager.reindex()?;
+    Ok(Json(json!({ "docs": docs })))
+}
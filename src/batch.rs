@@ -0,0 +1,90 @@
+use std::{
+    collections::BTreeMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use uuid::Uuid;
+
+use crate::{CrdtOperation, Error};
+
+// 本地的修改先攒起来：第一个修改之后等 window，或者攒够 max_ops 个，
+// 同一个 doc 的修改合成一个 change，只同步一次
+#[derive(Debug, Clone)]
+pub struct BatchPolicy {
+    pub window: Duration,
+    pub max_ops: usize,
+}
+
+impl Default for BatchPolicy {
+    fn default() -> Self {
+        BatchPolicy {
+            window: Duration::from_millis(200),
+            max_ops: 64,
+        }
+    }
+}
+
+// 一个 doc 攒的修改提交之后的结果
+#[derive(Debug)]
+pub struct Flushed {
+    pub id: Uuid,
+    // 提交了几个修改
+    pub applied: usize,
+    // 丢掉的修改，以及提交或者同步失败的原因
+    pub failed: Vec<Error>,
+    // 发给了几个 peer
+    pub sent: usize,
+}
+
+#[derive(Default)]
+struct Pending {
+    ops: BTreeMap<Uuid, Vec<CrdtOperation>>,
+    // 第一个修改加入的时间
+    since: Option<Instant>,
+}
+
+// 还没有提交的本地修改
+pub struct Batch {
+    pub policy: BatchPolicy,
+    pending: Mutex<Pending>,
+}
+
+impl Batch {
+    pub fn new(policy: BatchPolicy) -> Self {
+        Batch {
+            policy,
+            pending: Mutex::new(Pending::default()),
+        }
+    }
+
+    // 加入一个修改，攒够 max_ops 个返回 true，应该马上提交
+    pub fn push(&self, id: Uuid, op: CrdtOperation) -> bool {
+        let mut pending = self.pending.lock().unwrap();
+        pending.since.get_or_insert_with(Instant::now);
+        pending.ops.entry(id).or_default().push(op);
+        pending.ops.values().map(Vec::len).sum::<usize>() >= self.policy.max_ops
+    }
+
+    // 应该提交的时间，没有修改是 None
+    pub fn due(&self) -> Option<Instant> {
+        let pending = self.pending.lock().unwrap();
+        pending.since.map(|since| since + self.policy.window)
+    }
+
+    pub fn len(&self) -> usize {
+        let pending = self.pending.lock().unwrap();
+        pending.ops.values().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // 取出所有攒的修改
+    pub fn take(&self) -> BTreeMap<Uuid, Vec<CrdtOperation>> {
+        let mut pending = self.pending.lock().unwrap();
+        pending.since = None;
+        std::mem::take(&mut pending.ops)
+    }
+}
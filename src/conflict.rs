@@ -0,0 +1,163 @@
+use automerge::{
+    transaction::Transactable, AutoCommit, ObjId, ObjType, Prop, ReadDoc, ScalarValue, Value,
+};
+use serde::Serialize;
+use serde_json::json;
+
+use crate::Error;
+
+// 一个并发修改产生的候选值
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Candidate {
+    pub value: serde_json::Value,
+    // 修改它的 actor
+    pub actor: String,
+    // 所在 change 的时间，毫秒
+    pub timestamp: i64,
+    // hydrate 时用的是这个值
+    pub winner: bool,
+}
+
+// 有冲突的字段，field 是从根开始的路径，例如 "name"、"nodes/<pub_id>/folder/name"
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FieldConflict {
+    pub field: String,
+    pub candidates: Vec<Candidate>,
+}
+
+fn scalar_json(value: &ScalarValue) -> serde_json::Value {
+    match value {
+        ScalarValue::Str(s) => json!(s.as_str()),
+        ScalarValue::Int(i) | ScalarValue::Timestamp(i) => json!(i),
+        ScalarValue::Uint(u) => json!(u),
+        ScalarValue::F64(f) => json!(f),
+        ScalarValue::Boolean(b) => json!(b),
+        ScalarValue::Null => serde_json::Value::Null,
+        ScalarValue::Bytes(b) => json!(hex::encode(b)),
+        other => json!(other.to_string()),
+    }
+}
+
+pub(crate) fn value_json(doc: &AutoCommit, value: &Value, id: &ObjId) -> serde_json::Value {
+    match value {
+        Value::Scalar(s) => scalar_json(s),
+        Value::Object(ObjType::Text) => json!(doc.text(id).unwrap_or_default()),
+        Value::Object(kind) => json!(format!("<{kind}>")),
+    }
+}
+
+fn props(doc: &AutoCommit, obj: &ObjId) -> Vec<Prop> {
+    match doc.object_type(obj) {
+        Ok(ObjType::Map | ObjType::Table) => doc.keys(obj).map(Prop::Map).collect(),
+        Ok(ObjType::List) => (0..doc.length(obj)).map(Prop::Seq).collect(),
+        _ => vec![],
+    }
+}
+
+pub(crate) fn join(parent: &str, prop: &Prop) -> String {
+    let prop = match prop {
+        Prop::Map(key) => key.clone(),
+        Prop::Seq(index) => index.to_string(),
+    };
+    if parent.is_empty() {
+        prop
+    } else {
+        format!("{parent}/{prop}")
+    }
+}
+
+// 列出 doc 里所有有冲突的字段
+pub fn conflicts(doc: &mut AutoCommit) -> Result<Vec<FieldConflict>, Error> {
+    let mut found = vec![];
+    let mut stack = vec![(automerge::ROOT, String::new())];
+    while let Some((obj, path)) = stack.pop() {
+        for prop in props(doc, &obj) {
+            let field = join(&path, &prop);
+            let values: Vec<_> = doc
+                .get_all(&obj, prop)?
+                .into_iter()
+                .map(|(value, id)| (value.into_owned(), id))
+                .collect();
+            if values.len() > 1 {
+                let mut candidates = vec![];
+                for (i, (value, id)) in values.iter().enumerate() {
+                    let change = doc.hash_for_opid(id);
+                    let timestamp = change
+                        .and_then(|hash| doc.get_change_by_hash(&hash))
+                        .map(|c| c.timestamp())
+                        .unwrap_or_default();
+                    let actor = match id {
+                        ObjId::Id(_, actor, _) => actor.to_hex_string(),
+                        ObjId::Root => String::new(),
+                    };
+                    candidates.push(Candidate {
+                        value: value_json(doc, value, id),
+                        actor,
+                        timestamp,
+                        winner: i == values.len() - 1,
+                    });
+                }
+                found.push(FieldConflict {
+                    field: field.clone(),
+                    candidates,
+                });
+            }
+            // 只往下看胜出的对象
+            if let Some((Value::Object(_), id)) = values.last() {
+                stack.push((id.clone(), field));
+            }
+        }
+    }
+    found.sort_by(|a, b| a.field.cmp(&b.field));
+    Ok(found)
+}
+
+// 按路径找到字段所在的对象
+pub(crate) fn locate(doc: &AutoCommit, field: &str) -> Result<(ObjId, Prop), Error> {
+    let invalid = || Error::InvalidInput(format!("field {field}"));
+    let mut obj = automerge::ROOT;
+    let mut parts = field.split('/').peekable();
+    while let Some(part) = parts.next() {
+        let prop = match doc.object_type(&obj) {
+            Ok(ObjType::List) => Prop::Seq(part.parse().map_err(|_| invalid())?),
+            Ok(ObjType::Map | ObjType::Table) => Prop::Map(part.to_string()),
+            _ => return Err(invalid()),
+        };
+        if parts.peek().is_none() {
+            return Ok((obj, prop));
+        }
+        match doc.get(&obj, prop)? {
+            Some((Value::Object(_), id)) => obj = id,
+            _ => return Err(invalid()),
+        }
+    }
+    Err(invalid())
+}
+
+// 选择一个候选值，写入之后冲突就没有了
+pub fn resolve(doc: &mut AutoCommit, field: &str, choice: usize) -> Result<(), Error> {
+    let (obj, prop) = locate(doc, field)?;
+    let values: Vec<_> = doc
+        .get_all(&obj, prop.clone())?
+        .into_iter()
+        .map(|(value, id)| (value.into_owned(), id))
+        .collect();
+    if values.len() < 2 {
+        return Err(Error::InvalidInput(format!(
+            "field {field} has no conflict"
+        )));
+    }
+    let (value, id) = values
+        .get(choice)
+        .ok_or_else(|| Error::InvalidInput(format!("choice {choice}")))?;
+    match value {
+        Value::Scalar(s) => doc.put(&obj, prop, s.as_ref().clone())?,
+        Value::Object(ObjType::Text) => {
+            let text = doc.text(id)?;
+            let new = doc.put_object(&obj, prop, ObjType::Text)?;
+            doc.splice_text(&new, 0, 0, &text)?;
+        }
+        Value::Object(_) => return Err(Error::InvalidInput(format!("field {field}"))),
+    }
+    Ok(())
+}
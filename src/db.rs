@@ -0,0 +1,676 @@
+use std::collections::{BTreeMap, HashSet};
+
+use automerge::ChangeHash;
+use serde::Serialize;
+use sqlite::{Connection, State, Value};
+use uuid::Uuid;
+
+use crate::{
+    folder::{AssetObject, Folder},
+    record::SyncRecord,
+    Deleted,
+};
+
+// 把共享文件夹写入数据库，先删除这个共享文件夹之前的记录再全部插入
+pub fn sync_folder_db(conn: &Connection, root: &Folder) -> sqlite::Result<()> {
+    conn.execute("BEGIN")?;
+    match write_folder(conn, root) {
+        Ok(()) => conn.execute("COMMIT"),
+        Err(e) => {
+            conn.execute("ROLLBACK")?;
+            Err(e)
+        }
+    }
+}
+
+fn write_folder(conn: &Connection, root: &Folder) -> sqlite::Result<()> {
+    let root_id = root.pub_id.to_string();
+    delete_folder_rows(conn, &root_id)?;
+
+    // 并发删除之后同一个条目可能出现几次，只写第一次，树里的条目先写
+    let mut seen = HashSet::new();
+    let mut result = Ok(());
+    root.walk(&mut |parent, folder| {
+        if result.is_err() {
+            return;
+        }
+        let parent = parent.map(|p| p.to_string());
+        result = insert_folder_row(conn, &root_id, parent, folder, None, &mut seen);
+    });
+    result?;
+
+    // 删除了的条目也写入，带上删除的时间和 actor
+    for tombstone in root.tombstones.iter() {
+        let deleted = Some(&tombstone.deleted);
+        if let Some(file) = &tombstone.file {
+            let parent = tombstone.parent.to_string();
+            insert_file_row(conn, &root_id, &parent, file, deleted, &mut seen)?;
+        }
+        if let Some(folder) = &tombstone.folder {
+            let mut result = Ok(());
+            folder.walk(&mut |parent, f| {
+                if result.is_err() {
+                    return;
+                }
+                let parent = parent.unwrap_or(&tombstone.parent).to_string();
+                result = insert_folder_row(conn, &root_id, Some(parent), f, deleted, &mut seen);
+            });
+            result?;
+        }
+    }
+    Ok(())
+}
+
+// 删除一个共享文件夹的所有记录
+pub fn delete_folder_rows(conn: &Connection, root_id: &str) -> sqlite::Result<()> {
+    for table in ["folders", "files"] {
+        let mut stmt = conn.prepare(format!("DELETE FROM {table} WHERE root_pub_id = ?"))?;
+        stmt.bind((1, root_id))?;
+        stmt.next()?;
+    }
+    Ok(())
+}
+
+fn deleted_values(deleted: Option<&Deleted>) -> [Value; 2] {
+    match deleted {
+        Some(d) => [Value::from(d.time), Value::from(d.actor.as_str())],
+        None => [Value::Null, Value::Null],
+    }
+}
+
+fn insert_folder_row(
+    conn: &Connection,
+    root_id: &str,
+    parent_id: Option<String>,
+    folder: &Folder,
+    deleted: Option<&Deleted>,
+    seen: &mut HashSet<Uuid>,
+) -> sqlite::Result<()> {
+    let folder_id = folder.pub_id.to_string();
+    for file in folder.files.iter() {
+        insert_file_row(conn, root_id, &folder_id, file, deleted, seen)?;
+    }
+    if !seen.insert(folder.pub_id) {
+        return Ok(());
+    }
+    let mut stmt = conn.prepare(
+        "INSERT INTO folders (pub_id, root_pub_id, parent_pub_id, name, description, materialized_path, deleted_at, deleted_by)
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+    )?;
+    let [deleted_at, deleted_by] = deleted_values(deleted);
+    stmt.bind(
+        &[
+            Value::from(folder_id.as_str()),
+            Value::from(root_id),
+            Value::from(parent_id),
+            Value::from(folder.name.as_str()),
+            Value::from(folder.description.as_str()),
+            Value::from(folder.materialized_path.as_str()),
+            deleted_at,
+            deleted_by,
+        ][..],
+    )?;
+    stmt.next()?;
+    Ok(())
+}
+
+fn insert_file_row(
+    conn: &Connection,
+    root_id: &str,
+    folder_id: &str,
+    file: &AssetObject,
+    deleted: Option<&Deleted>,
+    seen: &mut HashSet<Uuid>,
+) -> sqlite::Result<()> {
+    if !seen.insert(file.pub_id) {
+        return Ok(());
+    }
+    let mut stmt = conn.prepare(
+        "INSERT INTO files (pub_id, root_pub_id, folder_pub_id, name, description, materialized_path, hash, size, mime_type, deleted_at, deleted_by)
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+    )?;
+    let [deleted_at, deleted_by] = deleted_values(deleted);
+    stmt.bind(
+        &[
+            Value::from(file.pub_id.to_string()),
+            Value::from(root_id),
+            Value::from(folder_id),
+            Value::from(file.file_path.name.as_str()),
+            Value::from(file.file_path.description.as_str()),
+            Value::from(file.file_path.materialized_path.as_str()),
+            Value::from(file.hash.as_str()),
+            Value::from(file.size as i64),
+            Value::from(file.mime_type.as_str()),
+            deleted_at,
+            deleted_by,
+        ][..],
+    )?;
+    stmt.next()?;
+    Ok(())
+}
+
+// 每个物化的表都有的删除时间和删除的 actor，没有删除是 NULL
+const DELETED_COLUMNS: [&str; 2] = ["deleted_at", "deleted_by"];
+
+// 建一个记录的表，已经有了就不变，只补上缺少的删除列。列不指定类型，按写入的值存
+pub fn ensure_table(conn: &Connection, table: &str, columns: &[&str]) -> sqlite::Result<()> {
+    let columns: Vec<String> = columns.iter().map(|c| format!("{c} NOT NULL")).collect();
+    conn.execute(format!(
+        "CREATE TABLE IF NOT EXISTS {table} (
+            id INTEGER PRIMARY KEY,
+            pub_id TEXT NOT NULL UNIQUE,
+            {},
+            deleted_at INTEGER,
+            deleted_by TEXT
+        )",
+        columns.join(", ")
+    ))?;
+    let mut stmt = conn.prepare(format!("SELECT name FROM pragma_table_info('{table}')"))?;
+    let mut existing = vec![];
+    while let State::Row = stmt.next()? {
+        existing.push(stmt.read::<String, _>(0)?);
+    }
+    for column in DELETED_COLUMNS {
+        if !existing.iter().any(|c| c == column) {
+            conn.execute(format!("ALTER TABLE {table} ADD COLUMN {column}"))?;
+        }
+    }
+    Ok(())
+}
+
+// 记录的列加上删除列
+fn record_values<T: SyncRecord>(record: &T) -> Vec<Value> {
+    let mut values = record.values();
+    values.extend(deleted_values(record.deleted()));
+    values
+}
+
+// 写入一条记录，已经存在就更新
+pub fn write_record<T: SyncRecord>(conn: &Connection, record: &T) -> sqlite::Result<()> {
+    let pub_id = Value::from(record.pub_id().to_string());
+    let columns: Vec<&str> = T::COLUMNS.iter().copied().chain(DELETED_COLUMNS).collect();
+    let assign: Vec<String> = columns.iter().map(|c| format!("{c} = ?")).collect();
+    let mut stmt = conn.prepare(format!(
+        "UPDATE {} SET {} WHERE pub_id = ?",
+        T::TABLE,
+        assign.join(", ")
+    ))?;
+    let mut values = record_values(record);
+    values.push(pub_id.clone());
+    stmt.bind(&values[..])?;
+    stmt.next()?;
+    if conn.change_count() > 0 {
+        return Ok(());
+    }
+
+    let mut stmt = conn.prepare(format!(
+        "INSERT INTO {} (pub_id, {}) VALUES (?{})",
+        T::TABLE,
+        columns.join(", "),
+        ", ?".repeat(columns.len())
+    ))?;
+    let mut values = record_values(record);
+    values.insert(0, pub_id);
+    stmt.bind(&values[..])?;
+    stmt.next()?;
+    Ok(())
+}
+
+fn read_record<T: SyncRecord>(stmt: &mut sqlite::Statement) -> sqlite::Result<Option<T>> {
+    let pub_id: String = stmt.read(1)?;
+    let values = (0..T::COLUMNS.len())
+        .map(|i| stmt.read::<Value, _>(i + 2))
+        .collect::<sqlite::Result<Vec<_>>>()?;
+    Ok(T::from_values(pub_id.parse().unwrap_or_default(), values))
+}
+
+// 查询表里所有的记录，和行的 id 一起返回，读不出来的行和删除了的行跳过
+pub fn query_records<T: SyncRecord>(conn: &Connection) -> sqlite::Result<Vec<(i64, T)>> {
+    let mut stmt = conn.prepare(format!(
+        "SELECT id, pub_id, {} FROM {} WHERE deleted_at IS NULL",
+        T::COLUMNS.join(", "),
+        T::TABLE
+    ))?;
+    let mut records = vec![];
+    while let State::Row = stmt.next()? {
+        let id = stmt.read(0)?;
+        if let Some(record) = read_record(&mut stmt)? {
+            records.push((id, record));
+        }
+    }
+    Ok(records)
+}
+
+// 查询一条没有删除的记录
+pub fn query_record<T: SyncRecord>(conn: &Connection, pub_id: &str) -> sqlite::Result<Option<T>> {
+    let mut stmt = conn.prepare(format!(
+        "SELECT id, pub_id, {} FROM {} WHERE pub_id = ? AND deleted_at IS NULL",
+        T::COLUMNS.join(", "),
+        T::TABLE
+    ))?;
+    stmt.bind((1, pub_id))?;
+    if let State::Row = stmt.next()? {
+        return read_record(&mut stmt);
+    }
+    Ok(None)
+}
+
+// 记录 doc 写入数据库时的 heads，多个 head 用逗号分隔
+pub fn sync_doc_heads(
+    conn: &Connection,
+    pub_id: &str,
+    kind: &str,
+    heads: &[ChangeHash],
+) -> sqlite::Result<()> {
+    let heads: Vec<String> = heads.iter().map(|h| h.to_string()).collect();
+    let mut stmt =
+        conn.prepare("INSERT OR REPLACE INTO docs (pub_id, kind, heads) VALUES (?, ?, ?)")?;
+    stmt.bind(
+        &[
+            Value::from(pub_id),
+            Value::from(kind),
+            Value::from(heads.join(",")),
+        ][..],
+    )?;
+    stmt.next()?;
+    Ok(())
+}
+
+// 查询 doc 写入数据库时的 heads
+pub fn query_doc_heads(conn: &Connection, pub_id: &str) -> sqlite::Result<Option<Vec<String>>> {
+    let mut stmt = conn.prepare("SELECT heads FROM docs WHERE pub_id = ?")?;
+    stmt.bind((1, pub_id))?;
+    if let State::Row = stmt.next()? {
+        let heads: String = stmt.read(0)?;
+        let heads = heads
+            .split(',')
+            .filter(|h| !h.is_empty())
+            .map(|h| h.to_string())
+            .collect();
+        return Ok(Some(heads));
+    }
+    Ok(None)
+}
+
+// 存储的 doc，kind、permission 和 peers 是 json
+pub struct StoredDoc {
+    pub pub_id: String,
+    pub kind: String,
+    pub permission: String,
+    pub peers: String,
+    pub actor: String,
+    // 截断历史的次数
+    pub epoch: i64,
+    // 上一次截断历史时的 heads，以及截断后的 doc 里内容和它一样的 heads，逗号分隔
+    pub base: String,
+    pub rebased: String,
+    pub snapshot: Vec<u8>,
+    // 快照之后的 change，按写入顺序
+    pub changes: Vec<Vec<u8>>,
+}
+
+// 一个 automerge 的 change
+pub struct StoredChange {
+    pub hash: String,
+    pub actor: String,
+    pub seq: u64,
+    pub bytes: Vec<u8>,
+}
+
+impl From<&automerge::Change> for StoredChange {
+    fn from(change: &automerge::Change) -> Self {
+        StoredChange {
+            hash: change.hash().to_string(),
+            actor: change.actor_id().to_hex_string(),
+            seq: change.seq(),
+            bytes: change.raw_bytes().to_vec(),
+        }
+    }
+}
+
+// 写入 doc 的快照，快照里已经有的 change 都删掉
+pub fn save_snapshot(conn: &Connection, doc: &StoredDoc) -> sqlite::Result<()> {
+    conn.execute("BEGIN")?;
+    match write_snapshot(conn, doc) {
+        Ok(()) => conn.execute("COMMIT"),
+        Err(e) => {
+            conn.execute("ROLLBACK")?;
+            Err(e)
+        }
+    }
+}
+
+fn write_snapshot(conn: &Connection, doc: &StoredDoc) -> sqlite::Result<()> {
+    let mut stmt = conn.prepare(
+        "INSERT OR REPLACE INTO doc_snapshots (pub_id, kind, permission, peers, actor, epoch, base, rebased, data)
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+    )?;
+    stmt.bind(
+        &[
+            Value::from(doc.pub_id.as_str()),
+            Value::from(doc.kind.as_str()),
+            Value::from(doc.permission.as_str()),
+            Value::from(doc.peers.as_str()),
+            Value::from(doc.actor.as_str()),
+            Value::from(doc.epoch),
+            Value::from(doc.base.as_str()),
+            Value::from(doc.rebased.as_str()),
+            Value::from(doc.snapshot.as_slice()),
+        ][..],
+    )?;
+    stmt.next()?;
+    let mut stmt = conn.prepare("DELETE FROM changes WHERE doc_id = ?")?;
+    stmt.bind((1, doc.pub_id.as_str()))?;
+    stmt.next()?;
+    Ok(())
+}
+
+// 追加 change，在一个事务里写入。已经写过的 change 会跳过，重复写入没有影响
+pub fn append_changes(
+    conn: &Connection,
+    doc_id: &str,
+    changes: &[StoredChange],
+) -> sqlite::Result<()> {
+    conn.execute("BEGIN")?;
+    match write_changes(conn, doc_id, changes) {
+        Ok(()) => conn.execute("COMMIT"),
+        Err(e) => {
+            conn.execute("ROLLBACK")?;
+            Err(e)
+        }
+    }
+}
+
+pub(crate) fn write_changes(
+    conn: &Connection,
+    doc_id: &str,
+    changes: &[StoredChange],
+) -> sqlite::Result<()> {
+    for change in changes {
+        let mut stmt = conn.prepare(
+            "INSERT OR IGNORE INTO changes (doc_id, hash, actor, seq, bytes) VALUES (?, ?, ?, ?, ?)",
+        )?;
+        stmt.bind(
+            &[
+                Value::from(doc_id),
+                Value::from(change.hash.as_str()),
+                Value::from(change.actor.as_str()),
+                Value::from(change.seq as i64),
+                Value::from(change.bytes.as_slice()),
+            ][..],
+        )?;
+        stmt.next()?;
+    }
+    Ok(())
+}
+
+// 更新 doc 的权限和分享的 peer
+pub fn update_doc_peers(
+    conn: &Connection,
+    pub_id: &str,
+    permission: &str,
+    peers: &str,
+) -> sqlite::Result<()> {
+    let mut stmt =
+        conn.prepare("UPDATE doc_snapshots SET permission = ?, peers = ? WHERE pub_id = ?")?;
+    stmt.bind(
+        &[
+            Value::from(permission),
+            Value::from(peers),
+            Value::from(pub_id),
+        ][..],
+    )?;
+    stmt.next()?;
+    Ok(())
+}
+
+// 去掉一个 doc 的存储、heads 和物化出来的行，记下清除过这个 doc
+pub fn delete_doc(conn: &Connection, pub_id: &str, table: &str, time: i64) -> sqlite::Result<()> {
+    conn.execute("BEGIN")?;
+    match delete_doc_rows(conn, pub_id, table, time) {
+        Ok(()) => conn.execute("COMMIT"),
+        Err(e) => {
+            conn.execute("ROLLBACK")?;
+            Err(e)
+        }
+    }
+}
+
+fn delete_doc_rows(conn: &Connection, pub_id: &str, table: &str, time: i64) -> sqlite::Result<()> {
+    for sql in [
+        "DELETE FROM doc_snapshots WHERE pub_id = ?".to_string(),
+        "DELETE FROM changes WHERE doc_id = ?".to_string(),
+        "DELETE FROM docs WHERE pub_id = ?".to_string(),
+        format!("DELETE FROM {table} WHERE pub_id = ?"),
+    ] {
+        let mut stmt = conn.prepare(sql)?;
+        stmt.bind((1, pub_id))?;
+        stmt.next()?;
+    }
+    let mut stmt =
+        conn.prepare("INSERT OR REPLACE INTO purged_docs (pub_id, purged_at) VALUES (?, ?)")?;
+    stmt.bind(&[Value::from(pub_id), Value::from(time)][..])?;
+    stmt.next()?;
+    Ok(())
+}
+
+// 这个 doc 是不是清除过
+pub fn is_purged(conn: &Connection, pub_id: &str) -> sqlite::Result<bool> {
+    let mut stmt = conn.prepare("SELECT COUNT(*) FROM purged_docs WHERE pub_id = ?")?;
+    stmt.bind((1, pub_id))?;
+    stmt.next()?;
+    Ok(stmt.read::<i64, _>(0)? > 0)
+}
+
+// 记录的 doc 整个删除了，物化出来的行填上删除列
+pub fn mark_deleted(
+    conn: &Connection,
+    table: &str,
+    pub_id: &str,
+    deleted: &Deleted,
+) -> sqlite::Result<()> {
+    let mut stmt = conn.prepare(format!(
+        "UPDATE {table} SET deleted_at = ?, deleted_by = ? WHERE pub_id = ?"
+    ))?;
+    let [time, actor] = deleted_values(Some(deleted));
+    stmt.bind(&[time, actor, Value::from(pub_id)][..])?;
+    stmt.next()?;
+    Ok(())
+}
+
+// 快照之后的 change 数量
+pub fn count_changes(conn: &Connection, doc_id: &str) -> sqlite::Result<usize> {
+    let mut stmt = conn.prepare("SELECT COUNT(*) FROM changes WHERE doc_id = ?")?;
+    stmt.bind((1, doc_id))?;
+    stmt.next()?;
+    Ok(stmt.read::<i64, _>(0)? as usize)
+}
+
+// 读取所有存储的 doc
+pub fn load_docs(conn: &Connection) -> sqlite::Result<Vec<StoredDoc>> {
+    let mut stmt = conn.prepare(
+        "SELECT pub_id, kind, permission, peers, actor, epoch, base, rebased, data FROM doc_snapshots",
+    )?;
+    let mut docs = vec![];
+    while let State::Row = stmt.next()? {
+        docs.push(StoredDoc {
+            pub_id: stmt.read(0)?,
+            kind: stmt.read(1)?,
+            permission: stmt.read(2)?,
+            peers: stmt.read(3)?,
+            actor: stmt.read(4)?,
+            epoch: stmt.read(5)?,
+            base: stmt.read(6)?,
+            rebased: stmt.read(7)?,
+            snapshot: stmt.read(8)?,
+            changes: vec![],
+        });
+    }
+    // 只读快照之后的 change
+    for doc in docs.iter_mut() {
+        let mut stmt = conn.prepare("SELECT bytes FROM changes WHERE doc_id = ? ORDER BY id")?;
+        stmt.bind((1, doc.pub_id.as_str()))?;
+        while let State::Row = stmt.next()? {
+            doc.changes.push(stmt.read(0)?);
+        }
+    }
+    Ok(docs)
+}
+
+// 从 doc 物化出来的表，以及用来比较的列，都按 pub_id 找到对应的行
+pub const MATERIALIZED: &[(&str, &[&str])] = &[
+    (
+        "paths",
+        &["name", "path", "description", "deleted_at", "deleted_by"],
+    ),
+    (
+        "folders",
+        &[
+            "root_pub_id",
+            "parent_pub_id",
+            "name",
+            "description",
+            "materialized_path",
+            "deleted_at",
+            "deleted_by",
+        ],
+    ),
+    (
+        "files",
+        &[
+            "root_pub_id",
+            "folder_pub_id",
+            "name",
+            "description",
+            "materialized_path",
+            "hash",
+            "size",
+            "mime_type",
+            "deleted_at",
+            "deleted_by",
+        ],
+    ),
+    ("docs", &["kind", "heads"]),
+];
+
+// 一个表的所有行，pub_id -> 列 -> 值，pub_id 重复的行都放在一起
+pub type Rows = BTreeMap<String, Vec<BTreeMap<String, String>>>;
+
+// 删除所有物化的记录
+pub fn clear_materialized(conn: &Connection, tables: &[&str]) -> sqlite::Result<()> {
+    conn.execute("BEGIN")?;
+    for table in tables {
+        if let Err(e) = conn.execute(format!("DELETE FROM {table}")) {
+            conn.execute("ROLLBACK")?;
+            return Err(e);
+        }
+    }
+    conn.execute("COMMIT")
+}
+
+// 读取表里要比较的列，值都按文本比较，NULL 是空字符串
+pub fn read_rows(conn: &Connection, table: &str, columns: &[&str]) -> sqlite::Result<Rows> {
+    let select: Vec<String> = columns
+        .iter()
+        .map(|c| format!("COALESCE(CAST({c} AS TEXT), '')"))
+        .collect();
+    let mut stmt = conn.prepare(format!(
+        "SELECT pub_id, {} FROM {table} ORDER BY pub_id",
+        select.join(", ")
+    ))?;
+    let mut rows = Rows::new();
+    while let State::Row = stmt.next()? {
+        let mut row = BTreeMap::new();
+        for (i, column) in columns.iter().enumerate() {
+            row.insert(column.to_string(), stmt.read::<String, _>(i + 1)?);
+        }
+        rows.entry(stmt.read(0)?).or_default().push(row);
+    }
+    Ok(rows)
+}
+
+// 数据库里和 doc 不一致的地方。column 是 None 时是整行：
+// expected 是 None 表示多出来的行，found 是 None 表示缺少的行
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Mismatch {
+    pub table: String,
+    pub pub_id: String,
+    pub column: Option<String>,
+    pub expected: Option<String>,
+    pub found: Option<String>,
+}
+
+// 比较同一个表期望的行和实际的行
+pub fn compare_rows(table: &str, expected: &Rows, found: &Rows) -> Vec<Mismatch> {
+    let mut mismatches = vec![];
+    let mismatch = |pub_id: &str, column: Option<&str>, expected, found| Mismatch {
+        table: table.to_string(),
+        pub_id: pub_id.to_string(),
+        column: column.map(|c| c.to_string()),
+        expected,
+        found,
+    };
+    for (pub_id, rows) in expected {
+        let Some(found) = found.get(pub_id) else {
+            mismatches.push(mismatch(pub_id, None, Some(format!("{:?}", rows[0])), None));
+            continue;
+        };
+        for (column, value) in rows[0].iter() {
+            let actual = &found[0][column];
+            if actual != value {
+                mismatches.push(mismatch(
+                    pub_id,
+                    Some(column),
+                    Some(value.clone()),
+                    Some(actual.clone()),
+                ));
+            }
+        }
+        for extra in &found[rows.len().min(found.len())..] {
+            mismatches.push(mismatch(pub_id, None, None, Some(format!("{extra:?}"))));
+        }
+    }
+    for (pub_id, rows) in found {
+        if !expected.contains_key(pub_id) {
+            for row in rows {
+                mismatches.push(mismatch(pub_id, None, None, Some(format!("{row:?}"))));
+            }
+        }
+    }
+    mismatches
+}
+
+// 应用直接写入 paths 的一次修改，field 是 create 或 delete 时 value 是 None
+#[derive(Debug, Clone, PartialEq)]
+pub struct PathEdit {
+    pub pub_id: String,
+    pub field: String,
+    pub value: Option<String>,
+}
+
+// 打开或关闭 paths 的修改记录
+pub fn set_capture(conn: &Connection, enabled: bool) -> sqlite::Result<()> {
+    let mut stmt = conn.prepare("UPDATE capture SET enabled = ?")?;
+    stmt.bind((1, enabled as i64))?;
+    stmt.next()?;
+    Ok(())
+}
+
+// 按顺序取出记录的修改，取出后删除
+pub fn take_path_edits(conn: &Connection) -> sqlite::Result<Vec<PathEdit>> {
+    let mut stmt = conn.prepare("SELECT id, pub_id, field, value FROM path_edits ORDER BY id")?;
+    let mut edits = vec![];
+    let mut last = 0;
+    while let State::Row = stmt.next()? {
+        last = stmt.read::<i64, _>(0)?;
+        edits.push(PathEdit {
+            pub_id: stmt.read(1)?,
+            field: stmt.read(2)?,
+            value: stmt.read(3)?,
+        });
+    }
+    let mut stmt = conn.prepare("DELETE FROM path_edits WHERE id <= ?")?;
+    stmt.bind((1, last))?;
+    stmt.next()?;
+    Ok(edits)
+}
@@ -0,0 +1,513 @@
+use std::fmt;
+
+use autosurgeon::{Hydrate, Reconcile};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{sync_record, text::Text, CrdtOperation, Deleted, Entry, EntryKind, Update};
+
+// 共享文件夹中的文件
+#[derive(Debug, Clone, Default, Reconcile, Hydrate, PartialEq, Serialize, Deserialize)]
+pub struct AssetObject {
+    // 分布式全局唯一，列表按 pub_id 对比，并发增删不同的文件不会互相覆盖
+    #[key]
+    pub pub_id: Uuid,
+    pub hash: String,
+    pub size: u64,
+    pub mime_type: String,
+    pub file_path: FilePath,
+    pub media_data: MediaData,
+}
+
+#[derive(Debug, Clone, Default, Reconcile, Hydrate, PartialEq, Serialize, Deserialize)]
+pub struct FilePath {
+    pub name: String,
+    pub description: Text,
+    // 文件所在文件夹的路径，由所属文件夹维护
+    pub materialized_path: String,
+}
+
+#[derive(Debug, Clone, Default, Reconcile, Hydrate, PartialEq, Serialize, Deserialize)]
+pub struct MediaData {
+    pub width: u32,
+    pub height: u32,
+    pub duration: u32,
+    pub bitrate: u32,
+    pub has_audio: bool,
+}
+
+// 单独同步的文件，媒体信息展开成列
+sync_record!(AssetObject, "assets", pub_id, {
+    hash => "hash",
+    size => "size",
+    mime_type => "mime_type",
+    file_path.name => "name",
+    file_path.description => "description",
+    file_path.materialized_path => "materialized_path",
+    media_data.width => "width",
+    media_data.height => "height",
+    media_data.duration => "duration",
+    media_data.bitrate => "bitrate",
+    media_data.has_audio => "has_audio",
+});
+
+// 分享文件夹
+#[derive(Debug, Clone, Reconcile, Hydrate, PartialEq, Serialize, Deserialize)]
+pub struct Folder {
+    #[key]
+    pub pub_id: Uuid,
+    pub name: String,
+    pub description: Text,
+    // 自己所在的路径，共享的根文件夹是 "/"，子文件夹是父文件夹的路径加上父文件夹的名字
+    pub materialized_path: String,
+    pub files: Vec<AssetObject>,
+    pub folders: Vec<Folder>,
+    // 删除了的条目，只有共享的根文件夹有
+    #[autosurgeon(missing = "Vec::new")]
+    #[serde(default)]
+    pub tombstones: Vec<Tombstone>,
+}
+
+// 删除的条目从树里取出来放在这里，同一个 pub_id 不会再出现在树里
+#[derive(Debug, Clone, Reconcile, Hydrate, PartialEq, Serialize, Deserialize)]
+pub struct Tombstone {
+    #[key]
+    pub pub_id: Uuid,
+    pub deleted: Deleted,
+    // 删除时所在的文件夹
+    pub parent: Uuid,
+    // 删除时的内容，文件夹和文件只有一个
+    pub folder: Option<Folder>,
+    pub file: Option<AssetObject>,
+}
+
+// 从文件夹中取出的条目，移动时使用
+#[derive(Debug, Clone, PartialEq)]
+pub enum Item {
+    Folder(Folder),
+    File(AssetObject),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FolderError {
+    // 找不到 pub_id 对应的条目
+    NotFound(Uuid),
+    // 找不到相对路径对应的父文件夹
+    ParentNotFound(String),
+    // pub_id 已经存在
+    AlreadyExists(Uuid),
+    // 不能移动或删除共享的根文件夹，也不能把文件夹移动到自己里面
+    InvalidMove(Uuid),
+    // 只有文件才有内容
+    NotAFile(Uuid),
+    // 文本修改的位置超出范围
+    OutOfRange(Uuid),
+    // 名字不能是空的、"." 或 ".."，也不能带路径分隔符
+    InvalidName(String),
+}
+
+impl fmt::Display for FolderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FolderError::NotFound(id) => write!(f, "item {id} not found"),
+            FolderError::ParentNotFound(path) => write!(f, "parent folder of {path} not found"),
+            FolderError::AlreadyExists(id) => write!(f, "item {id} already exists"),
+            FolderError::InvalidMove(id) => write!(f, "item {id} cannot be moved there"),
+            FolderError::NotAFile(id) => write!(f, "item {id} is not a file"),
+            FolderError::OutOfRange(id) => write!(f, "edit out of range for item {id}"),
+            FolderError::InvalidName(name) => write!(f, "invalid name {name:?}"),
+        }
+    }
+}
+
+impl std::error::Error for FolderError {}
+
+// 拆分相对路径 "a/b/c" => ("a/b", "c")
+pub fn split_path(path: &str) -> (&str, &str) {
+    let path = path.trim_matches('/');
+    match path.rsplit_once('/') {
+        Some((parent, name)) => (parent, name),
+        None => ("", path),
+    }
+}
+
+// 名字来自远端，落地到本地目录之前要保证不会跑到共享文件夹外面
+pub fn valid_name(name: &str) -> bool {
+    !matches!(name, "" | "." | "..") && !name.contains(['/', '\\', '\0'])
+}
+
+fn check_name(name: &str) -> Result<(), FolderError> {
+    if valid_name(name) {
+        Ok(())
+    } else {
+        Err(FolderError::InvalidName(name.to_string()))
+    }
+}
+
+// 拼接路径 "/" + "a" = "/a"，"/a" + "b" = "/a/b"
+pub fn join_path(parent: &str, name: &str) -> String {
+    if parent.ends_with('/') {
+        format!("{parent}{name}")
+    } else {
+        format!("{parent}/{name}")
+    }
+}
+
+impl AssetObject {
+    pub fn new(name: &str, hash: &str, size: u64, mime_type: &str) -> Self {
+        AssetObject {
+            pub_id: Uuid::new_v4(),
+            hash: hash.to_string(),
+            size,
+            mime_type: mime_type.to_string(),
+            file_path: FilePath {
+                name: name.to_string(),
+                description: Text::default(),
+                materialized_path: "/".to_string(),
+            },
+            media_data: MediaData::default(),
+        }
+    }
+}
+
+impl Folder {
+    // 创建共享的根文件夹
+    pub fn new(name: &str, description: &str) -> Self {
+        Folder {
+            pub_id: Uuid::new_v4(),
+            name: name.to_string(),
+            description: description.into(),
+            materialized_path: "/".to_string(),
+            files: vec![],
+            folders: vec![],
+            tombstones: vec![],
+        }
+    }
+
+    // 子文件夹和文件所在的路径
+    pub fn child_path(&self) -> String {
+        join_path(&self.materialized_path, &self.name)
+    }
+
+    // 添加子文件夹，返回添加后的子文件夹
+    pub fn add_folder(&mut self, mut folder: Folder) -> &mut Folder {
+        folder.materialized_path = self.child_path();
+        folder.materialize();
+        self.folders.push(folder);
+        self.folders.last_mut().unwrap()
+    }
+
+    // 添加文件
+    pub fn add_file(&mut self, mut file: AssetObject) -> &mut AssetObject {
+        file.file_path.materialized_path = self.child_path();
+        self.files.push(file);
+        self.files.last_mut().unwrap()
+    }
+
+    // 重命名或移动之后，重新计算所有子项的路径
+    pub fn materialize(&mut self) {
+        let child_path = self.child_path();
+        for file in self.files.iter_mut() {
+            file.file_path.materialized_path = child_path.clone();
+        }
+        for folder in self.folders.iter_mut() {
+            folder.materialized_path = child_path.clone();
+            folder.materialize();
+        }
+    }
+
+    // 按 pub_id 查找文件夹（包括自己）
+    pub fn find_folder(&self, pub_id: &Uuid) -> Option<&Folder> {
+        if &self.pub_id == pub_id {
+            return Some(self);
+        }
+        self.folders.iter().find_map(|f| f.find_folder(pub_id))
+    }
+
+    pub fn find_folder_mut(&mut self, pub_id: &Uuid) -> Option<&mut Folder> {
+        if &self.pub_id == pub_id {
+            return Some(self);
+        }
+        self.folders
+            .iter_mut()
+            .find_map(|f| f.find_folder_mut(pub_id))
+    }
+
+    // 按 pub_id 查找文件
+    pub fn find_file(&self, pub_id: &Uuid) -> Option<&AssetObject> {
+        self.files
+            .iter()
+            .find(|f| &f.pub_id == pub_id)
+            .or_else(|| self.folders.iter().find_map(|f| f.find_file(pub_id)))
+    }
+
+    // 按相对共享文件夹的路径查找文件夹，"" 是根文件夹自己
+    pub fn folder_by_path_mut(&mut self, path: &str) -> Option<&mut Folder> {
+        let mut folder = self;
+        for name in path.split('/').filter(|n| !n.is_empty()) {
+            folder = folder.folders.iter_mut().find(|f| f.name == name)?;
+        }
+        Some(folder)
+    }
+
+    // 条目相对共享文件夹的路径
+    pub fn relative_path(&self, materialized_path: &str, name: &str) -> String {
+        let full = join_path(materialized_path, name);
+        full.strip_prefix(&self.child_path())
+            .unwrap_or(&full)
+            .trim_start_matches('/')
+            .to_string()
+    }
+
+    // 所有条目的相对路径，(路径, pub_id, 是否是文件夹)
+    pub fn entries(&self) -> Vec<(String, Uuid, bool)> {
+        let mut entries = vec![];
+        self.walk(&mut |parent, folder| {
+            if parent.is_some() {
+                let path = self.relative_path(&folder.materialized_path, &folder.name);
+                entries.push((path, folder.pub_id, true));
+            }
+            for file in folder.files.iter() {
+                let path =
+                    self.relative_path(&file.file_path.materialized_path, &file.file_path.name);
+                entries.push((path, file.pub_id, false));
+            }
+        });
+        entries
+    }
+
+    // 从子项中取出条目
+    pub fn take(&mut self, pub_id: &Uuid) -> Option<Item> {
+        if let Some(i) = self.files.iter().position(|f| &f.pub_id == pub_id) {
+            return Some(Item::File(self.files.remove(i)));
+        }
+        if let Some(i) = self.folders.iter().position(|f| &f.pub_id == pub_id) {
+            return Some(Item::Folder(self.folders.remove(i)));
+        }
+        self.folders.iter_mut().find_map(|f| f.take(pub_id))
+    }
+
+    fn find_file_mut(&mut self, pub_id: &Uuid) -> Option<&mut AssetObject> {
+        if let Some(file) = self.files.iter_mut().find(|f| &f.pub_id == pub_id) {
+            return Some(file);
+        }
+        self.folders
+            .iter_mut()
+            .find_map(|f| f.find_file_mut(pub_id))
+    }
+
+    fn description_mut(&mut self, pub_id: &Uuid) -> Result<&mut Text, FolderError> {
+        if self.find_folder(pub_id).is_some() {
+            return Ok(&mut self.find_folder_mut(pub_id).unwrap().description);
+        }
+        let file = self
+            .find_file_mut(pub_id)
+            .ok_or(FolderError::NotFound(*pub_id))?;
+        Ok(&mut file.file_path.description)
+    }
+
+    pub(crate) fn fresh_text(&mut self) {
+        self.description = self.description.fresh();
+        for file in self.files.iter_mut() {
+            file.file_path.description = file.file_path.description.fresh();
+        }
+        for folder in self.folders.iter_mut() {
+            folder.fresh_text();
+        }
+    }
+
+    fn contains(&self, pub_id: &Uuid) -> bool {
+        self.find_folder(pub_id).is_some() || self.find_file(pub_id).is_some()
+    }
+
+    // 条目所在的文件夹
+    fn parent_of(&self, pub_id: &Uuid) -> Option<Uuid> {
+        let mut parent = None;
+        self.walk(&mut |_, folder| {
+            if folder.files.iter().any(|f| &f.pub_id == pub_id)
+                || folder.folders.iter().any(|f| &f.pub_id == pub_id)
+            {
+                parent = Some(folder.pub_id);
+            }
+        });
+        parent
+    }
+
+    // 是不是已经删除了，包括删除了的文件夹里的条目
+    pub fn is_deleted(&self, pub_id: &Uuid) -> bool {
+        self.tombstones
+            .iter()
+            .any(|t| &t.pub_id == pub_id || t.folder.as_ref().is_some_and(|f| f.contains(pub_id)))
+    }
+
+    // 把操作应用到共享文件夹，删除时不记录是谁删除的
+    pub fn apply(&mut self, op: &CrdtOperation) -> Result<(), FolderError> {
+        match op {
+            CrdtOperation::Create(entry) => self.create(entry),
+            CrdtOperation::Update(pub_id, update) => self.update(pub_id, update),
+            CrdtOperation::Delete(pub_id) => self.delete(pub_id, Deleted::default()),
+        }
+    }
+
+    // 删除条目，留下 tombstone
+    pub fn delete(&mut self, pub_id: &Uuid, deleted: Deleted) -> Result<(), FolderError> {
+        if pub_id == &self.pub_id {
+            return Err(FolderError::InvalidMove(*pub_id));
+        }
+        let parent = self
+            .parent_of(pub_id)
+            .ok_or(FolderError::NotFound(*pub_id))?;
+        // 放到新的位置，文本要整个重新写入
+        let (folder, file) = match self.take(pub_id) {
+            Some(Item::Folder(mut f)) => {
+                f.fresh_text();
+                (Some(f), None)
+            }
+            Some(Item::File(mut f)) => {
+                f.file_path.description = f.file_path.description.fresh();
+                (None, Some(f))
+            }
+            None => return Err(FolderError::NotFound(*pub_id)),
+        };
+        self.tombstones.push(Tombstone {
+            pub_id: *pub_id,
+            deleted,
+            parent,
+            folder,
+            file,
+        });
+        Ok(())
+    }
+
+    fn create(&mut self, entry: &Entry) -> Result<(), FolderError> {
+        if self.contains(&entry.pub_id) || self.is_deleted(&entry.pub_id) {
+            return Err(FolderError::AlreadyExists(entry.pub_id));
+        }
+        let (parent, name) = split_path(&entry.path);
+        check_name(name)?;
+        let item = match &entry.kind {
+            EntryKind::Folder => Item::Folder(Folder {
+                pub_id: entry.pub_id,
+                ..Folder::new(name, "")
+            }),
+            EntryKind::File {
+                hash,
+                size,
+                mime_type,
+            } => Item::File(AssetObject {
+                pub_id: entry.pub_id,
+                ..AssetObject::new(name, hash, *size, mime_type)
+            }),
+        };
+        self.insert(parent, item)
+    }
+
+    // 把条目放到相对路径 parent 的文件夹下
+    fn insert(&mut self, parent: &str, item: Item) -> Result<(), FolderError> {
+        let folder = self
+            .folder_by_path_mut(parent)
+            .ok_or_else(|| FolderError::ParentNotFound(parent.to_string()))?;
+        match item {
+            Item::Folder(f) => {
+                folder.add_folder(f);
+            }
+            Item::File(f) => {
+                folder.add_file(f);
+            }
+        }
+        Ok(())
+    }
+
+    fn update(&mut self, pub_id: &Uuid, update: &Update) -> Result<(), FolderError> {
+        match update {
+            Update::Name(name) => {
+                check_name(name)?;
+                if let Some(folder) = self.find_folder_mut(pub_id) {
+                    folder.name = name.clone();
+                    folder.materialize();
+                } else {
+                    let file = self
+                        .find_file_mut(pub_id)
+                        .ok_or(FolderError::NotFound(*pub_id))?;
+                    file.file_path.name = name.clone();
+                }
+            }
+            Update::Description(description) => {
+                self.description_mut(pub_id)?.update(description);
+            }
+            Update::SpliceDescription {
+                index,
+                delete,
+                insert,
+            } => {
+                if !self
+                    .description_mut(pub_id)?
+                    .splice(*index, *delete, insert)
+                {
+                    return Err(FolderError::OutOfRange(*pub_id));
+                }
+            }
+            Update::Path(path) => {
+                if pub_id == &self.pub_id {
+                    return Err(FolderError::InvalidMove(*pub_id));
+                }
+                let (parent, name) = split_path(path);
+                check_name(name)?;
+                let target = self
+                    .folder_by_path_mut(parent)
+                    .ok_or_else(|| FolderError::ParentNotFound(parent.to_string()))?
+                    .pub_id;
+                // 不能移动到自己或者自己的子文件夹里
+                if let Some(folder) = self.find_folder(pub_id) {
+                    if folder.find_folder(&target).is_some() {
+                        return Err(FolderError::InvalidMove(*pub_id));
+                    }
+                }
+                // 同一个文件夹里只是改名，不用移动
+                let target = self.find_folder(&target).unwrap();
+                if target.files.iter().any(|f| &f.pub_id == pub_id)
+                    || target.folders.iter().any(|f| &f.pub_id == pub_id)
+                {
+                    return self.update(pub_id, &Update::Name(name.to_string()));
+                }
+                // 移动后在新的位置是新的对象，文本要整个重新写入
+                let mut item = self.take(pub_id).ok_or(FolderError::NotFound(*pub_id))?;
+                match &mut item {
+                    Item::Folder(f) => {
+                        f.name = name.to_string();
+                        f.fresh_text();
+                    }
+                    Item::File(f) => {
+                        f.file_path.name = name.to_string();
+                        f.file_path.description = f.file_path.description.fresh();
+                    }
+                }
+                self.insert(parent, item)?;
+            }
+            Update::Content { hash, size } => {
+                if self.find_folder(pub_id).is_some() {
+                    return Err(FolderError::NotAFile(*pub_id));
+                }
+                let file = self
+                    .find_file_mut(pub_id)
+                    .ok_or(FolderError::NotFound(*pub_id))?;
+                file.hash = hash.clone();
+                file.size = *size;
+            }
+        }
+        Ok(())
+    }
+
+    // 遍历所有文件夹，回调参数是 (父文件夹 pub_id, 文件夹)
+    pub fn walk<F: FnMut(Option<&Uuid>, &Folder)>(&self, f: &mut F) {
+        f(None, self);
+        self.walk_children(f);
+    }
+
+    fn walk_children<F: FnMut(Option<&Uuid>, &Folder)>(&self, f: &mut F) {
+        for folder in self.folders.iter() {
+            f(Some(&self.pub_id), folder);
+            folder.walk_children(f);
+        }
+    }
+}
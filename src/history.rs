@@ -0,0 +1,697 @@
+use std::collections::HashSet;
+
+use automerge::{
+    transaction::{CommitOptions, Transactable},
+    AutoCommit, ChangeHash, Cursor, ObjId, ObjType, Patch, PatchAction, Prop, ReadDoc, Value,
+};
+use serde::Serialize;
+
+use crate::{
+    conflict::{join, locate, value_json},
+    Error,
+};
+
+// 一次修改
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HistoryEntry {
+    pub hash: String,
+    pub actor: String,
+    // 毫秒，没有带时间的 change 是 0
+    pub time: i64,
+    pub message: Option<String>,
+    pub deps: Vec<String>,
+    // 这次修改改了哪些字段
+    pub fields: Vec<String>,
+}
+
+// 两个版本之间变化的字段，不存在是 None
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FieldDiff {
+    pub field: String,
+    pub before: Option<serde_json::Value>,
+    pub after: Option<serde_json::Value>,
+}
+
+// 逗号分隔的 change hash，空字符串是空的 heads
+pub fn parse_heads(input: &str) -> Result<Vec<ChangeHash>, Error> {
+    input
+        .split(',')
+        .filter(|h| !h.is_empty())
+        .map(|h| {
+            h.parse()
+                .map_err(|_| Error::InvalidInput(format!("change hash {h}")))
+        })
+        .collect()
+}
+
+// 检查 heads 都在 doc 的历史里
+pub fn check_heads(doc: &mut AutoCommit, heads: &[ChangeHash]) -> Result<(), Error> {
+    for head in heads {
+        if doc.get_change_by_hash(head).is_none() {
+            return Err(Error::InvalidInput(format!("unknown change {head}")));
+        }
+    }
+    Ok(())
+}
+
+// patch 改的是哪个字段
+fn patch_field(doc: &AutoCommit, patch: &Patch) -> String {
+    let path = patch
+        .path
+        .iter()
+        .fold(String::new(), |path, (_, prop)| join(&path, prop));
+    // 文本的修改算在文本字段上
+    if doc.object_type(&patch.obj) == Ok(ObjType::Text) {
+        return path;
+    }
+    let prop = match &patch.action {
+        PatchAction::PutMap { key, .. } | PatchAction::DeleteMap { key } => Prop::Map(key.clone()),
+        PatchAction::PutSeq { index, .. }
+        | PatchAction::Insert { index, .. }
+        | PatchAction::DeleteSeq { index, .. } => Prop::Seq(*index),
+        PatchAction::Increment { prop, .. } | PatchAction::Conflict { prop } => prop.clone(),
+        PatchAction::SpliceText { .. } | PatchAction::Mark { .. } => return path,
+    };
+    join(&path, &prop)
+}
+
+fn fields(doc: &AutoCommit, patches: &[Patch]) -> Vec<String> {
+    let mut fields = vec![];
+    for patch in patches {
+        let field = patch_field(doc, patch);
+        if !fields.contains(&field) {
+            fields.push(field);
+        }
+    }
+    fields
+}
+
+// 重放别人的 change 时作者记在说明的最后一行
+const AUTHOR: &str = "\nauthor: ";
+
+// 拆出说明和重放前的作者
+fn split_author(message: Option<&str>) -> (Option<String>, Option<String>) {
+    let Some(message) = message else {
+        return (None, None);
+    };
+    match message.rsplit_once(AUTHOR) {
+        Some((message, author)) => {
+            let message = (!message.is_empty()).then(|| message.to_string());
+            (message, Some(author.to_string()))
+        }
+        None => (Some(message.to_string()), None),
+    }
+}
+
+// 按因果顺序列出 doc 的所有修改
+pub fn history(doc: &mut AutoCommit) -> Vec<HistoryEntry> {
+    let changes: Vec<_> = doc
+        .get_changes(&[])
+        .into_iter()
+        .map(|c| {
+            let (message, author) = split_author(c.message().map(String::as_str));
+            (
+                c.hash(),
+                author.unwrap_or_else(|| c.actor_id().to_hex_string()),
+                c.timestamp(),
+                message,
+                c.deps().to_vec(),
+            )
+        })
+        .collect();
+    changes
+        .into_iter()
+        .map(|(hash, actor, time, message, deps)| {
+            let patches = doc.diff(&deps, &[hash]);
+            HistoryEntry {
+                fields: fields(doc, &patches),
+                hash: hash.to_string(),
+                actor,
+                time,
+                message,
+                deps: deps.iter().map(|h| h.to_string()).collect(),
+            }
+        })
+        .collect()
+}
+
+// 读取字段的值，字段不存在是 None
+fn read(doc: &AutoCommit, field: &str) -> Option<serde_json::Value> {
+    let (obj, prop) = locate(doc, field).ok()?;
+    let (value, id) = doc.get(&obj, prop).ok()??;
+    Some(value_json(doc, &value, &id))
+}
+
+// 比较两个版本
+pub fn diff(
+    doc: &mut AutoCommit,
+    before: &[ChangeHash],
+    after: &[ChangeHash],
+) -> Result<Vec<FieldDiff>, Error> {
+    check_heads(doc, before)?;
+    check_heads(doc, after)?;
+    let patches = doc.diff(before, after);
+    let old = doc.fork_at(before)?;
+    let new = doc.fork_at(after)?;
+    Ok(fields(doc, &patches)
+        .into_iter()
+        .map(|field| FieldDiff {
+            before: read(&old, &field),
+            after: read(&new, &field),
+            field,
+        })
+        .collect())
+}
+
+// 把 src 里的对象复制到 doc 的新对象里
+pub(crate) fn copy_object(
+    doc: &mut AutoCommit,
+    src: &AutoCommit,
+    from: &ObjId,
+    to: &ObjId,
+    kind: ObjType,
+) -> Result<(), Error> {
+    match kind {
+        ObjType::Map | ObjType::Table => {
+            for key in src.keys(from).collect::<Vec<_>>() {
+                if let Some((value, id)) = src.get(from, key.as_str())? {
+                    write_value(doc, src, to, Prop::Map(key), &value, &id, false)?;
+                }
+            }
+        }
+        ObjType::List => {
+            for index in 0..src.length(from) {
+                if let Some((value, id)) = src.get(from, index)? {
+                    write_value(doc, src, to, Prop::Seq(index), &value, &id, true)?;
+                }
+            }
+        }
+        ObjType::Text => doc.splice_text(to, 0, 0, &src.text(from)?)?,
+    }
+    Ok(())
+}
+
+// 写入一个值，对象会整个复制一份
+fn write_value(
+    doc: &mut AutoCommit,
+    src: &AutoCommit,
+    obj: &ObjId,
+    prop: Prop,
+    value: &Value,
+    id: &ObjId,
+    insert: bool,
+) -> Result<(), Error> {
+    match (value, prop) {
+        (Value::Scalar(s), Prop::Seq(index)) if insert => {
+            doc.insert(obj, index, s.as_ref().clone())?
+        }
+        (Value::Scalar(s), prop) => doc.put(obj, prop, s.as_ref().clone())?,
+        (Value::Object(kind), Prop::Seq(index)) if insert => {
+            let new = doc.insert_object(obj, index, *kind)?;
+            copy_object(doc, src, id, &new, *kind)?;
+        }
+        (Value::Object(kind), prop) => {
+            let new = doc.put_object(obj, prop, *kind)?;
+            copy_object(doc, src, id, &new, *kind)?;
+        }
+    }
+    Ok(())
+}
+
+// 按下标在 obj 上应用一个 patch，新的对象从 src 复制
+fn apply_patch(
+    doc: &mut AutoCommit,
+    src: &AutoCommit,
+    obj: &ObjId,
+    action: &PatchAction,
+) -> Result<(), Error> {
+    match action {
+        PatchAction::PutMap { key, value, .. } => write_value(
+            doc,
+            src,
+            obj,
+            Prop::Map(key.clone()),
+            &value.0,
+            &value.1,
+            false,
+        )?,
+        PatchAction::PutSeq { index, value, .. } => {
+            write_value(doc, src, obj, Prop::Seq(*index), &value.0, &value.1, false)?
+        }
+        PatchAction::Insert { index, values, .. } => {
+            for (i, (value, id, _)) in values.iter().enumerate() {
+                write_value(doc, src, obj, Prop::Seq(index + i), value, id, true)?;
+            }
+        }
+        PatchAction::SpliceText { index, value, .. } => {
+            doc.splice_text(obj, *index, 0, &value.make_string())?
+        }
+        PatchAction::DeleteMap { key } => doc.delete(obj, key.as_str())?,
+        PatchAction::DeleteSeq { index, length } => {
+            if doc.object_type(obj)? == ObjType::Text {
+                doc.splice_text(obj, *index, *length as isize, "")?;
+            } else {
+                for _ in 0..*length {
+                    doc.delete(obj, *index)?;
+                }
+            }
+        }
+        PatchAction::Increment { prop, value } => doc.increment(obj, prop.clone(), *value)?,
+        PatchAction::Conflict { .. } | PatchAction::Mark { .. } => {}
+    }
+    Ok(())
+}
+
+// 撤销时写的位置：map 的 key，或者列表、文本里的元素。
+// 元素记成 cursor，之后的修改改变了下标也能找到
+enum Target {
+    Key(String),
+    Element(Cursor),
+}
+
+// 撤销一个 change 要写入的一个修改
+enum Step {
+    Put {
+        obj: ObjId,
+        target: Target,
+        value: Value<'static>,
+        id: ObjId,
+    },
+    Delete {
+        obj: ObjId,
+        key: String,
+    },
+    // 插入到 after 元素后面，None 是开头
+    Insert {
+        obj: ObjId,
+        after: Option<Cursor>,
+        values: Vec<(Value<'static>, ObjId)>,
+    },
+    SpliceText {
+        obj: ObjId,
+        after: Option<Cursor>,
+        text: String,
+    },
+    DeleteElements {
+        obj: ObjId,
+        elements: Vec<Cursor>,
+    },
+    Increment {
+        obj: ObjId,
+        target: Target,
+        by: i64,
+    },
+}
+
+// 撤销一个 change 要写入的修改。用 plan_revert 算好之后再写入，
+// 读历史会提交还没提交的修改，算的时候不能写
+pub struct Revert {
+    change: ChangeHash,
+    // change 之前的版本，重新写入的对象从这里复制
+    src: AutoCommit,
+    steps: Vec<Step>,
+}
+
+// 元素现在的下标，以及是不是还在
+fn position(
+    doc: &AutoCommit,
+    obj: &ObjId,
+    element: &Cursor,
+    change: &ChangeHash,
+) -> Result<(usize, bool), Error> {
+    let index = doc
+        .get_cursor_position(obj, element, None)
+        .map_err(|_| Error::InvalidInput(format!("cannot map change {change} onto current doc")))?;
+    let visible = doc.get_cursor(obj, index, None).ok().as_ref() == Some(element);
+    Ok((index, visible))
+}
+
+// 插入的下标：after 还在的话是它后面，删除了的话是它原来的位置
+fn insert_index(
+    doc: &AutoCommit,
+    obj: &ObjId,
+    after: &Option<Cursor>,
+    change: &ChangeHash,
+) -> Result<usize, Error> {
+    match after {
+        Some(after) => {
+            let (index, visible) = position(doc, obj, after, change)?;
+            Ok(if visible { index + 1 } else { index })
+        }
+        None => Ok(0),
+    }
+}
+
+impl Step {
+    fn obj(&self) -> &ObjId {
+        match self {
+            Step::Put { obj, .. }
+            | Step::Delete { obj, .. }
+            | Step::Insert { obj, .. }
+            | Step::SpliceText { obj, .. }
+            | Step::DeleteElements { obj, .. }
+            | Step::Increment { obj, .. } => obj,
+        }
+    }
+
+    // 写入当前的 doc，已经删除的元素和 key 跳过，返回是不是写了
+    fn apply(
+        &self,
+        doc: &mut AutoCommit,
+        src: &AutoCommit,
+        change: &ChangeHash,
+    ) -> Result<bool, Error> {
+        // 之后被删除的对象上的修改跳过
+        if doc.parents(self.obj())?.visible_path().is_none() {
+            return Ok(false);
+        }
+        match self {
+            Step::Put {
+                obj,
+                target,
+                value,
+                id,
+            } => {
+                let prop = match target {
+                    Target::Key(key) => Prop::Map(key.clone()),
+                    Target::Element(element) => match position(doc, obj, element, change)? {
+                        (index, true) => Prop::Seq(index),
+                        (_, false) => return Ok(false),
+                    },
+                };
+                write_value(doc, src, obj, prop, value, id, false)?;
+            }
+            Step::Delete { obj, key } => {
+                if doc.get(obj, key.as_str())?.is_none() {
+                    return Ok(false);
+                }
+                doc.delete(obj, key.as_str())?;
+            }
+            Step::Insert { obj, after, values } => {
+                let index = insert_index(doc, obj, after, change)?;
+                for (i, (value, id)) in values.iter().enumerate() {
+                    write_value(doc, src, obj, Prop::Seq(index + i), value, id, true)?;
+                }
+            }
+            Step::SpliceText { obj, after, text } => {
+                let index = insert_index(doc, obj, after, change)?;
+                doc.splice_text(obj, index, 0, text)?;
+            }
+            Step::DeleteElements { obj, elements } => {
+                let mut indexes = vec![];
+                for element in elements {
+                    if let (index, true) = position(doc, obj, element, change)? {
+                        indexes.push(index);
+                    }
+                }
+                if indexes.is_empty() {
+                    return Ok(false);
+                }
+                // 从后往前删，前面的下标不变
+                indexes.sort_unstable_by(|a, b| b.cmp(a));
+                let text = doc.object_type(obj)? == ObjType::Text;
+                for index in indexes {
+                    if text {
+                        doc.splice_text(obj, index, 1, "")?;
+                    } else {
+                        doc.delete(obj, index)?;
+                    }
+                }
+            }
+            Step::Increment { obj, target, by } => match target {
+                Target::Key(key) => doc.increment(obj, key.as_str(), *by)?,
+                Target::Element(element) => match position(doc, obj, element, change)? {
+                    (index, true) => doc.increment(obj, index, *by)?,
+                    (_, false) => return Ok(false),
+                },
+            },
+        }
+        Ok(true)
+    }
+}
+
+// 算出撤销一个 change 要写入的修改。反向的 patch 里的下标是相对 change 之后的版本的，
+// 列表和文本的位置换成元素，写入时再找到元素现在的下标
+pub fn plan_revert(doc: &mut AutoCommit, change: &ChangeHash) -> Result<Revert, Error> {
+    let deps = doc
+        .get_change_by_hash(change)
+        .map(|c| c.deps().to_vec())
+        .ok_or_else(|| Error::InvalidInput(format!("unknown change {change}")))?;
+    let patches = doc.diff(&[*change], &deps);
+    let src = doc.fork_at(&deps)?;
+    // 按顺序应用列表和文本的 patch，后面的 patch 的下标才对得上
+    let mut scratch = doc.fork_at(&[*change])?;
+    // 整个复制的对象，里面的 patch 已经包含在复制里了
+    let mut copied: HashSet<ObjId> = HashSet::new();
+    let mut steps = vec![];
+    for patch in patches.iter() {
+        if copied.contains(&patch.obj) || patch.path.iter().any(|(o, _)| copied.contains(o)) {
+            continue;
+        }
+        let obj = patch.obj.clone();
+        let element = |scratch: &AutoCommit, index: usize| {
+            scratch
+                .get_cursor(&obj, index, None)
+                .map_err(|_| Error::InvalidInput(format!("cannot map change {change}")))
+        };
+        let after = |scratch: &AutoCommit, index: usize| match index {
+            0 => Ok(None),
+            i => element(scratch, i - 1).map(Some),
+        };
+        let mut copy = |value: &Value, id: &ObjId| {
+            if let Value::Object(_) = value {
+                copied.insert(id.clone());
+            }
+        };
+        let step = match &patch.action {
+            PatchAction::PutMap { key, value, .. } => {
+                copy(&value.0, &value.1);
+                Step::Put {
+                    obj,
+                    target: Target::Key(key.clone()),
+                    value: value.0.clone(),
+                    id: value.1.clone(),
+                }
+            }
+            PatchAction::PutSeq { index, value, .. } => {
+                copy(&value.0, &value.1);
+                Step::Put {
+                    target: Target::Element(element(&scratch, *index)?),
+                    obj,
+                    value: value.0.clone(),
+                    id: value.1.clone(),
+                }
+            }
+            PatchAction::Insert { index, values, .. } => {
+                for (value, id, _) in values.iter() {
+                    copy(value, id);
+                }
+                Step::Insert {
+                    after: after(&scratch, *index)?,
+                    obj,
+                    values: values
+                        .iter()
+                        .map(|(value, id, _)| (value.clone(), id.clone()))
+                        .collect(),
+                }
+            }
+            PatchAction::SpliceText { index, value, .. } => Step::SpliceText {
+                after: after(&scratch, *index)?,
+                obj,
+                text: value.make_string(),
+            },
+            PatchAction::DeleteMap { key } => Step::Delete {
+                obj,
+                key: key.clone(),
+            },
+            PatchAction::DeleteSeq { index, length } => Step::DeleteElements {
+                elements: (*index..index + length)
+                    .map(|i| element(&scratch, i))
+                    .collect::<Result<_, _>>()?,
+                obj,
+            },
+            PatchAction::Increment { prop, value } => Step::Increment {
+                target: match prop {
+                    Prop::Map(key) => Target::Key(key.clone()),
+                    Prop::Seq(index) => Target::Element(element(&scratch, *index)?),
+                },
+                obj,
+                by: *value,
+            },
+            PatchAction::Conflict { .. } | PatchAction::Mark { .. } => continue,
+        };
+        if matches!(
+            patch.action,
+            PatchAction::Insert { .. }
+                | PatchAction::SpliceText { .. }
+                | PatchAction::DeleteSeq { .. }
+        ) {
+            apply_patch(&mut scratch, &src, &patch.obj, &patch.action)?;
+        }
+        steps.push(step);
+    }
+    Ok(Revert {
+        change: *change,
+        src,
+        steps,
+    })
+}
+
+impl Revert {
+    // 在当前的 doc 上写入反向的修改，不改写历史，也不提交。
+    // 之后被删除的对象和元素上的修改会被跳过，返回实际写入的修改数量
+    pub fn apply(&self, doc: &mut AutoCommit) -> Result<usize, Error> {
+        let mut applied = 0;
+        for step in self.steps.iter() {
+            if step.apply(doc, &self.src, &self.change)? {
+                applied += 1;
+            }
+        }
+        Ok(applied)
+    }
+}
+
+// 当前 actor 最近的 n 个 change，新的在前。重放的别人的 change 不算
+pub fn last_local_changes(doc: &mut AutoCommit, n: usize) -> Vec<ChangeHash> {
+    let actor = doc.get_actor().clone();
+    let mut changes: Vec<_> = doc
+        .get_changes(&[])
+        .into_iter()
+        .filter(|c| {
+            c.actor_id() == &actor && split_author(c.message().map(String::as_str)).1.is_none()
+        })
+        .map(|c| c.hash())
+        .collect();
+    changes.reverse();
+    changes.truncate(n);
+    changes
+}
+
+// 时间不晚于 cutoff、依赖也都不晚于 cutoff 的 change 的 heads，
+// 以及这些 change 的数量和最晚的时间
+pub fn settled(doc: &mut AutoCommit, cutoff: i64) -> (Vec<ChangeHash>, usize, i64) {
+    let mut settled: HashSet<ChangeHash> = HashSet::new();
+    let mut deps: HashSet<ChangeHash> = HashSet::new();
+    let mut latest = 0;
+    // get_changes 是按因果顺序的，依赖总在前面
+    for change in doc.get_changes(&[]) {
+        if change.timestamp() <= cutoff && change.deps().iter().all(|d| settled.contains(d)) {
+            settled.insert(change.hash());
+            deps.extend(change.deps().iter().copied());
+            latest = latest.max(change.timestamp());
+        }
+    }
+    let mut heads: Vec<_> = settled
+        .iter()
+        .filter(|h| !deps.contains(h))
+        .copied()
+        .collect();
+    heads.sort();
+    (heads, settled.len(), latest)
+}
+
+// patch 改的对象在 doc 里是哪个，按路径从根找下来
+fn resolve(doc: &AutoCommit, patch: &Patch) -> Result<ObjId, Error> {
+    let mut obj = automerge::ROOT;
+    for (_, prop) in patch.path.iter() {
+        obj = match doc.get(&obj, prop.clone())? {
+            Some((Value::Object(_), id)) => id,
+            _ => {
+                return Err(Error::InvalidInput(format!(
+                    "cannot replay patch at {prop}"
+                )))
+            }
+        };
+    }
+    Ok(obj)
+}
+
+// 把 old 里 base 之后的 change 按因果顺序重新写入 doc，保留时间和说明。
+// 都用 doc 的 actor 写：各个 peer 重放的 change 不一样，用原来的 actor 会和别处的 seq 重复，
+// 原来的作者记在说明里。doc 的内容要和 old 在 base 时一样
+pub(crate) fn replay(
+    doc: &mut AutoCommit,
+    old: &mut AutoCommit,
+    base: &[ChangeHash],
+) -> Result<(), Error> {
+    let changes: Vec<_> = old
+        .get_changes(base)
+        .into_iter()
+        .map(|c| {
+            (
+                c.hash(),
+                c.deps().to_vec(),
+                c.actor_id().clone(),
+                c.timestamp(),
+                c.message().cloned(),
+            )
+        })
+        .collect();
+    let actor = doc.get_actor().clone();
+    let mut heads = base.to_vec();
+    for (hash, deps, change_actor, time, message) in changes {
+        let mut next: Vec<_> = heads
+            .iter()
+            .filter(|h| !deps.contains(h))
+            .copied()
+            .collect();
+        next.push(hash);
+        // 两个版本之间的 patch，下标都是相对 doc 现在的内容
+        let patches = old.diff(&heads, &next);
+        let src = old.fork_at(&next)?;
+        let mut copied: HashSet<ObjId> = HashSet::new();
+        for patch in patches.iter() {
+            // 整个复制的对象，里面的 patch 已经包含在复制里了
+            if copied.contains(&patch.obj) || patch.path.iter().any(|(o, _)| copied.contains(o)) {
+                continue;
+            }
+            if let PatchAction::PutMap { value, .. } | PatchAction::PutSeq { value, .. } =
+                &patch.action
+            {
+                if let Value::Object(_) = value.0 {
+                    copied.insert(value.1.clone());
+                }
+            }
+            if let PatchAction::Insert { values, .. } = &patch.action {
+                for (value, id, _) in values.iter() {
+                    if let Value::Object(_) = value {
+                        copied.insert(id.clone());
+                    }
+                }
+            }
+            let obj = resolve(doc, patch)?;
+            apply_patch(doc, &src, &obj, &patch.action)?;
+        }
+        let mut options = CommitOptions::default().with_time(time);
+        // 已经重放过的保留最早的作者
+        let (message, author) = split_author(message.as_deref());
+        let author =
+            author.or_else(|| (change_actor != actor).then(|| change_actor.to_hex_string()));
+        let message = match (message, author) {
+            (message, Some(author)) => {
+                Some(format!("{}{AUTHOR}{author}", message.unwrap_or_default()))
+            }
+            (message, None) => message,
+        };
+        if let Some(message) = message {
+            options.set_message(message);
+        }
+        doc.commit_with(options);
+        heads = next;
+    }
+    Ok(())
+}
+
+// 把 old 里 base 之后的 change 接到 doc 在 rebased 时的版本后面，再合并回 doc。
+// doc 在 rebased 时的内容要和 old 在 base 时一样
+pub(crate) fn rebase(
+    doc: &mut AutoCommit,
+    old: &mut AutoCommit,
+    base: &[ChangeHash],
+    rebased: &[ChangeHash],
+) -> Result<(), Error> {
+    let mut branch = doc.fork_at(rebased)?;
+    replay(&mut branch, old, base)?;
+    doc.merge(&mut branch)?;
+    Ok(())
+}
@@ -0,0 +1,120 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use libp2p::PeerId;
+use serde::Serialize;
+
+// 收到的 stream 怎么处理：同时合并的数量，以及排队的上限。
+// 每个 peer 最多占 per_peer 个位置，一个 peer 发得再多也不会挤掉其他 peer
+#[derive(Debug, Clone)]
+pub struct IncomingLimits {
+    pub concurrency: usize,
+    pub per_peer: usize,
+    pub capacity: usize,
+}
+
+impl Default for IncomingLimits {
+    fn default() -> Self {
+        IncomingLimits {
+            concurrency: 4,
+            per_peer: 8,
+            capacity: 64,
+        }
+    }
+}
+
+// 收到的 stream 的统计
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct IncomingStats {
+    // 等待合并的数量，以及出现过的最大值
+    pub queued: usize,
+    pub max_queued: usize,
+    // 正在合并的数量
+    pub active: usize,
+    pub accepted: u64,
+    // 排满了拒绝的
+    pub refused: u64,
+    pub merged: u64,
+    pub failed: u64,
+    // 每个 peer 排队和合并中的数量
+    pub peers: Vec<(String, usize)>,
+}
+
+#[derive(Default)]
+struct Queue {
+    // 每个 peer 排队和合并中的数量
+    pending: HashMap<PeerId, usize>,
+    stats: IncomingStats,
+}
+
+// 收到的 stream 的排队情况，合并的许可是 Manager 的 timestamp_lock
+pub struct Incoming {
+    pub limits: IncomingLimits,
+    queue: Mutex<Queue>,
+}
+
+impl Incoming {
+    pub fn new(limits: IncomingLimits) -> Self {
+        Incoming {
+            limits,
+            queue: Mutex::new(Queue::default()),
+        }
+    }
+
+    // 排进队列，这个 peer 或者整个队列满了返回 false
+    pub fn admit(&self, peer: &PeerId) -> bool {
+        let mut queue = self.queue.lock().unwrap();
+        let total: usize = queue.pending.values().sum();
+        let mine = queue.pending.get(peer).copied().unwrap_or_default();
+        if mine >= self.limits.per_peer || total >= self.limits.capacity {
+            queue.stats.refused += 1;
+            return false;
+        }
+        *queue.pending.entry(*peer).or_default() += 1;
+        let stats = &mut queue.stats;
+        stats.accepted += 1;
+        stats.queued += 1;
+        stats.max_queued = stats.max_queued.max(stats.queued);
+        true
+    }
+
+    // 拿到许可，开始合并。没有 admit 过的不会让排队的数量变成负的
+    pub fn start(&self) {
+        let stats = &mut self.queue.lock().unwrap().stats;
+        stats.queued = stats.queued.saturating_sub(1);
+        stats.active += 1;
+    }
+
+    // 处理完了，started 表示是不是已经开始合并
+    pub fn finish(&self, peer: &PeerId, started: bool, ok: bool) {
+        let mut queue = self.queue.lock().unwrap();
+        if let Some(n) = queue.pending.get_mut(peer) {
+            *n -= 1;
+            if *n == 0 {
+                queue.pending.remove(peer);
+            }
+        }
+        let stats = &mut queue.stats;
+        if started {
+            stats.active = stats.active.saturating_sub(1);
+        } else {
+            stats.queued = stats.queued.saturating_sub(1);
+        }
+        if ok {
+            stats.merged += 1;
+        } else {
+            stats.failed += 1;
+        }
+    }
+
+    pub fn stats(&self) -> IncomingStats {
+        let queue = self.queue.lock().unwrap();
+        let mut stats = queue.stats.clone();
+        stats.peers = queue
+            .pending
+            .iter()
+            .map(|(peer, n)| (peer.to_string(), *n))
+            .collect();
+        stats.peers.sort();
+        stats
+    }
+}
@@ -1,31 +1,81 @@
-use std::{
-    collections::{BTreeMap, HashMap},
-    sync::{Arc, RwLock},
-};
+use std::fmt;
 
-use automerge::ActorId;
+use autosurgeon::{Hydrate, Reconcile};
+use serde::{Deserialize, Serialize};
 
-use libp2p::PeerId;
+pub mod api;
+pub mod batch;
+pub mod conflict;
+pub mod db;
+pub mod folder;
+pub mod history;
+pub mod incoming;
+pub mod manager;
+pub mod materialize;
+pub mod migrate;
+pub mod record;
+pub mod status;
+pub mod text;
+pub mod transfer;
+pub mod tree;
+pub mod watcher;
+
+pub use manager::{DocInfo, Manager};
+
+#[cfg(test)]
+mod test;
 
 // crdt 操作 只要 创建，更新，删除
+#[derive(Debug, Clone, PartialEq)]
 pub enum CrdtOperation {
-    Create,
+    Create(Entry),
     Update(uuid::Uuid, Update),
-    Delete,
+    Delete(uuid::Uuid),
 }
 
 // 更新操作
+#[derive(Debug, Clone, PartialEq)]
 pub enum Update {
     // 文件名字更新
     Name(String),
-    // 文件描述更新
+    // 文件描述整个替换，只写入不同的部分
     Description(String),
+    // 文件描述的文本编辑：从 index 开始删除 delete 个字符，再插入 insert
+    SpliceDescription {
+        index: usize,
+        delete: usize,
+        insert: String,
+    },
     // 文件路径更新 需要共享的是文件夹，如果共享的是文件就不需要路径 注意：路径是相对路径，如果移动到了共享文件夹外，算是删除
     Path(String),
+    // 文件内容更新
+    Content {
+        hash: String,
+        size: u64,
+    },
+}
+
+// 共享文件夹中新建的文件或文件夹
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub pub_id: uuid::Uuid,
+    // 相对共享文件夹的路径，包括名字
+    pub path: String,
+    pub kind: EntryKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EntryKind {
+    Folder,
+    File {
+        hash: String,
+        size: u64,
+        mime_type: String,
+    },
 }
 
 // peer权限
-#[derive(Debug, Default, Clone, PartialEq)]
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
 pub enum PeerPermission {
     #[default]
     ReadOnly, // peer只读，只能其他人的变更
@@ -33,33 +83,156 @@ pub enum PeerPermission {
     Owner,     // 可读写，接收和广播变更
 }
 
-#[derive(Clone, Debug)]
+// Manager::subscribe 收到的事件
+#[derive(Clone, Debug, PartialEq)]
 pub enum SyncMessage {
-    Ingested,
-    Created,
+    // 合并了 peer 发来的修改，heads 是合并之后的
+    Ingested {
+        id: uuid::Uuid,
+        peer: libp2p::PeerId,
+        heads: Vec<automerge::ChangeHash>,
+    },
+    // 本地新建的，或者第一次收到的 doc
+    Created {
+        id: uuid::Uuid,
+        kind: DocKind,
+    },
+    // mdns 发现和过期的 peer
+    PeerJoined(libp2p::PeerId),
+    PeerLeft(libp2p::PeerId),
+    // 分享给 peer 的权限变了，或者 peer 改了分享给本地的权限
+    PermissionChanged {
+        id: uuid::Uuid,
+        peer: libp2p::PeerId,
+        permission: PeerPermission,
+    },
+    // 发给 peer 或者合并 peer 发来的 doc 失败
+    SyncFailed {
+        id: uuid::Uuid,
+        peer: libp2p::PeerId,
+        error: String,
+    },
 }
 
-pub struct Manager {
-    // 已经共享的doc
-    pub shared: BTreeMap<ActorId, DocInfo>,
+// 共享的单个文件
+#[derive(Debug, Clone, Default, Reconcile, Hydrate, PartialEq, Serialize, Deserialize)]
+pub struct Path {
+    pub pub_id: uuid::Uuid,
+    pub name: String,
+    pub path: String,
+    pub description: text::Text,
+    // 删除之后保留 doc，并发的修改不会让它重新出现
+    #[serde(default)]
+    pub deleted: Option<Deleted>,
+}
 
-    // 发送者
-    pub sender: tokio::sync::broadcast::Sender<automerge::sync::Message>,
+// 删除的记录：谁在什么时候删除的
+#[derive(Debug, Clone, Default, Reconcile, Hydrate, PartialEq, Serialize, Deserialize)]
+pub struct Deleted {
+    // 删除的 actor
+    pub actor: String,
+    // 毫秒时间戳
+    pub time: i64,
+}
 
-    // 时间锁， 防止并发，crdt太多，cpu会爆炸
-    pub timestamp_lock: tokio::sync::Semaphore,
+sync_record!(Path, "paths", pub_id, deleted, {
+    name => "name",
+    path => "path",
+    description => "description",
+});
 
-    // 失败的共享消息
-    pub failed_messages: Vec<automerge::sync::Message>,
+// doc 里存的是什么
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum DocKind {
+    Path,
+    Folder,
+    // 用 record::register 注册过的记录
+    Record,
 }
 
-pub struct DocInfo {
-    // doc id
-    pub doc_id: ActorId,
-    // doc的权限
+// 分享给其他 peer 的 doc
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Invite {
+    pub id: uuid::Uuid,
+    pub kind: DocKind,
+    pub data: Vec<u8>,
     pub permission: PeerPermission,
-    // doc的crdt
-    pub crdt: automerge::AutoCommit,
-    // 分享状态
-    pub shared: automerge::sync::State,
+    // 发送方截断历史的次数，以及最后一次截断时的 heads
+    #[serde(default)]
+    pub epoch: u64,
+    #[serde(default)]
+    pub base: Vec<automerge::ChangeHash>,
+    // 截断后的 doc 里内容和 base 一样的 heads，没截断过的 peer 的修改接在这后面
+    #[serde(default)]
+    pub rebased: Vec<automerge::ChangeHash>,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    // 没有这个 doc
+    NotFound(uuid::Uuid),
+    // 没有权限修改这个 doc
+    PermissionDenied(uuid::Uuid),
+    // 这种 doc 不支持这个操作
+    Unsupported(uuid::Uuid),
+    // 参数不对
+    InvalidInput(String),
+    // doc 的历史被截断过，两边的历史接不上
+    Truncated(uuid::Uuid),
+    Db(sqlite::Error),
+    Automerge(Box<automerge::AutomergeError>),
+    Reconcile(Box<autosurgeon::ReconcileError>),
+    Hydrate(autosurgeon::HydrateError),
+    Folder(folder::FolderError),
+    Network(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NotFound(id) => write!(f, "doc {id} not found"),
+            Error::PermissionDenied(id) => write!(f, "permission denied for doc {id}"),
+            Error::Unsupported(id) => write!(f, "operation not supported by doc {id}"),
+            Error::InvalidInput(e) => write!(f, "invalid input: {e}"),
+            Error::Truncated(id) => write!(f, "history of doc {id} was truncated"),
+            Error::Db(e) => write!(f, "db error: {e}"),
+            Error::Automerge(e) => write!(f, "automerge error: {e}"),
+            Error::Reconcile(e) => write!(f, "reconcile error: {e}"),
+            Error::Hydrate(e) => write!(f, "hydrate error: {e}"),
+            Error::Folder(e) => write!(f, "{e}"),
+            Error::Network(e) => write!(f, "network error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<sqlite::Error> for Error {
+    fn from(e: sqlite::Error) -> Self {
+        Error::Db(e)
+    }
+}
+
+impl From<automerge::AutomergeError> for Error {
+    fn from(e: automerge::AutomergeError) -> Self {
+        Error::Automerge(Box::new(e))
+    }
+}
+
+impl From<autosurgeon::ReconcileError> for Error {
+    fn from(e: autosurgeon::ReconcileError) -> Self {
+        Error::Reconcile(Box::new(e))
+    }
+}
+
+impl From<autosurgeon::HydrateError> for Error {
+    fn from(e: autosurgeon::HydrateError) -> Self {
+        Error::Hydrate(e)
+    }
+}
+
+impl From<folder::FolderError> for Error {
+    fn from(e: folder::FolderError) -> Self {
+        Error::Folder(e)
+    }
 }
@@ -1,40 +1,65 @@
-// cargo run port 3000
+// cargo run -- --port 3000
 
-use std::{
-    collections::HashMap,
-    sync::{Arc, Mutex},
-    time::Duration,
-};
-
-use serde::{Deserialize, Serialize};
-use sqlite::State;
+use std::{path::PathBuf, sync::Arc, time::Duration};
 
-use autosurgeon::{hydrate, reconcile, Hydrate, Reconcile};
+use clap::Parser;
 use libp2p::{
-    futures::{AsyncReadExt, AsyncWriteExt, StreamExt},
+    futures::StreamExt,
     mdns, noise,
     swarm::{NetworkBehaviour, SwarmEvent},
-    tcp, yamux, PeerId, StreamProtocol,
+    tcp, yamux, PeerId,
 };
 use libp2p_stream as stream;
-use tokio::io::{self, AsyncBufReadExt};
-
-const SYNC_PROTOCOL: StreamProtocol = StreamProtocol::new("/sync");
+use tokio::{
+    io::{self, AsyncBufReadExt},
+    sync::{broadcast, mpsc},
+};
 
-#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
-pub enum PeerPermission {
-    #[default]
-    ReadOnly, // peer只读，只能其他人的变更
-    ReadWrite, // peer可读写，接收和广播变更
-    Owner,     // 可读写，接收和广播变更
-}
+use crdt::{
+    batch::BatchPolicy,
+    db,
+    folder::{Folder, FolderError},
+    history::parse_heads,
+    incoming::IncomingLimits,
+    manager::CompactionPolicy,
+    materialize::{local_path, Materializer},
+    transfer::ContentStore,
+    watcher::{scan, FolderWatcher},
+    CrdtOperation, DocKind, Error, Manager, Path, PeerPermission, SyncMessage, Update,
+};
 
-#[derive(Debug, Clone, Reconcile, Hydrate, PartialEq, Serialize, Deserialize)]
-struct Path {
-    pub_id: uuid::Uuid,
-    name: String,
-    path: String,
-    description: String,
+#[derive(Parser, Debug)]
+struct Args {
+    // http 接口的端口
+    #[arg(long, default_value_t = 3000)]
+    port: u16,
+    // sqlite 数据库文件
+    #[arg(long, default_value = ":memory:")]
+    db: String,
+    // 多少个 change 之后合并成一个完整的快照
+    #[arg(long, default_value_t = 32)]
+    snapshot_every: usize,
+    // 最早的修改超过多少秒、peer 都收到之后截断历史，不设置就不截断
+    #[arg(long)]
+    history_horizon: Option<u64>,
+    // 同时合并几个收到的 doc
+    #[arg(long, default_value_t = 4)]
+    merge_concurrency: usize,
+    // 每个 peer 最多排队几个，超过的拒绝
+    #[arg(long, default_value_t = 8)]
+    merge_queue_per_peer: usize,
+    // 所有 peer 一共最多排队几个
+    #[arg(long, default_value_t = 64)]
+    merge_queue: usize,
+    // 本地修改攒多少毫秒之后一起提交、同步
+    #[arg(long, default_value_t = 200)]
+    batch_window_ms: u64,
+    // 攒够多少个修改马上提交
+    #[arg(long, default_value_t = 64)]
+    batch_max: usize,
+    // 共享的本地文件夹，可以传多个，doc 按文件夹的名字找，没有就新建
+    #[arg(long)]
+    folder: Vec<PathBuf>,
 }
 
 #[derive(NetworkBehaviour)]
@@ -43,71 +68,488 @@ struct MyBehaviour {
     stream: stream::Behaviour,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Invite {
-    pub id: uuid::Uuid,
-    pub data: Vec<u8>,
-    pub permission: PeerPermission,
+fn parse_permission(input: Option<&str>) -> PeerPermission {
+    match input {
+        Some("owner") => PeerPermission::Owner,
+        Some("ro") => PeerPermission::ReadOnly,
+        _ => PeerPermission::ReadWrite,
+    }
 }
 
-#[tokio::main]
-async fn main() {
-    let state: Arc<Mutex<HashMap<uuid::Uuid, automerge::AutoCommit>>> =
-        Arc::new(Mutex::new(HashMap::new()));
-
-    // let args: Vec<String> = env::args().collect();
-
-    // let port = &args[2];
-
-    // let peer = &args[4];
+fn parse_id(input: Option<&str>) -> Result<uuid::Uuid, Error> {
+    let input = input.unwrap_or_default();
+    uuid::Uuid::parse_str(input).map_err(|_| Error::InvalidInput(format!("doc id {input}")))
+}
 
-    // 创建内存sqlite数据库
-    let db = sqlite::open(":memory:").unwrap();
+fn print_paths(manager: &Manager) -> Result<(), Error> {
+    // 查询数据库的 paths 数据
+    let conn = manager.db.lock().unwrap();
+    for (id, path) in db::query_records::<Path>(&conn)? {
+        println!(
+            "id: {}, pub_id: {}, name: {}, path: {}, description: {}",
+            id, path.pub_id, path.name, path.path, path.description
+        );
+    }
+    Ok(())
+}
 
-    let db_arc = Arc::new(Mutex::new(db));
+// 提交攒的本地修改，每个 doc 同步一次
+async fn flush(manager: &Manager) {
+    for flushed in manager.flush().await {
+        for e in flushed.failed {
+            println!("{e}");
+        }
+        if flushed.applied > 0 {
+            println!(
+                "Committed {} edits of doc {}, sent to {} peers",
+                flushed.applied, flushed.id, flushed.sent
+            );
+        }
+    }
+}
 
-    let mut peers: Vec<PeerId> = vec![];
+// 共享文件夹对应的 doc，启动时先把本地已有的文件提交一次
+fn open_folder(manager: &Manager, root: &std::path::Path) -> Result<uuid::Uuid, Error> {
+    let name = root
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| Error::InvalidInput(format!("folder {}", root.display())))?;
+    let id = match manager
+        .list()
+        .into_iter()
+        .find(|doc| doc.kind == DocKind::Folder && doc.name == name)
+    {
+        Some(doc) => doc.id,
+        None => manager.create_folder(&Folder::new(name, ""))?,
+    };
+    let mut folder = manager.folder(&id)?;
+    // 还没下载到本地的文件不能当成删除
+    let ops = scan(root, &mut folder, false)
+        .map_err(|e| Error::InvalidInput(format!("folder {}: {e}", root.display())))?;
+    if let Some(e) = manager.update_all(&id, &ops)?.into_iter().next() {
+        return Err(e);
+    }
+    Ok(id)
+}
 
-    // doc 对应的 id
-    let id: uuid::Uuid = uuid::Uuid::new_v4();
-    println!("doc id: {id}");
+// 本地文件的变更提交到 doc 之后马上同步
+fn watch_folder(
+    manager: Arc<Manager>,
+    id: uuid::Uuid,
+    root: PathBuf,
+    store: Arc<ContentStore>,
+) -> Result<(), Error> {
+    let mut watcher = FolderWatcher::new(&root, Duration::from_millis(500))
+        .map_err(|e| Error::InvalidInput(format!("watch {}: {e}", root.display())))?;
+    tokio::spawn(async move {
+        while let Some(result) = watcher.sync(&manager, &id).await {
+            match result {
+                Ok(ops) if ops.is_empty() => {}
+                Ok(_) => {
+                    index_folder(&manager, id, &root, &store).await;
+                    if let Err(e) = manager.sync(&id).await {
+                        eprintln!("sync {id} failed: {e}");
+                    }
+                }
+                Err(e) => eprintln!("watch {} failed: {e}", root.display()),
+            }
+        }
+    });
+    Ok(())
+}
 
-    // create a path table with:name path description
-    let query = format!(
-        "
-    CREATE TABLE IF NOT EXISTS paths (
-        id INTEGER PRIMARY KEY, 
-        pub_id TEXT NOT NULL,
-        name TEXT NOT NULL,
-        path TEXT NOT NULL, 
-        description TEXT NOT NULL
-    );
-    INSERT INTO paths VALUES (NULL, '{}', 'test', 'test', 'test');
-",
-        id.to_string()
-    );
+// 登记本地已有的文件，其他 peer 按 hash 来下载
+async fn index_folder(
+    manager: &Manager,
+    id: uuid::Uuid,
+    root: &std::path::Path,
+    store: &Arc<ContentStore>,
+) {
+    let folder = match manager.folder(&id) {
+        Ok(folder) => folder,
+        Err(e) => return eprintln!("index {} failed: {e}", root.display()),
+    };
+    let (root, store) = (root.to_path_buf(), store.clone());
+    let _ = tokio::task::spawn_blocking(move || store.index_folder(&root, &folder)).await;
+}
 
-    // 记录 test 记录的 doc
-    let mut doc: automerge::AutoCommit = automerge::AutoCommit::new();
+// 从分享过这个文件夹的 peer 下载本地没有的文件内容
+async fn fetch_file(
+    manager: &Manager,
+    mut control: stream::Control,
+    id: uuid::Uuid,
+    root: &std::path::Path,
+    pub_id: uuid::Uuid,
+) -> Result<(), Error> {
+    let folder = manager.folder(&id)?;
+    let rel = folder
+        .entries()
+        .into_iter()
+        .find(|(_, entry, _)| *entry == pub_id)
+        .map(|(rel, _, _)| rel)
+        .ok_or(Error::NotFound(pub_id))?;
+    // 下载的内容只能写到共享文件夹里面
+    let target = local_path(root, &rel).ok_or(Error::Folder(FolderError::InvalidName(rel)))?;
+    let file = folder.find_file(&pub_id).ok_or(Error::NotFound(pub_id))?;
+    let peers: Vec<PeerId> = manager
+        .shared
+        .lock()
+        .unwrap()
+        .get(&id)
+        .map(|info| info.peers.keys().copied().collect())
+        .unwrap_or_default();
+    crdt::transfer::fetch(&mut control, &peers, &file.hash, file.size, &target)
+        .await
+        .map_err(|e| Error::Network(format!("fetch {}: {e}", file.hash)))
+}
 
-    let path = Path {
-        pub_id: id,
-        name: "test".to_string(),
-        path: "test".to_string(),
-        description: "test".to_string(),
-    };
-    reconcile(&mut doc, &path).unwrap();
+// 其他 peer 的修改落地到本地文件夹，缺的文件内容下载下来
+fn materialize_folder(
+    manager: Arc<Manager>,
+    id: uuid::Uuid,
+    root: PathBuf,
+    store: Arc<ContentStore>,
+    control: stream::Control,
+) -> Result<(), Error> {
+    let last = manager.folder(&id)?;
+    let (tx, mut reports) = mpsc::unbounded_channel();
+    Materializer::new(&root)
+        .with_last(last)
+        .spawn(manager.clone(), id, tx);
+    tokio::spawn(async move {
+        while let Some(report) = reports.recv().await {
+            match report {
+                Ok(report) => {
+                    for conflict in report.conflicts {
+                        println!("conflict in {}: {conflict:?}", root.display());
+                    }
+                    for (pub_id, path) in report.rejected {
+                        eprintln!("skip {pub_id} in {}: unsafe path {path}", root.display());
+                    }
+                    // 改名、移动过的文件路径变了
+                    index_folder(&manager, id, &root, &store).await;
+                    for (pub_id, _) in report.missing {
+                        let (manager, control) = (manager.clone(), control.clone());
+                        let (root, store) = (root.clone(), store.clone());
+                        tokio::spawn(async move {
+                            match fetch_file(&manager, control, id, &root, pub_id).await {
+                                Ok(()) => index_folder(&manager, id, &root, &store).await,
+                                Err(e) => eprintln!("{e}"),
+                            }
+                        });
+                    }
+                }
+                Err(e) => eprintln!("materialize {} failed: {e}", root.display()),
+            }
+        }
+    });
+    Ok(())
+}
 
-    {
-        // 存入state
-        state.lock().unwrap().insert(id, doc.clone());
+// 修改先攒起来，攒够了马上提交
+async fn queue(manager: &Manager, id: uuid::Uuid, op: CrdtOperation) -> Result<(), Error> {
+    if manager.queue(&id, op)? {
+        flush(manager).await;
     }
+    Ok(())
+}
 
-    let conn = db_arc.lock().unwrap();
+// 处理一行命令，doc 都用 uuid 指定
+async fn handle_command(
+    manager: &Manager,
+    default_id: uuid::Uuid,
+    input: &str,
+) -> Result<(), Error> {
+    let mut parts = input.splitn(3, ' ');
+    let command = parts.next().unwrap_or_default();
+    match command {
+        "list" => {
+            for doc in manager.list() {
+                println!(
+                    "{} {:?} {} {:?} peers: {:?}",
+                    doc.id, doc.kind, doc.name, doc.permission, doc.peers
+                );
+            }
+        }
+        "peers" => {
+            for peer in manager.known_peers() {
+                println!("{peer}");
+            }
+        }
+        "status" => {
+            // status [doc id]，不指定 doc 就是所有的 doc
+            let docs = match parts.next() {
+                Some(id) => {
+                    let id = parse_id(Some(id))?;
+                    vec![(id, manager.status(&id)?)]
+                }
+                None => manager
+                    .statuses()
+                    .into_iter()
+                    .map(|doc| (doc.id, doc.peers))
+                    .collect(),
+            };
+            for (id, peers) in docs {
+                println!("{id}:");
+                for p in peers {
+                    println!(
+                        "  {} {:?} {:?} last synced: {:?}",
+                        p.peer, p.permission, p.state, p.last_synced
+                    );
+                }
+            }
+        }
+        "stats" => {
+            let stats = manager.incoming_stats();
+            println!(
+                "queued: {} (max {}), active: {}, accepted: {}, refused: {}, merged: {}, failed: {}",
+                stats.queued,
+                stats.max_queued,
+                stats.active,
+                stats.accepted,
+                stats.refused,
+                stats.merged,
+                stats.failed
+            );
+            for (peer, pending) in stats.peers {
+                println!("{peer}: {pending}");
+            }
+        }
+        "create" => {
+            let name = parts.next().unwrap_or("untitled");
+            let id = manager.create_path(name, name, "")?;
+            println!("doc id: {id}");
+        }
+        "folder" => {
+            let name = parts.next().unwrap_or("untitled");
+            let id = manager.create_folder(&Folder::new(name, ""))?;
+            println!("doc id: {id}");
+        }
+        "show" => {
+            let id = parse_id(parts.next())?;
+            match manager.kind(&id)? {
+                DocKind::Path => println!("{:#?}", manager.path(&id)?),
+                DocKind::Folder => println!("{:#?}", manager.folder(&id)?),
+                DocKind::Record => println!("{:#}", manager.record_json(&id)?),
+            }
+        }
+        "rename" | "describe" => {
+            // rename <doc id> <name> / describe <doc id> <description>
+            let id = parse_id(parts.next())?;
+            let value = parts.next().unwrap_or_default().to_string();
+            let update = match command {
+                "rename" => Update::Name(value),
+                _ => Update::Description(value),
+            };
+            queue(manager, id, CrdtOperation::Update(id, update)).await?;
+        }
+        "history" => {
+            let id = parse_id(parts.next())?;
+            for entry in manager.history(&id)? {
+                println!(
+                    "{} by {} at {} {:?} {:?}",
+                    entry.hash,
+                    entry.actor,
+                    entry.time,
+                    entry.message.unwrap_or_default(),
+                    entry.fields
+                );
+            }
+        }
+        "at" => {
+            // at <doc id> <hash,hash>
+            let id = parse_id(parts.next())?;
+            let heads = parse_heads(parts.next().unwrap_or_default())?;
+            match manager.kind(&id)? {
+                DocKind::Path => println!("{:#?}", manager.hydrate_at::<Path>(&id, &heads)?),
+                DocKind::Folder => println!("{:#?}", manager.folder_at(&id, &heads)?),
+                DocKind::Record => return Err(Error::Unsupported(id)),
+            }
+        }
+        "diff" => {
+            // diff <doc id> <before> [after]，heads 用逗号分隔，- 表示空
+            let id = parse_id(parts.next())?;
+            let mut rest = parts.next().unwrap_or_default().split(' ');
+            let before = parse_heads(rest.next().unwrap_or_default().trim_matches('-'))?;
+            let after = match rest.next() {
+                Some(after) => parse_heads(after)?,
+                None => manager.heads(&id)?,
+            };
+            for d in manager.diff(&id, &before, &after)? {
+                println!("{}: {:?} -> {:?}", d.field, d.before, d.after);
+            }
+        }
+        "revert" => {
+            // revert <doc id> <hash,hash>
+            let id = parse_id(parts.next())?;
+            let changes = parse_heads(parts.next().unwrap_or_default())?;
+            manager.revert(&id, &changes)?;
+            let sent = manager.sync(&id).await?;
+            println!("Reverted doc {id}, sent to {sent} peers");
+        }
+        "undo" => {
+            // undo <doc id> [n]
+            let id = parse_id(parts.next())?;
+            let n = parts.next().unwrap_or("1");
+            let n = n
+                .parse()
+                .map_err(|_| Error::InvalidInput(format!("count {n}")))?;
+            manager.undo(&id, n)?;
+            let sent = manager.sync(&id).await?;
+            println!("Undid {n} changes of doc {id}, sent to {sent} peers");
+        }
+        "conflicts" => {
+            let id = parse_id(parts.next())?;
+            for conflict in manager.conflicts(&id)? {
+                println!("{}:", conflict.field);
+                for (i, c) in conflict.candidates.iter().enumerate() {
+                    let winner = if c.winner { " *" } else { "" };
+                    println!(
+                        "  [{i}] {} by {} at {}{winner}",
+                        c.value, c.actor, c.timestamp
+                    );
+                }
+            }
+        }
+        "collisions" => {
+            let id = parse_id(parts.next())?;
+            for c in manager.collisions(&id)? {
+                println!("{} in {}: {} -> {}", c.pub_id, c.folder, c.name, c.resolved);
+            }
+        }
+        "resolve" => {
+            // resolve <doc id> <field> <choice>
+            let id = parse_id(parts.next())?;
+            let mut rest = parts.next().unwrap_or_default().split(' ');
+            let field = rest.next().unwrap_or_default();
+            let choice = rest.next().unwrap_or_default();
+            let choice = choice
+                .parse()
+                .map_err(|_| Error::InvalidInput(format!("choice {choice}")))?;
+            manager.resolve(&id, field, choice)?;
+            let sent = manager.sync(&id).await?;
+            println!("Resolved {field} of doc {id}, sent to {sent} peers");
+        }
+        "splice" => {
+            // splice <doc id> <index> <delete> [text]
+            let id = parse_id(parts.next())?;
+            let mut rest = parts.next().unwrap_or_default().splitn(3, ' ');
+            let mut number = |name: &str| {
+                let value = rest.next().unwrap_or_default();
+                value
+                    .parse::<usize>()
+                    .map_err(|_| Error::InvalidInput(format!("{name} {value}")))
+            };
+            let index = number("index")?;
+            let delete = number("delete")?;
+            let insert = rest.next().unwrap_or_default().to_string();
+            let update = Update::SpliceDescription {
+                index,
+                delete,
+                insert,
+            };
+            queue(manager, id, CrdtOperation::Update(id, update)).await?;
+        }
+        "delete" => {
+            // delete <doc id> [item id]，不指定条目就是删除 doc 自己
+            let id = parse_id(parts.next())?;
+            let item = match parts.next() {
+                Some(item) => parse_id(Some(item))?,
+                None => id,
+            };
+            queue(manager, id, CrdtOperation::Delete(item)).await?;
+        }
+        "purge" => {
+            // purge <doc id>
+            let id = parse_id(parts.next())?;
+            let purged = manager.purge(&id)?;
+            println!("Purged {purged} deleted items of doc {id}");
+        }
+        "share" => {
+            // share <doc id> <peer id> [ro|rw|owner]
+            let id = parse_id(parts.next())?;
+            let mut rest = parts.next().unwrap_or_default().split(' ');
+            let peer = rest.next().unwrap_or_default();
+            let peer: PeerId = peer
+                .parse()
+                .map_err(|_| Error::InvalidInput(format!("peer id {peer}")))?;
+            manager.share(&id, peer, parse_permission(rest.next()))?;
+            let sent = manager.sync(&id).await?;
+            println!("Shared doc {id} with {peer}, sent to {sent} peers");
+        }
+        "sql" => {
+            // sql <statement>，写入 paths 的修改会变成 doc 的操作
+            let sql = input.trim_start_matches("sql").trim();
+            for id in manager.execute(sql)? {
+                let sent = manager.sync(&id).await?;
+                println!("Updated doc {id}, sent to {sent} peers");
+            }
+        }
+        "check" => {
+            let mismatches = manager.check()?;
+            for m in mismatches.iter() {
+                println!(
+                    "{} {} {}: expected {:?}, found {:?}",
+                    m.table,
+                    m.pub_id,
+                    m.column.as_deref().unwrap_or("<row>"),
+                    m.expected,
+                    m.found
+                );
+            }
+            println!("{} mismatches", mismatches.len());
+        }
+        "reindex" => {
+            let docs = manager.reindex()?;
+            println!("Reindexed {docs} docs");
+        }
+        "compact" => {
+            // compact <doc id>
+            let id = parse_id(parts.next())?;
+            if manager.compact(&id)? {
+                let sent = manager.sync(&id).await?;
+                println!("Truncated history of doc {id}, sent to {sent} peers");
+            } else {
+                println!("Compacted doc {id}");
+            }
+        }
+        "flush" => flush(manager).await,
+        "paths" => print_paths(manager)?,
+        "sync" => {
+            flush(manager).await;
+            // 不指定 doc 时，把默认的 doc 分享给所有发现的 peer
+            let id = match parts.next() {
+                Some(id) => parse_id(Some(id))?,
+                None => {
+                    for peer in manager.known_peers() {
+                        manager.share(&default_id, peer, PeerPermission::ReadWrite)?;
+                    }
+                    default_id
+                }
+            };
+            let sent = manager.sync(&id).await?;
+            println!("Sent doc {id} to {sent} peers");
+        }
+        input => {
+            // input 修改默认 doc 的 name
+            let update = Update::Name(input.to_string());
+            queue(
+                manager,
+                default_id,
+                CrdtOperation::Update(default_id, update),
+            )
+            .await?;
+        }
+    }
+    Ok(())
+}
 
-    conn.execute(query).unwrap();
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
 
-    drop(conn);
+    // 创建sqlite数据库
+    let db = sqlite::open(&args.db).unwrap();
 
     // 运行p2p服务
     let mut swarm = libp2p::SwarmBuilder::with_new_identity()
@@ -133,170 +575,138 @@ async fn main() {
         .listen_on("/ip4/0.0.0.0/tcp/0".parse().unwrap())
         .unwrap();
 
-    let mut stdin = io::BufReader::new(io::stdin()).lines();
+    let policy = CompactionPolicy {
+        snapshot_every: args.snapshot_every,
+        history_horizon: args.history_horizon.map(Duration::from_secs),
+    };
+    let limits = IncomingLimits {
+        concurrency: args.merge_concurrency,
+        per_peer: args.merge_queue_per_peer,
+        capacity: args.merge_queue,
+    };
+    // 单独同步的文件物化到 assets 表
+    crdt::record::register::<crdt::folder::AssetObject>();
+
+    let manager = Arc::new(
+        Manager::new(db, swarm.behaviour().stream.new_control())
+            .unwrap()
+            .with_policy(policy)
+            .with_incoming(limits)
+            .with_batch(BatchPolicy {
+                window: Duration::from_millis(args.batch_window_ms),
+                max_ops: args.batch_max.max(1),
+            }),
+    );
 
-    let mut incoming_streams = swarm
-        .behaviour()
-        .stream
-        .new_control()
-        .accept(SYNC_PROTOCOL)
-        .unwrap();
+    // 默认的 test doc，数据库里已经有了就用原来的
+    let id = match manager
+        .list()
+        .into_iter()
+        .find(|doc| doc.kind == DocKind::Path && doc.name == "test")
+    {
+        Some(doc) => doc.id,
+        None => manager.create_path("test", "test", "test").unwrap(),
+    };
+    println!("doc id: {id}");
+
+    // 其他 peer 按 hash 下载文件内容
+    let content_store = Arc::new(ContentStore::new());
+    crdt::transfer::listen(
+        swarm.behaviour().stream.new_control(),
+        content_store.clone(),
+    )
+    .unwrap();
+
+    // 共享的本地文件夹，本地的修改提交到 doc，其他 peer 的修改落地到本地
+    for root in args.folder.iter() {
+        let store = content_store.clone();
+        let control = swarm.behaviour().stream.new_control();
+        let folder = open_folder(&manager, root).and_then(|folder| {
+            watch_folder(manager.clone(), folder, root.clone(), store.clone())?;
+            materialize_folder(
+                manager.clone(),
+                folder,
+                root.clone(),
+                store.clone(),
+                control,
+            )?;
+            Ok(folder)
+        });
+        match folder {
+            Ok(folder) => {
+                index_folder(&manager, folder, root, &store).await;
+                println!("folder {}: doc id {folder}", root.display());
+            }
+            Err(e) => eprintln!("{e}"),
+        }
+    }
 
-    let db_arc_copy = db_arc.clone();
-    let state_copy = state.clone();
+    // 接收其他 peer 同步的 doc
+    manager.clone().listen().unwrap();
 
+    // 打印收到的其他 peer 的修改
+    let mut events = manager.subscribe();
     tokio::spawn(async move {
-        while let Some((_peer, mut stream)) = incoming_streams.next().await {
-            // 读取stream数据
-            let mut buf = Vec::new();
-            stream.read_to_end(&mut buf).await.unwrap();
-            // 解析数据
-            let invite: Invite = serde_json::from_slice(&buf).unwrap();
-            let data = invite.data;
-            let id = invite.id;
-
-            // state中是否存在id
-            if state_copy.lock().unwrap().contains_key(&id) {
-                let state_copy2 = state_copy.clone();
-                // 存在则更新
-                // 从state中拿到doc
-                let state_lock = state_copy2.lock().unwrap();
-
-                let mut doc = state_lock.get(&id).unwrap().clone();
-                // 更新doc
-                let other_doc = automerge::AutoCommit::load(&data).unwrap();
-                let mut doc_fork = doc.fork();
-                // other_doc 读取数据
-                let other_doc_path: Path = hydrate(&other_doc).unwrap();
-                // 更新 doc_fork
-                reconcile(&mut doc_fork, &other_doc_path).unwrap();
-                doc.merge(&mut doc_fork).expect("Failed to merge doc");
-
-                // 更新数据库
-                update_db(db_arc_copy.clone(), doc.clone());
-            } else {
-                let other_doc = automerge::AutoCommit::load(&data).unwrap();
-                // 插入数据库
-                insert_db(db_arc_copy.clone(), other_doc.clone());
-                // 不存在则插入
-                state_copy.lock().unwrap().insert(id, other_doc);
+        loop {
+            match events.recv().await {
+                Ok(SyncMessage::Ingested { id, peer, heads }) => {
+                    println!("Received doc {id} from {peer}, heads: {heads:?}");
+                }
+                Ok(_) => {}
+                Err(broadcast::error::RecvError::Lagged(n)) => eprintln!("missed {n} events"),
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+
+    // 其他程序直接写入数据库的修改，定时变成 doc 的操作
+    let ingester = manager.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            let changed = match ingester.ingest() {
+                Ok(changed) => changed,
+                Err(e) => {
+                    eprintln!("ingest failed: {e}");
+                    continue;
+                }
+            };
+            for id in changed {
+                if let Err(e) = ingester.sync(&id).await {
+                    eprintln!("sync {id} failed: {e}");
+                }
             }
         }
     });
 
-    let db_loop = db_arc.clone();
-    let state_loop = state.clone();
+    // http 接口
+    let listener = tokio::net::TcpListener::bind(("0.0.0.0", args.port))
+        .await
+        .unwrap();
+    println!("http listening on {}", listener.local_addr().unwrap());
+    let router = crdt::api::router(manager.clone());
+    tokio::spawn(async move { axum::serve(listener, router).await });
+
+    let mut stdin = io::BufReader::new(io::stdin()).lines();
+
     loop {
+        // 没有攒的修改时不会等到
+        let due = manager
+            .batch
+            .due()
+            .map(tokio::time::Instant::from_std)
+            .unwrap_or_else(|| tokio::time::Instant::now() + Duration::from_secs(3600));
         tokio::select! {
+            _ = tokio::time::sleep_until(due) => flush(&manager).await,
             Ok(Some(line)) = stdin.next_line() => {
                 let input = line.trim();
-                match input {
-                    "exit" => break,
-                    "sync" => {
-                        let peer_id = peers[0];
-                        // 发送 doc 数据
-                        let sync_request = serde_json::to_vec(&Invite {
-                            id,
-                            data: doc.save(),
-                            permission: PeerPermission::ReadWrite,
-                        }).expect("Failed to serialize sync request");
-
-                        // open new stream
-                        let mut stream = swarm.behaviour().stream.new_control().open_stream(peer_id, SYNC_PROTOCOL).await.expect("Failed to open stream");
-                        // println!("Opened stream to: {peer_id}");
-                        // write data to stream
-                        stream.write_all(&sync_request).await.unwrap();
-                        println!("Sent sync request to: {peer_id}")
-                    },
-                    "update" => {
-                        // 更新数据
-                        // 请再输入新的name
-                        println!("Please enter doc id: ");
-                        match stdin.next_line().await {
-                            Ok(Some(line)) => {
-                                let input = line.trim();
-                                println!("Updating doc id: {input}");
-                                let id = uuid::Uuid::parse_str(input).unwrap();
-
-                                // 拿到doc
-
-                                match state_loop.lock().unwrap().clone().get_mut(&id){
-                                    Some(doc) => {
-                                        // 从doc拿到数据
-                                        let path: Path = hydrate(doc).unwrap();
-                                        // 更新数据
-                                        let path = Path {
-                                            pub_id: path.pub_id,
-                                            name: "update".to_string(),
-                                            path: path.path,
-                                            description: path.description,
-                                        };
-                                        // 更新doc
-                                        reconcile(doc, &path).unwrap();
-                                        // 更新数据库
-                                        update_db(db_arc.clone(), doc.clone());
-
-                                        // 发送同步数据
-                                        let peer_id = peers[0];
-                                        // 发送 doc 数据
-                                        let sync_request = serde_json::to_vec(&Invite {
-                                            id,
-                                            data: doc.save(),
-                                            permission: PeerPermission::ReadWrite,
-                                        }).expect("Failed to serialize sync request");
-
-                                        // open new stream
-                                        let mut stream = swarm.behaviour().stream.new_control().open_stream(peer_id, SYNC_PROTOCOL).await.expect("Failed to open stream");
-                                        // println!("Opened stream to: {peer_id}");
-                                        // write data to stream
-                                        stream.write_all(&sync_request).await.unwrap();
-                                        // println!("Sent sync request to: {peer_id}")
-                                    },
-                                    None => {
-                                        println!("Doc not found");
-                                    }
-                                }
-                            },
-                            _ => {}
-                        }
-                    },
-                    input => {
-                        // 拿到test数据
-                        let path: Path = hydrate(&doc).unwrap();
-                        // input 修改数据的 name
-                        let path = Path {
-                            pub_id: path.pub_id,
-                            name: input.to_string(),
-                            path: path.path,
-                            description: path.description,
-                        };
-                        // 更新doc
-                        reconcile(&mut doc, &path).unwrap();
-
-                        // 更新数据库
-                        let query = format!("UPDATE paths SET name = '{}' WHERE id = 1;", path.name);
-
-                        let conn = db_loop.lock().unwrap();
-
-                        conn.execute(&query).unwrap();
-
-                        // 查询数据库的 test数据
-                        let query = "SELECT * FROM paths";
-                        let mut stmt = conn.prepare(query).unwrap();
-
-                        while let State::Row = stmt.next().unwrap() {
-                            let id: i64 = stmt.read(0).unwrap();
-                            let pub_id: String = stmt.read(1).unwrap();
-                            let name: String = stmt.read(2).unwrap();
-                            let path: String = stmt.read(3).unwrap();
-                            let description: String = stmt.read(4).unwrap();
-
-                            println!(
-                                "id: {},pub_id:{}, name: {}, path: {}, description: {}",
-                                id,pub_id, name, path, description
-                            );
-                        }
-                    }
+                if input == "exit" {
+                    flush(&manager).await;
+                    break;
+                }
+                if let Err(e) = handle_command(&manager, id, input).await {
+                    println!("{e}");
                 }
             }
             event = swarm.select_next_some() => match event{
@@ -308,12 +718,13 @@ async fn main() {
                         println!("Dialed peer: {peer_id}");
 
                         // 添加peer到peers
-                        peers.push(peer_id);
+                        manager.add_known_peer(peer_id);
                     }
                 },
                 SwarmEvent::Behaviour(MyBehaviourEvent::Mdns(mdns::Event::Expired(list))) => {
                     for (peer_id, _multiaddr) in list {
                         println!("mDNS discover peer has expired: {peer_id}");
+                        manager.remove_known_peer(&peer_id);
                     }
                 },
                 _ => {}
@@ -321,71 +732,3 @@ async fn main() {
         }
     }
 }
-
-pub fn insert_db(conn: Arc<Mutex<sqlite::Connection>>, doc: automerge::AutoCommit) {
-    // 将data序列化到doc
-    // 拿到数据
-    let path: Path = hydrate(&doc).unwrap();
-    // 插入数据库
-    let query = format!(
-        "INSERT INTO paths VALUES (NULL,'{}', '{}', '{}', '{}');",
-        path.pub_id, path.name, path.path, path.description
-    );
-
-    let conn = conn.lock().unwrap();
-
-    conn.execute(&query).unwrap();
-
-    // 查询数据库
-    let query = "SELECT * FROM paths";
-    let mut stmt = conn.prepare(query).unwrap();
-
-    while let State::Row = stmt.next().unwrap() {
-        let id: i64 = stmt.read(0).unwrap();
-        let pub_id: String = stmt.read(1).unwrap();
-        let name: String = stmt.read(2).unwrap();
-        let path: String = stmt.read(3).unwrap();
-        let description: String = stmt.read(4).unwrap();
-
-        println!(
-            "id: {},pub_id: {},  name: {}, path: {}, description: {}",
-            id, pub_id, name, path, description
-        );
-    }
-}
-
-pub fn update_db(conn: Arc<Mutex<sqlite::Connection>>, doc: automerge::AutoCommit) {
-    // 将data序列化到doc
-    // 拿到数据
-    let path: Path = hydrate(&doc).unwrap();
-    // path pub_id
-    let pub_id = path.pub_id;
-
-    // 更新数据库 pub_id
-    // let query = format!("UPDATE paths SET name = '{}' WHERE p = 2;", path.name);
-    let query = format!(
-        "UPDATE paths SET name = '{}', path = '{}', description = '{}' WHERE pub_id = '{}';",
-        path.name, path.path, path.description, pub_id
-    );
-
-    let conn = conn.lock().unwrap();
-
-    conn.execute(&query).unwrap();
-
-    // 查询数据库
-    let query = "SELECT * FROM paths";
-    let mut stmt = conn.prepare(query).unwrap();
-
-    while let State::Row = stmt.next().unwrap() {
-        let id: i64 = stmt.read(0).unwrap();
-        let pub_id: String = stmt.read(1).unwrap();
-        let name: String = stmt.read(2).unwrap();
-        let path: String = stmt.read(3).unwrap();
-        let description: String = stmt.read(4).unwrap();
-
-        println!(
-            "id: {}, pub: {} name: {}, path: {}, description: {}",
-            id, pub_id, name, path, description
-        );
-    }
-}
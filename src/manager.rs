@@ -0,0 +1,1435 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use automerge::{transaction::CommitOptions, ActorId, AutoCommit, ChangeHash, ObjType};
+
+use autosurgeon::{hydrate, reconcile, Hydrate};
+use libp2p::{
+    futures::{AsyncReadExt, AsyncWriteExt, StreamExt},
+    PeerId, StreamProtocol,
+};
+use libp2p_stream as stream;
+use serde::Serialize;
+use tokio::sync::{broadcast, Semaphore};
+use uuid::Uuid;
+
+use crate::{
+    batch::{Batch, BatchPolicy, Flushed},
+    conflict::{self, FieldConflict},
+    db,
+    folder::Folder,
+    history::{self, FieldDiff, HistoryEntry},
+    incoming::{Incoming, IncomingLimits, IncomingStats},
+    migrate,
+    record::{self, SyncRecord},
+    status::{DocStatus, PeerStatus, PeerSync},
+    tree, CrdtOperation, Deleted, DocKind, Error, Invite, Path, PeerPermission, SyncMessage,
+    Update,
+};
+
+// 提交说明
+fn op_message(op: &CrdtOperation) -> String {
+    match op {
+        CrdtOperation::Create(entry) => format!("create {}", entry.path),
+        CrdtOperation::Update(id, update) => {
+            let field = match update {
+                Update::Name(_) => "name",
+                Update::Description(_) | Update::SpliceDescription { .. } => "description",
+                Update::Path(_) => "path",
+                Update::Content { .. } => "content",
+            };
+            format!("update {field} of {id}")
+        }
+        CrdtOperation::Delete(id) => format!("delete {id}"),
+    }
+}
+
+// 同步 doc 的协议
+pub const SYNC_PROTOCOL: StreamProtocol = StreamProtocol::new("/sync");
+
+fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or_default()
+}
+
+fn join_heads(heads: &[ChangeHash]) -> String {
+    heads
+        .iter()
+        .map(|h| h.to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+// 物化出来的表，包括注册过的记录
+fn materialized_tables() -> Vec<(&'static str, &'static [&'static str])> {
+    let mut tables = db::MATERIALIZED.to_vec();
+    for (table, columns) in record::registered() {
+        if !tables.iter().any(|(t, _)| *t == table) {
+            tables.push((table, columns));
+        }
+    }
+    tables
+}
+
+// doc 怎么存储：平时只追加 change，够多了合并成一个完整的快照。
+// 设置了 history_horizon 时，最早的修改超过这个时间、并且分享过的 peer
+// 都已经收到了全部修改，就丢掉历史，只留下当前的内容
+#[derive(Debug, Clone)]
+pub struct CompactionPolicy {
+    pub snapshot_every: usize,
+    pub history_horizon: Option<Duration>,
+}
+
+impl Default for CompactionPolicy {
+    fn default() -> Self {
+        CompactionPolicy {
+            snapshot_every: 32,
+            history_horizon: None,
+        }
+    }
+}
+
+pub struct Manager {
+    // 已经共享的doc
+    pub shared: Mutex<BTreeMap<Uuid, DocInfo>>,
+
+    // 事件，用 subscribe 接收
+    pub sender: broadcast::Sender<SyncMessage>,
+
+    // 时间锁， 防止并发，crdt太多，cpu会爆炸。收到的 doc 拿到许可才合并
+    pub timestamp_lock: Semaphore,
+
+    // 收到的 stream 的排队情况
+    pub incoming: Incoming,
+
+    // 还没有提交的本地修改
+    pub batch: Batch,
+
+    // 失败的共享消息
+    pub failed_messages: Mutex<Vec<automerge::sync::Message>>,
+
+    // doc 物化后的数据库
+    pub db: Mutex<sqlite::Connection>,
+
+    // mdns 发现的 peer
+    pub known_peers: Mutex<Vec<PeerId>>,
+
+    // 打开到其他 peer 的 stream
+    control: stream::Control,
+
+    // doc 的存储和压缩策略
+    policy: CompactionPolicy,
+}
+
+pub struct DocInfo {
+    // doc id
+    pub doc_id: Uuid,
+    // doc 里存的是单个文件还是文件夹
+    pub kind: DocKind,
+    // doc的权限
+    pub permission: PeerPermission,
+    // doc的crdt
+    pub crdt: automerge::AutoCommit,
+    // 分享给了哪些 peer，以及他们的权限
+    pub peers: HashMap<PeerId, PeerPermission>,
+    // 最后一次写入数据库时的 heads，物化失败时是已经写入存储的 heads
+    pub heads: Vec<ChangeHash>,
+    // 截断历史的次数，以及最后一次截断时的 heads
+    pub epoch: u64,
+    pub base: Vec<ChangeHash>,
+    // 截断后内容和 base 一样的 heads，旧 epoch 的修改接在这后面
+    pub rebased: Vec<ChangeHash>,
+    // 和每个 peer 的收发记录
+    pub peer_sync: HashMap<PeerId, PeerSync>,
+    // 最后一次写入存储时的 heads，还没有快照是 None
+    saved: Option<Vec<ChangeHash>>,
+    // 快照之后写入的 change 数量
+    changes: usize,
+}
+
+// 列出 doc 时的摘要
+#[derive(Debug, Clone, Serialize)]
+pub struct DocSummary {
+    pub id: Uuid,
+    pub kind: DocKind,
+    pub name: String,
+    pub permission: PeerPermission,
+    pub peers: Vec<(String, PeerPermission)>,
+}
+
+impl DocInfo {
+    pub fn new(
+        doc_id: Uuid,
+        kind: DocKind,
+        permission: PeerPermission,
+        crdt: automerge::AutoCommit,
+    ) -> Self {
+        DocInfo {
+            doc_id,
+            kind,
+            permission,
+            crdt,
+            peers: HashMap::new(),
+            heads: vec![],
+            epoch: 0,
+            base: vec![],
+            rebased: vec![],
+            peer_sync: HashMap::new(),
+            saved: None,
+            changes: 0,
+        }
+    }
+
+    // 从存储里读出来：快照加上之后的增量
+    fn load(stored: db::StoredDoc) -> Result<Self, Error> {
+        let invalid = || Error::InvalidInput(format!("stored doc {}", stored.pub_id));
+        let doc_id = stored.pub_id.parse().map_err(|_| invalid())?;
+        let kind = serde_json::from_str(&stored.kind).map_err(|_| invalid())?;
+        let permission = serde_json::from_str(&stored.permission).map_err(|_| invalid())?;
+        let peers: HashMap<String, PeerPermission> =
+            serde_json::from_str(&stored.peers).map_err(|_| invalid())?;
+        let actor: ActorId = stored.actor.parse().map_err(|_| invalid())?;
+
+        let mut crdt = AutoCommit::load(&stored.snapshot)?;
+        for change in stored.changes.iter() {
+            crdt.load_incremental(change)?;
+        }
+        crdt.set_actor(actor);
+        let mut info = DocInfo::new(doc_id, kind, permission, crdt);
+        if kind == DocKind::Folder && tree::upgrade(&mut info.crdt)? {
+            info.commit("upgrade folder layout");
+        }
+        for (peer, permission) in peers {
+            info.peers
+                .insert(peer.parse().map_err(|_| invalid())?, permission);
+        }
+        info.epoch = stored.epoch as u64;
+        info.base = history::parse_heads(&stored.base)?;
+        info.rebased = history::parse_heads(&stored.rebased)?;
+        info.heads = info.crdt.get_heads();
+        info.saved = Some(info.heads.clone());
+        info.changes = stored.changes.len();
+        Ok(info)
+    }
+
+    pub fn can_write(&self) -> bool {
+        self.permission != PeerPermission::ReadOnly
+    }
+
+    pub fn summary(&self) -> DocSummary {
+        let name = match self.kind {
+            DocKind::Path => hydrate::<_, Path>(&self.crdt).map(|p| p.name).ok(),
+            DocKind::Folder => tree::hydrate_folder(&self.crdt).map(|f| f.name).ok(),
+            DocKind::Record => record::table_of(&self.crdt),
+        };
+        DocSummary {
+            id: self.doc_id,
+            kind: self.kind,
+            name: name.unwrap_or_default(),
+            permission: self.permission.clone(),
+            peers: self
+                .peers
+                .iter()
+                .map(|(peer, permission)| (peer.to_string(), permission.clone()))
+                .collect(),
+        }
+    }
+
+    // 提交还没提交的修改，带上时间和说明，返回提交后的 heads
+    pub fn commit(&mut self, message: &str) -> Vec<ChangeHash> {
+        self.crdt.commit_with(
+            CommitOptions::default()
+                .with_message(message)
+                .with_time(now_millis()),
+        );
+        self.crdt.get_heads()
+    }
+
+    // 把 doc 的内容和 heads 写入数据库，调用前需要先 commit
+    pub fn materialize(&mut self, conn: &sqlite::Connection) -> Result<(), Error> {
+        self.heads = self.write_rows(conn)?;
+        Ok(())
+    }
+
+    // doc 已经写入存储之后再物化。失败的话表里还是旧的内容，doc 不受影响，reindex 之后就能恢复
+    fn refresh(&mut self, conn: &sqlite::Connection) {
+        if let Err(e) = self.materialize(conn) {
+            eprintln!(
+                "materialize {} failed, reindex to recover: {e}",
+                self.doc_id
+            );
+            self.heads = self.crdt.get_heads();
+        }
+    }
+
+    // 写入 doc 物化出来的行，返回写入的 heads。自己写入的不算应用的修改
+    fn write_rows(&mut self, conn: &sqlite::Connection) -> Result<Vec<ChangeHash>, Error> {
+        db::set_capture(conn, false)?;
+        let result = self.write_tables(conn);
+        db::set_capture(conn, true)?;
+        result
+    }
+
+    fn write_tables(&mut self, conn: &sqlite::Connection) -> Result<Vec<ChangeHash>, Error> {
+        match self.kind {
+            DocKind::Path => db::write_record::<Path>(conn, &hydrate(&self.crdt)?)?,
+            DocKind::Folder => db::sync_folder_db(conn, &self.folder()?)?,
+            DocKind::Record => record::materialize(&self.crdt, conn)?,
+        }
+        let heads = self.crdt.get_heads();
+        db::sync_doc_heads(
+            conn,
+            &self.doc_id.to_string(),
+            &format!("{:?}", self.kind),
+            &heads,
+        )?;
+        Ok(heads)
+    }
+
+    fn peers_json(&self) -> String {
+        let peers: HashMap<String, &PeerPermission> = self
+            .peers
+            .iter()
+            .map(|(peer, permission)| (peer.to_string(), permission))
+            .collect();
+        serde_json::to_string(&peers).unwrap()
+    }
+
+    // 写入完整的快照，之前的增量都不需要了
+    pub fn snapshot(&mut self, conn: &sqlite::Connection) -> Result<(), Error> {
+        let stored = db::StoredDoc {
+            pub_id: self.doc_id.to_string(),
+            kind: serde_json::to_string(&self.kind).unwrap(),
+            permission: serde_json::to_string(&self.permission).unwrap(),
+            peers: self.peers_json(),
+            actor: self.crdt.get_actor().to_hex_string(),
+            epoch: self.epoch as i64,
+            base: join_heads(&self.base),
+            rebased: join_heads(&self.rebased),
+            snapshot: self.crdt.save(),
+            changes: vec![],
+        };
+        db::save_snapshot(conn, &stored)?;
+        self.saved = Some(self.crdt.get_heads());
+        self.changes = 0;
+        Ok(())
+    }
+
+    // 把新的 change 逐个追加到存储，攒到 snapshot_every 个就合并成快照
+    pub fn persist(
+        &mut self,
+        conn: &sqlite::Connection,
+        policy: &CompactionPolicy,
+    ) -> Result<(), Error> {
+        let Some(saved) = &self.saved else {
+            return self.snapshot(conn);
+        };
+        let heads = self.crdt.get_heads();
+        if &heads == saved {
+            return Ok(());
+        }
+        // 本地的和收到的 change 都是从上次写入存储的 heads 开始算
+        let changes: Vec<_> = self
+            .crdt
+            .get_changes(saved)
+            .into_iter()
+            .map(db::StoredChange::from)
+            .collect();
+        if self.changes + changes.len() >= policy.snapshot_every {
+            return self.snapshot(conn);
+        }
+        db::append_changes(conn, &self.doc_id.to_string(), &changes)?;
+        self.saved = Some(heads);
+        self.changes += changes.len();
+        Ok(())
+    }
+
+    // 发给每个分享过的 peer 的 doc，发送的是已经写入数据库的 heads
+    fn invites(&mut self) -> Vec<(PeerId, Invite)> {
+        let data = self.crdt.save();
+        debug_assert_eq!(self.crdt.get_heads(), self.heads);
+        self.peers
+            .iter()
+            .map(|(peer, permission)| {
+                let invite = Invite {
+                    id: self.doc_id,
+                    kind: self.kind,
+                    data: data.clone(),
+                    permission: permission.clone(),
+                    epoch: self.epoch,
+                    base: self.base.clone(),
+                    rebased: self.rebased.clone(),
+                };
+                (*peer, invite)
+            })
+            .collect()
+    }
+
+    // 按 peer 排好序，没有收发过的 peer 落后全部的修改
+    fn status(&mut self) -> Vec<PeerStatus> {
+        let mut status: Vec<_> = self
+            .peers
+            .iter()
+            .map(|(peer, permission)| {
+                let sync = self.peer_sync.get(peer).cloned().unwrap_or_default();
+                PeerStatus {
+                    peer: peer.to_string(),
+                    permission: permission.clone(),
+                    state: sync.status(&mut self.crdt),
+                    last_synced: sync.last_synced,
+                }
+            })
+            .collect();
+        status.sort_by(|a, b| a.peer.cmp(&b.peer));
+        status
+    }
+
+    // 分享过的 peer 都已经有了当前的全部修改
+    fn acked_by_all(&mut self) -> bool {
+        for peer in self.peers.keys() {
+            let Some(heads) = self.peer_sync.get(peer).and_then(PeerSync::acked) else {
+                return false;
+            };
+            if !self.crdt.get_changes(heads).is_empty() {
+                return false;
+            }
+        }
+        true
+    }
+
+    // 按策略可以截断的话，返回截断到哪里：早于 horizon 的 change 的 heads
+    pub fn truncation_base(&mut self, policy: &CompactionPolicy) -> Option<Vec<ChangeHash>> {
+        let horizon = policy.history_horizon?;
+        // 只有 owner 截断，避免几个 peer 同时截断出不同的历史
+        if self.permission != PeerPermission::Owner {
+            return None;
+        }
+        let cutoff = now_millis() - horizon.as_millis() as i64;
+        let (base, settled, _) = history::settled(&mut self.crdt, cutoff);
+        (settled >= 2 && self.acked_by_all()).then_some(base)
+    }
+
+    // 丢掉 base 之前的历史，换成 base 时的快照，之后的 change 重新写在快照后面。
+    // epoch 加一，peer 收到之后整个替换掉旧的 doc，不会和截断前的历史合并
+    pub fn truncate(&mut self, base: &[ChangeHash]) -> Result<(), Error> {
+        let heads = self.crdt.get_heads();
+        let latest = base
+            .iter()
+            .filter_map(|h| self.crdt.get_change_by_hash(h).map(|c| c.timestamp()))
+            .max()
+            .unwrap_or_default();
+        let snapshot = self.crdt.fork_at(base)?;
+        let mut crdt = AutoCommit::new().with_actor(self.crdt.get_actor().clone());
+        history::copy_object(
+            &mut crdt,
+            &snapshot,
+            &automerge::ROOT,
+            &automerge::ROOT,
+            ObjType::Map,
+        )?;
+        // 快照的时间是 base 里最晚的，下次截断时和 base 一起算作旧的
+        crdt.commit_with(
+            CommitOptions::default()
+                .with_message("truncate history")
+                .with_time(latest),
+        );
+        history::replay(&mut crdt, &mut self.crdt, base)?;
+        self.rebased = crdt.get_heads();
+        self.crdt = crdt;
+        self.epoch += 1;
+        self.base = heads;
+        self.peer_sync.values_mut().for_each(PeerSync::reset);
+        self.saved = None;
+        Ok(())
+    }
+
+    // 文件夹的内容，按移动记录组装成树
+    pub fn folder(&self) -> Result<Folder, Error> {
+        tree::hydrate_folder(&self.crdt)
+    }
+
+    // 本地现在删除
+    fn deleted(&self) -> Deleted {
+        Deleted {
+            actor: self.crdt.get_actor().to_hex_string(),
+            time: now_millis(),
+        }
+    }
+
+    // 把操作应用到 doc 上
+    pub fn apply(&mut self, op: &CrdtOperation) -> Result<(), Error> {
+        match self.kind {
+            DocKind::Path => {
+                let mut path: Path = hydrate(&self.crdt)?;
+                let (CrdtOperation::Update(pub_id, _) | CrdtOperation::Delete(pub_id)) = op else {
+                    return Err(Error::Unsupported(self.doc_id));
+                };
+                // 删除了就不能再修改
+                if pub_id != &path.pub_id || path.deleted.is_some() {
+                    return Err(Error::NotFound(*pub_id));
+                }
+                let CrdtOperation::Update(_, update) = op else {
+                    path.deleted = Some(self.deleted());
+                    reconcile(&mut self.crdt, &path)?;
+                    return Ok(());
+                };
+                match update {
+                    Update::Name(name) => path.name = name.clone(),
+                    Update::Description(description) => path.description.update(description),
+                    Update::SpliceDescription {
+                        index,
+                        delete,
+                        insert,
+                    } => {
+                        if !path.description.splice(*index, *delete, insert) {
+                            return Err(Error::InvalidInput(format!("splice at {index}")));
+                        }
+                    }
+                    Update::Path(p) => path.path = p.clone(),
+                    Update::Content { .. } => return Err(Error::Unsupported(self.doc_id)),
+                }
+                reconcile(&mut self.crdt, &path)?;
+            }
+            DocKind::Folder => {
+                let deleted = self.deleted();
+                tree::edit_folder(&mut self.crdt, |folder| {
+                    match op {
+                        CrdtOperation::Delete(pub_id) => folder.delete(pub_id, deleted)?,
+                        op => folder.apply(op)?,
+                    }
+                    Ok(())
+                })?;
+            }
+            DocKind::Record => {
+                // 记录的字段用 update_record 修改，这里只能删除
+                let CrdtOperation::Delete(pub_id) = op else {
+                    return Err(Error::Unsupported(self.doc_id));
+                };
+                if pub_id != &self.doc_id || record::deleted(&self.crdt)?.is_some() {
+                    return Err(Error::NotFound(*pub_id));
+                }
+                let deleted = self.deleted();
+                record::delete(&mut self.crdt, &deleted)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Manager {
+    // 打开数据库，读出之前存储的 doc
+    pub fn new(db: sqlite::Connection, control: stream::Control) -> Result<Self, Error> {
+        migrate::migrate(&db)?;
+        let mut shared = BTreeMap::new();
+        for stored in db::load_docs(&db)? {
+            let info = DocInfo::load(stored)?;
+            shared.insert(info.doc_id, info);
+        }
+        // 接收得太慢的 subscriber 会丢掉旧的事件
+        let (sender, _) = broadcast::channel(256);
+        Ok(Manager {
+            shared: Mutex::new(shared),
+            sender,
+            timestamp_lock: Semaphore::new(IncomingLimits::default().concurrency),
+            incoming: Incoming::new(IncomingLimits::default()),
+            batch: Batch::new(BatchPolicy::default()),
+            failed_messages: Mutex::new(vec![]),
+            db: Mutex::new(db),
+            known_peers: Mutex::new(vec![]),
+            control,
+            policy: CompactionPolicy::default(),
+        })
+    }
+
+    // 订阅之后发生的事件
+    pub fn subscribe(&self) -> broadcast::Receiver<SyncMessage> {
+        self.sender.subscribe()
+    }
+
+    // 没有 subscriber 时丢掉
+    fn emit(&self, message: SyncMessage) {
+        let _ = self.sender.send(message);
+    }
+
+    pub fn with_policy(mut self, policy: CompactionPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn with_incoming(mut self, limits: IncomingLimits) -> Self {
+        self.timestamp_lock = Semaphore::new(limits.concurrency);
+        self.incoming = Incoming::new(limits);
+        self
+    }
+
+    pub fn with_batch(mut self, policy: BatchPolicy) -> Self {
+        self.batch = Batch::new(policy);
+        self
+    }
+
+    // 提交之后写入存储和数据库，按策略截断历史。
+    // 提交了的 change 可能已经发给别的 peer 了，先写入存储，物化失败也不会丢
+    fn store(&self, info: &mut DocInfo) -> Result<(), Error> {
+        let conn = self.db.lock().unwrap();
+        if let Some(base) = info.truncation_base(&self.policy) {
+            info.truncate(&base)?;
+        }
+        info.persist(&conn, &self.policy)?;
+        info.refresh(&conn);
+        Ok(())
+    }
+
+    fn insert(&self, mut info: DocInfo) -> Result<Uuid, Error> {
+        let id = info.doc_id;
+        let mut shared = self.shared.lock().unwrap();
+        info.commit("create");
+        self.store(&mut info)?;
+        self.emit(SyncMessage::Created {
+            id,
+            kind: info.kind,
+        });
+        shared.insert(id, info);
+        Ok(id)
+    }
+
+    // 新建共享的单个文件，doc id 就是 pub_id
+    pub fn create_path(&self, name: &str, path: &str, description: &str) -> Result<Uuid, Error> {
+        self.insert_path(Path {
+            pub_id: Uuid::new_v4(),
+            name: name.to_string(),
+            path: path.to_string(),
+            description: description.into(),
+            deleted: None,
+        })
+    }
+
+    fn insert_path(&self, path: Path) -> Result<Uuid, Error> {
+        let mut crdt = automerge::AutoCommit::new();
+        reconcile(&mut crdt, &path)?;
+        self.insert(DocInfo::new(
+            path.pub_id,
+            DocKind::Path,
+            PeerPermission::Owner,
+            crdt,
+        ))
+    }
+
+    // 新建共享文件夹，doc id 是根文件夹的 pub_id
+    pub fn create_folder(&self, folder: &Folder) -> Result<Uuid, Error> {
+        let crdt = tree::new_doc(folder)?;
+        self.insert(DocInfo::new(
+            folder.pub_id,
+            DocKind::Folder,
+            PeerPermission::Owner,
+            crdt,
+        ))
+    }
+
+    // 新建一条共享的记录，doc id 就是记录的 pub_id，记录的类型需要先注册
+    pub fn create_record<T: SyncRecord>(&self, record: &T) -> Result<Uuid, Error> {
+        let crdt = record::new_doc(record)?;
+        self.insert(DocInfo::new(
+            record.pub_id(),
+            DocKind::Record,
+            PeerPermission::Owner,
+            crdt,
+        ))
+    }
+
+    pub fn record<T: SyncRecord>(&self, id: &Uuid) -> Result<T, Error> {
+        let shared = self.shared.lock().unwrap();
+        let info = shared.get(id).ok_or(Error::NotFound(*id))?;
+        if info.kind != DocKind::Record {
+            return Err(Error::Unsupported(*id));
+        }
+        record::hydrate_doc(&info.crdt)
+    }
+
+    // 按注册的类型读出记录，不需要知道具体的类型
+    pub fn record_json(&self, id: &Uuid) -> Result<serde_json::Value, Error> {
+        let shared = self.shared.lock().unwrap();
+        let info = shared.get(id).ok_or(Error::NotFound(*id))?;
+        if info.kind != DocKind::Record {
+            return Err(Error::Unsupported(*id));
+        }
+        record::json(&info.crdt)
+    }
+
+    // 修改记录，和 update 一样提交、写入数据库
+    pub fn update_record<T: SyncRecord>(
+        &self,
+        id: &Uuid,
+        f: impl FnOnce(&mut T),
+    ) -> Result<Vec<ChangeHash>, Error> {
+        let mut shared = self.shared.lock().unwrap();
+        let info = shared.get_mut(id).ok_or(Error::NotFound(*id))?;
+        if info.kind != DocKind::Record {
+            return Err(Error::Unsupported(*id));
+        }
+        if !info.can_write() {
+            return Err(Error::PermissionDenied(*id));
+        }
+        // 删除了就不能再修改
+        if record::deleted(&info.crdt)?.is_some() {
+            return Err(Error::NotFound(*id));
+        }
+        let mut record: T = record::hydrate_doc(&info.crdt)?;
+        f(&mut record);
+        if record.pub_id() != *id {
+            return Err(Error::InvalidInput(format!("pub_id of record {id}")));
+        }
+        if let Err(e) = record::reconcile_doc(&mut info.crdt, &record) {
+            info.crdt.rollback();
+            return Err(e);
+        }
+        info.commit(&format!("update {} {id}", T::TABLE));
+        self.store(info)?;
+        Ok(info.heads.clone())
+    }
+
+    pub fn list(&self) -> Vec<DocSummary> {
+        let shared = self.shared.lock().unwrap();
+        shared.values().map(|info| info.summary()).collect()
+    }
+
+    pub fn kind(&self, id: &Uuid) -> Result<DocKind, Error> {
+        let shared = self.shared.lock().unwrap();
+        shared
+            .get(id)
+            .map(|info| info.kind)
+            .ok_or(Error::NotFound(*id))
+    }
+
+    pub fn path(&self, id: &Uuid) -> Result<Path, Error> {
+        let shared = self.shared.lock().unwrap();
+        let info = shared.get(id).ok_or(Error::NotFound(*id))?;
+        if info.kind != DocKind::Path {
+            return Err(Error::Unsupported(*id));
+        }
+        Ok(hydrate(&info.crdt)?)
+    }
+
+    pub fn folder(&self, id: &Uuid) -> Result<Folder, Error> {
+        let shared = self.shared.lock().unwrap();
+        let info = shared.get(id).ok_or(Error::NotFound(*id))?;
+        if info.kind != DocKind::Folder {
+            return Err(Error::Unsupported(*id));
+        }
+        info.folder()
+    }
+
+    pub fn heads(&self, id: &Uuid) -> Result<Vec<ChangeHash>, Error> {
+        let shared = self.shared.lock().unwrap();
+        let info = shared.get(id).ok_or(Error::NotFound(*id))?;
+        Ok(info.heads.clone())
+    }
+
+    // 本地修改 doc 都经过这里：修改、提交、写入数据库都在同一把锁里完成，
+    // 之后 sync 发出去的也是同样的 heads
+    pub fn update(&self, id: &Uuid, op: CrdtOperation) -> Result<Vec<ChangeHash>, Error> {
+        let mut shared = self.shared.lock().unwrap();
+        let info = shared.get_mut(id).ok_or(Error::NotFound(*id))?;
+        if !info.can_write() {
+            return Err(Error::PermissionDenied(*id));
+        }
+        if let Err(e) = info.apply(&op) {
+            // 丢掉失败的操作留下的修改
+            info.crdt.rollback();
+            return Err(e);
+        }
+        info.commit(&op_message(&op));
+        self.store(info)?;
+        Ok(info.heads.clone())
+    }
+
+    // 多个修改合成一个 change。失败的修改丢掉，其他的照样提交，返回失败的原因
+    pub fn update_all(&self, id: &Uuid, ops: &[CrdtOperation]) -> Result<Vec<Error>, Error> {
+        let mut shared = self.shared.lock().unwrap();
+        let info = shared.get_mut(id).ok_or(Error::NotFound(*id))?;
+        if !info.can_write() {
+            return Err(Error::PermissionDenied(*id));
+        }
+        let mut ops: Vec<_> = ops.iter().collect();
+        let mut failed = vec![];
+        // rollback 会丢掉所有没提交的修改，去掉失败的那个之后从头再来
+        while let Some((i, e)) = ops
+            .iter()
+            .enumerate()
+            .find_map(|(i, op)| info.apply(op).err().map(|e| (i, e)))
+        {
+            info.crdt.rollback();
+            ops.remove(i);
+            failed.push(e);
+        }
+        if ops.is_empty() {
+            return Ok(failed);
+        }
+        let message: Vec<_> = ops.iter().map(|op| op_message(op)).collect();
+        info.commit(&message.join("; "));
+        self.store(info)?;
+        Ok(failed)
+    }
+
+    // 修改先攒起来，之后 flush 的时候一起提交。攒够了返回 true
+    pub fn queue(&self, id: &Uuid, op: CrdtOperation) -> Result<bool, Error> {
+        let shared = self.shared.lock().unwrap();
+        let info = shared.get(id).ok_or(Error::NotFound(*id))?;
+        if !info.can_write() {
+            return Err(Error::PermissionDenied(*id));
+        }
+        Ok(self.batch.push(*id, op))
+    }
+
+    // 提交攒的修改，每个 doc 一个 change，同步一次
+    pub async fn flush(&self) -> Vec<Flushed> {
+        let mut flushed = vec![];
+        for (id, ops) in self.batch.take() {
+            let mut result = Flushed {
+                id,
+                applied: 0,
+                failed: vec![],
+                sent: 0,
+            };
+            match self.update_all(&id, &ops) {
+                Ok(failed) => {
+                    result.applied = ops.len() - failed.len();
+                    result.failed = failed;
+                }
+                Err(e) => result.failed.push(e),
+            }
+            if result.applied > 0 {
+                match self.sync(&id).await {
+                    Ok(sent) => result.sent = sent,
+                    Err(e) => result.failed.push(e),
+                }
+            }
+            flushed.push(result);
+        }
+        flushed
+    }
+
+    // doc 的修改记录
+    pub fn history(&self, id: &Uuid) -> Result<Vec<HistoryEntry>, Error> {
+        let mut shared = self.shared.lock().unwrap();
+        let info = shared.get_mut(id).ok_or(Error::NotFound(*id))?;
+        Ok(history::history(&mut info.crdt))
+    }
+
+    // 读取 doc 在某个历史版本时的内容
+    pub fn hydrate_at<T: Hydrate>(&self, id: &Uuid, heads: &[ChangeHash]) -> Result<T, Error> {
+        let mut shared = self.shared.lock().unwrap();
+        let info = shared.get_mut(id).ok_or(Error::NotFound(*id))?;
+        history::check_heads(&mut info.crdt, heads)?;
+        Ok(hydrate(&info.crdt.fork_at(heads)?)?)
+    }
+
+    // 文件夹里重名的条目，读出来的树里已经改了名字
+    pub fn collisions(&self, id: &Uuid) -> Result<Vec<tree::Collision>, Error> {
+        let shared = self.shared.lock().unwrap();
+        let info = shared.get(id).ok_or(Error::NotFound(*id))?;
+        if info.kind != DocKind::Folder {
+            return Err(Error::Unsupported(*id));
+        }
+        let stored: tree::FolderDoc = hydrate(&info.crdt)?;
+        Ok(stored.tree_with_collisions().1)
+    }
+
+    // 文件夹在某个历史版本时的内容
+    pub fn folder_at(&self, id: &Uuid, heads: &[ChangeHash]) -> Result<Folder, Error> {
+        let mut shared = self.shared.lock().unwrap();
+        let info = shared.get_mut(id).ok_or(Error::NotFound(*id))?;
+        if info.kind != DocKind::Folder {
+            return Err(Error::Unsupported(*id));
+        }
+        history::check_heads(&mut info.crdt, heads)?;
+        tree::hydrate_folder(&info.crdt.fork_at(heads)?)
+    }
+
+    // 比较 doc 的两个版本
+    pub fn diff(
+        &self,
+        id: &Uuid,
+        before: &[ChangeHash],
+        after: &[ChangeHash],
+    ) -> Result<Vec<FieldDiff>, Error> {
+        let mut shared = self.shared.lock().unwrap();
+        let info = shared.get_mut(id).ok_or(Error::NotFound(*id))?;
+        history::diff(&mut info.crdt, before, after)
+    }
+
+    // 撤销 doc 的一些 change，撤销本身也是一次普通的修改，会同步给其他 peer
+    pub fn revert(&self, id: &Uuid, changes: &[ChangeHash]) -> Result<Vec<ChangeHash>, Error> {
+        let mut shared = self.shared.lock().unwrap();
+        let info = shared.get_mut(id).ok_or(Error::NotFound(*id))?;
+        if !info.can_write() {
+            return Err(Error::PermissionDenied(*id));
+        }
+        history::check_heads(&mut info.crdt, changes)?;
+        // 先算好所有的反向修改再写，算的时候读历史会提交没提交的修改
+        let reverts = changes
+            .iter()
+            .map(|change| history::plan_revert(&mut info.crdt, change))
+            .collect::<Result<Vec<_>, _>>()?;
+        for revert in reverts.iter() {
+            if let Err(e) = revert.apply(&mut info.crdt) {
+                info.crdt.rollback();
+                return Err(e);
+            }
+        }
+        let changes: Vec<_> = changes.iter().map(|h| h.to_string()).collect();
+        info.commit(&format!("revert {}", changes.join(",")));
+        self.store(info)?;
+        Ok(info.heads.clone())
+    }
+
+    // 撤销本地最近的 n 个 change
+    pub fn undo(&self, id: &Uuid, n: usize) -> Result<Vec<ChangeHash>, Error> {
+        let changes = {
+            let mut shared = self.shared.lock().unwrap();
+            let info = shared.get_mut(id).ok_or(Error::NotFound(*id))?;
+            history::last_local_changes(&mut info.crdt, n)
+        };
+        self.revert(id, &changes)
+    }
+
+    // 列出 doc 里并发修改产生的冲突
+    pub fn conflicts(&self, id: &Uuid) -> Result<Vec<FieldConflict>, Error> {
+        let mut shared = self.shared.lock().unwrap();
+        let info = shared.get_mut(id).ok_or(Error::NotFound(*id))?;
+        conflict::conflicts(&mut info.crdt)
+    }
+
+    // 选择冲突字段的一个候选值
+    pub fn resolve(&self, id: &Uuid, field: &str, choice: usize) -> Result<Vec<ChangeHash>, Error> {
+        let mut shared = self.shared.lock().unwrap();
+        let info = shared.get_mut(id).ok_or(Error::NotFound(*id))?;
+        if !info.can_write() {
+            return Err(Error::PermissionDenied(*id));
+        }
+        if let Err(e) = conflict::resolve(&mut info.crdt, field, choice) {
+            info.crdt.rollback();
+            return Err(e);
+        }
+        info.commit(&format!("resolve conflict on {field}"));
+        self.store(info)?;
+        Ok(info.heads.clone())
+    }
+
+    // 把 doc 分享给 peer，只有 owner 可以分享 owner 权限
+    pub fn share(&self, id: &Uuid, peer: PeerId, permission: PeerPermission) -> Result<(), Error> {
+        let mut shared = self.shared.lock().unwrap();
+        let info = shared.get_mut(id).ok_or(Error::NotFound(*id))?;
+        let allowed = match permission {
+            PeerPermission::Owner => info.permission == PeerPermission::Owner,
+            _ => info.can_write(),
+        };
+        if !allowed {
+            return Err(Error::PermissionDenied(*id));
+        }
+        let previous = info.peers.insert(peer, permission.clone());
+        db::update_doc_peers(
+            &self.db.lock().unwrap(),
+            &id.to_string(),
+            &serde_json::to_string(&info.permission).unwrap(),
+            &info.peers_json(),
+        )?;
+        if previous.as_ref() != Some(&permission) {
+            self.emit(SyncMessage::PermissionChanged {
+                id: *id,
+                peer,
+                permission,
+            });
+        }
+        Ok(())
+    }
+
+    // 应用直接写入 paths 表的修改变成 doc 的操作，同一个 doc 的修改合成一个 change，
+    // 返回修改过的 doc。不能写入 doc 的修改会被撤销，数据库里的行恢复成 doc 的内容
+    pub fn ingest(&self) -> Result<Vec<Uuid>, Error> {
+        let edits = db::take_path_edits(&self.db.lock().unwrap())?;
+        // 按 pub_id 分组，保持第一次出现的顺序
+        let mut groups: Vec<(Uuid, bool, Vec<CrdtOperation>)> = vec![];
+        for edit in edits {
+            let Ok(id) = edit.pub_id.parse::<Uuid>() else {
+                eprintln!("ingest {}: invalid pub_id {}", edit.field, edit.pub_id);
+                continue;
+            };
+            let op = match (edit.field.as_str(), edit.value) {
+                ("create", _) => None,
+                ("delete", _) => Some(CrdtOperation::Delete(id)),
+                ("name", Some(name)) => Some(CrdtOperation::Update(id, Update::Name(name))),
+                ("path", Some(path)) => Some(CrdtOperation::Update(id, Update::Path(path))),
+                ("description", Some(description)) => {
+                    Some(CrdtOperation::Update(id, Update::Description(description)))
+                }
+                _ => continue,
+            };
+            let i = match groups.iter().position(|(g, _, _)| *g == id) {
+                Some(i) => i,
+                None => {
+                    groups.push((id, false, vec![]));
+                    groups.len() - 1
+                }
+            };
+            match op {
+                Some(op) => groups[i].2.push(op),
+                None => groups[i].1 = true,
+            }
+        }
+        let mut changed = vec![];
+        for (id, create, ops) in groups {
+            let mut ok = false;
+            let mut failed = vec![];
+            if create {
+                match self.ingest_create(id) {
+                    Ok(()) => ok = true,
+                    Err(e) => failed.push(e),
+                }
+            }
+            if !ops.is_empty() {
+                match self.update_all(&id, &ops) {
+                    Ok(errors) => {
+                        ok |= errors.len() < ops.len();
+                        failed.extend(errors);
+                    }
+                    Err(e) => failed.push(e),
+                }
+            }
+            if ok {
+                changed.push(id);
+            }
+            if !failed.is_empty() {
+                for e in &failed {
+                    eprintln!("ingest of {id} failed: {e}");
+                }
+                let mut shared = self.shared.lock().unwrap();
+                if let Some(info) = shared.get_mut(&id) {
+                    info.materialize(&self.db.lock().unwrap())?;
+                }
+            }
+        }
+        Ok(changed)
+    }
+
+    // 应用插入的行新建一个 doc
+    fn ingest_create(&self, id: Uuid) -> Result<(), Error> {
+        if self.shared.lock().unwrap().contains_key(&id) {
+            return Ok(());
+        }
+        let path = db::query_record::<Path>(&self.db.lock().unwrap(), &id.to_string())?;
+        self.insert_path(path.ok_or(Error::NotFound(id))?)?;
+        Ok(())
+    }
+
+    // 应用用 sql 写数据库，写完马上变成 doc 的操作
+    pub fn execute(&self, sql: &str) -> Result<Vec<Uuid>, Error> {
+        self.db.lock().unwrap().execute(sql)?;
+        self.ingest()
+    }
+
+    // 清空物化的表，按 doc 重新生成，返回 doc 的数量
+    pub fn reindex(&self) -> Result<usize, Error> {
+        let mut shared = self.shared.lock().unwrap();
+        let conn = self.db.lock().unwrap();
+        let tables: Vec<_> = materialized_tables().into_iter().map(|(t, _)| t).collect();
+        for (table, columns) in record::registered() {
+            db::ensure_table(&conn, table, columns)?;
+        }
+        // 清空时删除的行不是应用的删除
+        db::set_capture(&conn, false)?;
+        let cleared = db::clear_materialized(&conn, &tables);
+        db::set_capture(&conn, true)?;
+        cleared?;
+        for info in shared.values_mut() {
+            info.materialize(&conn)?;
+        }
+        Ok(shared.len())
+    }
+
+    // 把所有 doc 物化到一个空的数据库，和实际的数据库逐行比较
+    pub fn check(&self) -> Result<Vec<db::Mismatch>, Error> {
+        let mut shared = self.shared.lock().unwrap();
+        let scratch = sqlite::open(":memory:")?;
+        migrate::migrate(&scratch)?;
+        let conn = self.db.lock().unwrap();
+        for (table, columns) in record::registered() {
+            db::ensure_table(&scratch, table, columns)?;
+            db::ensure_table(&conn, table, columns)?;
+        }
+        for info in shared.values_mut() {
+            info.write_rows(&scratch)?;
+        }
+        let mut mismatches = vec![];
+        for (table, columns) in materialized_tables() {
+            let expected = db::read_rows(&scratch, table, columns)?;
+            let found = db::read_rows(&conn, table, columns)?;
+            mismatches.extend(db::compare_rows(table, &expected, &found));
+        }
+        Ok(mismatches)
+    }
+
+    // 马上写一次快照，可以截断历史的话也一起截断，返回是否截断了
+    pub fn compact(&self, id: &Uuid) -> Result<bool, Error> {
+        let mut shared = self.shared.lock().unwrap();
+        let info = shared.get_mut(id).ok_or(Error::NotFound(*id))?;
+        let conn = self.db.lock().unwrap();
+        let base = info.truncation_base(&self.policy);
+        let truncated = base.is_some();
+        if let Some(base) = base {
+            info.truncate(&base)?;
+        }
+        info.snapshot(&conn)?;
+        if truncated {
+            info.refresh(&conn);
+        }
+        Ok(truncated)
+    }
+
+    // 分享过的 peer 都收到删除之后，清除 doc 里删除的记录，返回清除的数量。
+    // 文件夹去掉 tombstone 和不再生效的移动，删除了的单个文件整个 doc 都去掉
+    pub fn purge(&self, id: &Uuid) -> Result<usize, Error> {
+        let mut shared = self.shared.lock().unwrap();
+        let info = shared.get_mut(id).ok_or(Error::NotFound(*id))?;
+        if !info.can_write() {
+            return Err(Error::PermissionDenied(*id));
+        }
+        if !info.acked_by_all() {
+            return Ok(0);
+        }
+        match info.kind {
+            // 整个 doc 清除掉，记下 doc id，之后 peer 再发来也不会新建
+            DocKind::Path | DocKind::Record => {
+                let table = match info.kind {
+                    DocKind::Path if hydrate::<_, Path>(&info.crdt)?.deleted.is_some() => {
+                        Path::TABLE.to_string()
+                    }
+                    DocKind::Record if record::deleted(&info.crdt)?.is_some() => {
+                        record::table_of(&info.crdt)
+                            .ok_or_else(|| Error::InvalidInput("record doc".to_string()))?
+                    }
+                    _ => return Ok(0),
+                };
+                let conn = self.db.lock().unwrap();
+                db::set_capture(&conn, false)?;
+                let deleted = db::delete_doc(&conn, &id.to_string(), &table, now_millis());
+                db::set_capture(&conn, true)?;
+                deleted?;
+                shared.remove(id);
+                Ok(1)
+            }
+            DocKind::Folder => {
+                // 移动记录也只留下生效的那些
+                let mut stored: tree::FolderDoc = hydrate(&info.crdt)?;
+                let purged = stored.tombstones.len();
+                let dropped = stored.compact();
+                if purged == 0 && dropped == 0 {
+                    return Ok(0);
+                }
+                stored.tombstones.clear();
+                if let Err(e) = reconcile(&mut info.crdt, &stored) {
+                    info.crdt.rollback();
+                    return Err(e.into());
+                }
+                info.commit(&format!("purge {purged} tombstones and {dropped} moves"));
+                self.store(info)?;
+                Ok(purged)
+            }
+        }
+    }
+
+    // 发给每个分享过的 peer 的 doc
+    pub fn invites(&self, id: &Uuid) -> Result<Vec<(PeerId, Invite)>, Error> {
+        let mut shared = self.shared.lock().unwrap();
+        let info = shared.get_mut(id).ok_or(Error::NotFound(*id))?;
+        Ok(info.invites())
+    }
+
+    // 把 doc 发送给分享过的所有 peer，返回发送成功的数量
+    pub async fn sync(&self, id: &Uuid) -> Result<usize, Error> {
+        let invites = {
+            let mut shared = self.shared.lock().unwrap();
+            let info = shared.get_mut(id).ok_or(Error::NotFound(*id))?;
+            for peer in info.peers.keys() {
+                info.peer_sync.entry(*peer).or_default().sending();
+            }
+            info.invites()
+        };
+
+        let mut sent = 0;
+        for (peer, invite) in invites {
+            let result = self.send(peer, &serde_json::to_vec(&invite).unwrap()).await;
+            if let Err(e) = &result {
+                eprintln!("sync {id} to {peer} failed: {e}");
+                self.emit(SyncMessage::SyncFailed {
+                    id: *id,
+                    peer,
+                    error: e.to_string(),
+                });
+            } else {
+                sent += 1;
+            }
+            let mut shared = self.shared.lock().unwrap();
+            if let Some(info) = shared.get_mut(id) {
+                let sync = info.peer_sync.entry(peer).or_default();
+                sync.sent(result.map_err(|e| e.to_string()), now_millis());
+            }
+        }
+        Ok(sent)
+    }
+
+    // 和分享过的每个 peer 的同步情况
+    pub fn status(&self, id: &Uuid) -> Result<Vec<PeerStatus>, Error> {
+        let mut shared = self.shared.lock().unwrap();
+        let info = shared.get_mut(id).ok_or(Error::NotFound(*id))?;
+        Ok(info.status())
+    }
+
+    pub fn statuses(&self) -> Vec<DocStatus> {
+        let mut shared = self.shared.lock().unwrap();
+        shared
+            .values_mut()
+            .map(|info| DocStatus {
+                id: info.doc_id,
+                name: info.summary().name,
+                peers: info.status(),
+            })
+            .collect()
+    }
+
+    async fn send(&self, peer: PeerId, data: &[u8]) -> Result<(), Error> {
+        let mut control = self.control.clone();
+        let mut stream = control
+            .open_stream(peer, SYNC_PROTOCOL)
+            .await
+            .map_err(|e| Error::Network(e.to_string()))?;
+        stream
+            .write_all(data)
+            .await
+            .map_err(|e| Error::Network(e.to_string()))?;
+        stream
+            .close()
+            .await
+            .map_err(|e| Error::Network(e.to_string()))
+    }
+
+    // 收到其他 peer 发来的 doc
+    pub fn receive(&self, peer: PeerId, invite: Invite) -> Result<(), Error> {
+        let mut shared = self.shared.lock().unwrap();
+        let id = invite.id;
+        match shared.get_mut(&id) {
+            Some(info) => {
+                // 只接受有写权限的 peer 的变更
+                match info.peers.get(&peer) {
+                    Some(PeerPermission::ReadWrite | PeerPermission::Owner) => {}
+                    _ => return Err(Error::PermissionDenied(id)),
+                }
+                if invite.kind != info.kind {
+                    return Err(Error::Unsupported(id));
+                }
+                let mut other_doc = AutoCommit::load(&invite.data)?;
+                if invite.epoch > info.epoch {
+                    // 对方截断了历史，整个换成对方的 doc。本地有对方截断时还没有的修改的话，
+                    // 接到截断后内容和 base 一样的版本后面再合并
+                    history::check_heads(&mut info.crdt, &invite.base)
+                        .map_err(|_| Error::Truncated(id))?;
+                    let actor = info.crdt.get_actor().clone();
+                    let mut crdt = other_doc.fork().with_actor(actor);
+                    if !info.crdt.get_changes(&invite.base).is_empty() {
+                        if invite.rebased.is_empty() {
+                            return Err(Error::Truncated(id));
+                        }
+                        history::check_heads(&mut crdt, &invite.rebased)
+                            .map_err(|_| Error::Truncated(id))?;
+                        history::rebase(&mut crdt, &mut info.crdt, &invite.base, &invite.rebased)?;
+                    }
+                    info.crdt = crdt;
+                    info.epoch = invite.epoch;
+                    info.base = invite.base;
+                    info.rebased = invite.rebased;
+                    info.peer_sync.values_mut().for_each(PeerSync::reset);
+                    info.saved = None;
+                } else if invite.epoch < info.epoch || invite.base != info.base {
+                    // 对方还是截断之前的历史，对方收到新的 doc 之后会把自己的修改接上去
+                    return Err(Error::Truncated(id));
+                } else {
+                    // 按 crdt 合并对方的历史，并发的修改都会保留
+                    info.crdt.merge(&mut other_doc)?;
+                }
+                // 对方改了分享给本地的权限，和 share 一样 owner 权限只有 owner 能给，也只有 owner 能收回
+                let owner = info.peers.get(&peer) == Some(&PeerPermission::Owner);
+                let allowed = owner
+                    || (invite.permission != PeerPermission::Owner
+                        && info.permission != PeerPermission::Owner);
+                if invite.permission != info.permission && allowed {
+                    info.permission = invite.permission.clone();
+                    db::update_doc_peers(
+                        &self.db.lock().unwrap(),
+                        &id.to_string(),
+                        &serde_json::to_string(&info.permission).unwrap(),
+                        &info.peers_json(),
+                    )?;
+                    self.emit(SyncMessage::PermissionChanged {
+                        id,
+                        peer,
+                        permission: invite.permission,
+                    });
+                }
+                // 对方已经有了它发来的这些修改
+                let sync = info.peer_sync.entry(peer).or_default();
+                sync.received(other_doc.get_heads(), now_millis());
+                let before = info.heads.clone();
+                self.store(info)?;
+                if info.heads != before {
+                    self.emit(SyncMessage::Ingested {
+                        id,
+                        peer,
+                        heads: info.heads.clone(),
+                    });
+                }
+            }
+            None => {
+                // 清除过的 doc 所有 peer 都收到了删除，再发来的是删除之前的旧数据
+                if db::is_purged(&self.db.lock().unwrap(), &id.to_string())? {
+                    return Ok(());
+                }
+                // 不存在则插入，发送者至少有读写权限
+                let crdt = AutoCommit::load(&invite.data)?;
+                let mut info = DocInfo::new(id, invite.kind, invite.permission, crdt);
+                info.peers.insert(peer, PeerPermission::ReadWrite);
+                info.epoch = invite.epoch;
+                info.base = invite.base;
+                info.rebased = invite.rebased;
+                let heads = info.crdt.get_heads();
+                let sync = info.peer_sync.entry(peer).or_default();
+                sync.received(heads, now_millis());
+                self.store(&mut info)?;
+                self.emit(SyncMessage::Created {
+                    id,
+                    kind: info.kind,
+                });
+                self.emit(SyncMessage::Ingested {
+                    id,
+                    peer,
+                    heads: info.heads.clone(),
+                });
+                shared.insert(id, info);
+            }
+        }
+        Ok(())
+    }
+
+    // 合并收到的数据，同时合并的数量由 timestamp_lock 限制。调用前需要先 incoming.admit。
+    // 先拿到许可再读数据：排队的 stream 先不读，对方写不进来，内存里最多同时有 concurrency 份数据
+    pub(crate) async fn merge(
+        self: Arc<Self>,
+        peer: PeerId,
+        data: impl std::future::Future<Output = std::io::Result<Vec<u8>>>,
+    ) -> Result<(), Error> {
+        let permit = self.timestamp_lock.acquire().await.unwrap();
+        self.incoming.start();
+        let data = match data.await {
+            Ok(data) => data,
+            Err(e) => {
+                drop(permit);
+                self.incoming.finish(&peer, true, false);
+                return Err(Error::Network(format!("read from {peer} failed: {e}")));
+            }
+        };
+        let invite = match serde_json::from_slice::<Invite>(&data) {
+            Ok(invite) => invite,
+            Err(e) => {
+                drop(permit);
+                self.incoming.finish(&peer, true, false);
+                return Err(Error::Network(e.to_string()));
+            }
+        };
+        let (id, epoch) = (invite.id, invite.epoch);
+        // 合并很耗 cpu，不放在异步的线程里
+        let manager = self.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            manager.receive(peer, invite).inspect_err(|e| {
+                let mut shared = manager.shared.lock().unwrap();
+                // 只记分享过的 peer
+                if let Some(info) = shared.get_mut(&id) {
+                    if info.peers.contains_key(&peer) {
+                        let sync = info.peer_sync.entry(peer).or_default();
+                        sync.failed(e.to_string());
+                    }
+                }
+                manager.emit(SyncMessage::SyncFailed {
+                    id,
+                    peer,
+                    error: e.to_string(),
+                })
+            })
+        })
+        .await
+        .unwrap_or_else(|e| Err(Error::Network(e.to_string())));
+        drop(permit);
+        self.incoming.finish(&peer, true, result.is_ok());
+        // 对方还是截断之前的历史：把截断后的 doc 发回去，对方接上自己的修改之后再发过来
+        if let Err(Error::Truncated(_)) = result {
+            let newer = self.shared.lock().unwrap().get(&id).map(|i| i.epoch) > Some(epoch);
+            if newer {
+                self.sync(&id).await?;
+            }
+        }
+        result
+    }
+
+    // 接收其他 peer 同步过来的 doc
+    pub fn listen(
+        self: Arc<Self>,
+    ) -> Result<tokio::task::JoinHandle<()>, stream::AlreadyRegistered> {
+        let mut incoming = self.control.clone().accept(SYNC_PROTOCOL)?;
+        Ok(tokio::spawn(async move {
+            while let Some((peer, mut stream)) = incoming.next().await {
+                // 排满了直接关掉，对方下次同步时再发
+                if !self.incoming.admit(&peer) {
+                    eprintln!("sync from {peer} refused: too many pending streams");
+                    continue;
+                }
+                let manager = self.clone();
+                tokio::spawn(async move {
+                    // 读取stream数据
+                    let data = async move {
+                        let mut buf = Vec::new();
+                        stream.read_to_end(&mut buf).await.map(|_| buf)
+                    };
+                    if let Err(e) = manager.merge(peer, data).await {
+                        eprintln!("sync from {peer} failed: {e}");
+                    }
+                });
+            }
+        }))
+    }
+
+    pub fn incoming_stats(&self) -> IncomingStats {
+        self.incoming.stats()
+    }
+
+    pub fn add_known_peer(&self, peer: PeerId) {
+        let mut peers = self.known_peers.lock().unwrap();
+        if !peers.contains(&peer) {
+            peers.push(peer);
+            self.emit(SyncMessage::PeerJoined(peer));
+        }
+    }
+
+    pub fn remove_known_peer(&self, peer: &PeerId) {
+        let mut peers = self.known_peers.lock().unwrap();
+        if peers.contains(peer) {
+            peers.retain(|p| p != peer);
+            self.emit(SyncMessage::PeerLeft(*peer));
+        }
+    }
+
+    pub fn known_peers(&self) -> Vec<PeerId> {
+        self.known_peers.lock().unwrap().clone()
+    }
+}
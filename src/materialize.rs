@@ -0,0 +1,299 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+use tokio::sync::{broadcast, mpsc};
+use uuid::Uuid;
+
+use crate::{folder::Folder, watcher::hash_file, Manager, SyncMessage};
+
+// 回收站，远端删除的文件移动到这里而不是直接删除
+pub const TRASH_DIR: &str = ".crdt-trash";
+
+// 本地冲突
+#[derive(Debug, Clone, PartialEq)]
+pub enum Conflict {
+    // 远端删除了，但是本地修改过，保留在回收站里
+    DeletedButModified { pub_id: Uuid, kept_as: PathBuf },
+    // 远端和本地都修改了内容，本地的内容另存一份
+    BothModified { pub_id: Uuid, kept_as: PathBuf },
+    // 目标位置有不认识的本地文件，另存一份
+    Occupied { path: PathBuf, kept_as: PathBuf },
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Report {
+    pub conflicts: Vec<Conflict>,
+    // 本地没有内容的文件，(pub_id, hash)，需要从其他 peer 下载
+    pub missing: Vec<(Uuid, String)>,
+    // 路径会跑到共享文件夹外面的条目，(pub_id, 相对路径)，本地不动
+    pub rejected: Vec<(Uuid, String)>,
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    path: String,
+    is_dir: bool,
+    hash: String,
+}
+
+fn nodes(folder: &Folder) -> HashMap<Uuid, Node> {
+    folder
+        .entries()
+        .into_iter()
+        .map(|(path, id, is_dir)| {
+            let hash = folder
+                .find_file(&id)
+                .map(|f| f.hash.clone())
+                .unwrap_or_default();
+            (id, Node { path, is_dir, hash })
+        })
+        .collect()
+}
+
+// 相对路径在本地的位置，只能在 root 下面，也不能放进回收站
+pub fn local_path(root: &Path, rel: &str) -> Option<PathBuf> {
+    let rel = Path::new(rel);
+    let mut components = rel.components().peekable();
+    components.peek()?;
+    if rel.starts_with(TRASH_DIR) || !components.all(|c| matches!(c, Component::Normal(_))) {
+        return None;
+    }
+    Some(root.join(rel))
+}
+
+fn depth(path: &str) -> usize {
+    path.matches('/').count()
+}
+
+// 把合并后的文档状态反映到本地目录
+pub struct Materializer {
+    root: PathBuf,
+    // 上一次落地到本地的状态，还没有落地过是 None
+    last: Option<Folder>,
+}
+
+impl Materializer {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Materializer {
+            root: root.into(),
+            last: None,
+        }
+    }
+
+    // 本地目录已经是 folder 的状态
+    pub fn with_last(mut self, folder: Folder) -> Self {
+        self.last = Some(folder);
+        self
+    }
+
+    pub fn trash(&self) -> PathBuf {
+        self.root.join(TRASH_DIR)
+    }
+
+    // 调用前已经用 local_path 筛过
+    fn local(&self, rel: &str) -> PathBuf {
+        self.root.join(rel)
+    }
+
+    // 移动到回收站，名字前面加上 pub_id 防止重名
+    fn to_trash(&self, id: &Uuid, path: &Path) -> io::Result<PathBuf> {
+        fs::create_dir_all(self.trash())?;
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let target = self.trash().join(format!("{}-{name}", id.simple()));
+        fs::rename(path, &target)?;
+        Ok(target)
+    }
+
+    // 冲突的本地文件另存为 "name (conflict xxxxxxxx)"
+    fn keep_copy(&self, path: &Path) -> io::Result<PathBuf> {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let short = &Uuid::new_v4().simple().to_string()[..8];
+        let (stem, ext) = match name.rsplit_once('.') {
+            Some((stem, ext)) if !stem.is_empty() => (stem.to_string(), format!(".{ext}")),
+            _ => (name.to_string(), String::new()),
+        };
+        let target = path.with_file_name(format!("{stem} (conflict {short}){ext}"));
+        fs::rename(path, &target)?;
+        Ok(target)
+    }
+
+    fn modified(path: &Path, hash: &str) -> bool {
+        match hash_file(path) {
+            Ok((local, _)) => local != hash,
+            Err(_) => false,
+        }
+    }
+
+    // old 是上一次落地到本地的状态，new 是合并后的状态
+    pub fn apply(&self, old: &Folder, new: &Folder) -> io::Result<Report> {
+        let mut report = Report::default();
+        let mut before = nodes(old);
+        let mut after = nodes(new);
+        // 不安全的路径跳过，这些条目在本地保持原样
+        let mut rejected: Vec<_> = after
+            .iter()
+            .filter(|(_, n)| local_path(&self.root, &n.path).is_none())
+            .map(|(id, n)| (*id, n.path.clone()))
+            .collect();
+        rejected.sort();
+        for (id, _) in rejected.iter() {
+            after.remove(id);
+            before.remove(id);
+        }
+        before.retain(|_, n| local_path(&self.root, &n.path).is_some());
+        report.rejected = rejected;
+
+        // 删除：深的先处理
+        let mut deleted: Vec<_> = before
+            .iter()
+            .filter(|(id, _)| !after.contains_key(id))
+            .collect();
+        deleted.sort_by_key(|(_, n)| std::cmp::Reverse(depth(&n.path)));
+        for (id, node) in deleted {
+            let path = self.local(&node.path);
+            if !path.exists() {
+                continue;
+            }
+            let modified = !node.is_dir && Self::modified(&path, &node.hash);
+            let kept_as = self.to_trash(id, &path)?;
+            if modified {
+                report.conflicts.push(Conflict::DeletedButModified {
+                    pub_id: *id,
+                    kept_as,
+                });
+            }
+        }
+
+        // 改名和移动：先全部移到暂存目录，再放到新位置，防止互相覆盖
+        let mut moved: Vec<_> = after
+            .iter()
+            .filter_map(|(id, n)| {
+                let old = before.get(id)?;
+                (old.path != n.path).then_some((id, old, n))
+            })
+            .collect();
+        let staging = self
+            .trash()
+            .join(format!(".staging-{}", Uuid::new_v4().simple()));
+        moved.sort_by_key(|(_, old, _)| std::cmp::Reverse(depth(&old.path)));
+        let mut staged = vec![];
+        for (id, old, new) in moved {
+            let path = self.local(&old.path);
+            if !path.exists() {
+                continue;
+            }
+            fs::create_dir_all(&staging)?;
+            let tmp = staging.join(id.simple().to_string());
+            fs::rename(&path, &tmp)?;
+            staged.push((tmp, new));
+        }
+        staged.sort_by_key(|(_, new)| depth(&new.path));
+        for (tmp, new) in staged {
+            let target = self.local(&new.path);
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            if target.exists() {
+                let kept_as = self.keep_copy(&target)?;
+                report.conflicts.push(Conflict::Occupied {
+                    path: target.clone(),
+                    kept_as,
+                });
+            }
+            fs::rename(&tmp, &target)?;
+        }
+        if staging.exists() {
+            fs::remove_dir_all(&staging)?;
+        }
+
+        // 新建和内容变更
+        let mut created: Vec<_> = after.iter().collect();
+        created.sort_by_key(|(_, n)| depth(&n.path));
+        for (id, node) in created {
+            let path = self.local(&node.path);
+            if node.is_dir {
+                fs::create_dir_all(&path)?;
+                continue;
+            }
+            let old_hash = before.get(id).map(|n| n.hash.as_str());
+            if !path.exists() {
+                report.missing.push((*id, node.hash.clone()));
+                continue;
+            }
+            if !Self::modified(&path, &node.hash) {
+                continue;
+            }
+            match old_hash {
+                // 本地没改过，等待下载新的内容
+                Some(old_hash) if !Self::modified(&path, old_hash) => {}
+                // 新文件的位置上已经有本地文件
+                None => {
+                    let kept_as = self.keep_copy(&path)?;
+                    report.conflicts.push(Conflict::Occupied { path, kept_as });
+                }
+                _ => {
+                    let kept_as = self.keep_copy(&path)?;
+                    report.conflicts.push(Conflict::BothModified {
+                        pub_id: *id,
+                        kept_as,
+                    });
+                }
+            }
+            report.missing.push((*id, node.hash.clone()));
+        }
+        Ok(report)
+    }
+
+    // 从上一次落地的状态更新到 new，失败的话下次还从上一次的状态开始
+    pub fn update(&mut self, new: Folder) -> io::Result<Report> {
+        let report = match &self.last {
+            Some(last) => self.apply(last, &new)?,
+            None => self.apply(&Folder::new("", ""), &new)?,
+        };
+        self.last = Some(new);
+        Ok(report)
+    }
+
+    // 每次合并了 peer 发来的修改就落地到本地目录，结果发到 reports
+    pub fn spawn(
+        mut self,
+        manager: Arc<Manager>,
+        id: Uuid,
+        reports: mpsc::UnboundedSender<io::Result<Report>>,
+    ) -> tokio::task::JoinHandle<()> {
+        let mut events = manager.subscribe();
+        tokio::spawn(async move {
+            loop {
+                match events.recv().await {
+                    Ok(SyncMessage::Ingested { id: ingested, .. }) if ingested == id => {}
+                    Ok(_) => continue,
+                    // 漏掉的事件里可能有这个文件夹，按当前的状态落地一次
+                    Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+                let folder = match manager.folder(&id) {
+                    Ok(folder) => folder,
+                    Err(e) => {
+                        let _ = reports.send(Err(io::Error::other(e.to_string())));
+                        continue;
+                    }
+                };
+                // 文件操作都是阻塞的
+                let (materializer, report) = tokio::task::spawn_blocking(move || {
+                    let report = self.update(folder);
+                    (self, report)
+                })
+                .await
+                .unwrap();
+                self = materializer;
+                if reports.send(report).is_err() {
+                    break;
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,269 @@
+use sqlite::{Connection, State};
+
+use crate::db;
+
+// 一次迁移要做的事，改表结构用 sql，需要转换数据的用代码
+pub enum Step {
+    Sql(&'static str),
+    Code(fn(&Connection) -> sqlite::Result<()>),
+}
+
+// 数据库的迁移，按顺序执行，第 i 个执行完之后版本是 i + 1。
+// 已经发布的迁移不能修改，改表结构只能在后面追加新的迁移。
+// 没有版本表的旧数据库版本是 0，表可能已经建好了，所以都用 IF NOT EXISTS
+pub const MIGRATIONS: &[(&str, Step)] = &[
+    (
+        // 单个共享的文件
+        "create paths",
+        Step::Sql(
+            "
+    CREATE TABLE IF NOT EXISTS paths (
+        id INTEGER PRIMARY KEY,
+        pub_id TEXT NOT NULL,
+        name TEXT NOT NULL,
+        path TEXT NOT NULL,
+        description TEXT NOT NULL
+    );",
+        ),
+    ),
+    (
+        // 共享文件夹展开后的记录
+        "create folders and files",
+        Step::Sql(
+            "
+    CREATE TABLE IF NOT EXISTS folders (
+        id INTEGER PRIMARY KEY,
+        pub_id TEXT NOT NULL UNIQUE,
+        root_pub_id TEXT NOT NULL,
+        parent_pub_id TEXT,
+        name TEXT NOT NULL,
+        description TEXT NOT NULL,
+        materialized_path TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS files (
+        id INTEGER PRIMARY KEY,
+        pub_id TEXT NOT NULL UNIQUE,
+        root_pub_id TEXT NOT NULL,
+        folder_pub_id TEXT NOT NULL,
+        name TEXT NOT NULL,
+        description TEXT NOT NULL,
+        materialized_path TEXT NOT NULL,
+        hash TEXT NOT NULL,
+        size INTEGER NOT NULL,
+        mime_type TEXT NOT NULL
+    );",
+        ),
+    ),
+    (
+        // 每个 doc 写入数据库时的 heads
+        "create docs",
+        Step::Sql(
+            "
+    CREATE TABLE IF NOT EXISTS docs (
+        pub_id TEXT PRIMARY KEY,
+        kind TEXT NOT NULL,
+        heads TEXT NOT NULL
+    );",
+        ),
+    ),
+    (
+        // doc 的快照和之后的增量
+        "create doc_snapshots and doc_chunks",
+        Step::Sql(
+            "
+    CREATE TABLE IF NOT EXISTS doc_snapshots (
+        pub_id TEXT PRIMARY KEY,
+        kind TEXT NOT NULL,
+        permission TEXT NOT NULL,
+        peers TEXT NOT NULL,
+        actor TEXT NOT NULL,
+        epoch INTEGER NOT NULL,
+        base TEXT NOT NULL,
+        data BLOB NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS doc_chunks (
+        id INTEGER PRIMARY KEY,
+        pub_id TEXT NOT NULL,
+        data BLOB NOT NULL
+    );",
+        ),
+    ),
+    (
+        // 增量改成逐个存 change
+        "replace doc_chunks with changes",
+        Step::Code(split_chunks),
+    ),
+    (
+        // 应用直接写入 paths 的修改记到 path_edits 里，之后变成 doc 的操作。
+        // 物化 doc 时把 capture 关掉，自己写入的不记录
+        "capture edits to paths",
+        Step::Sql(
+            "
+    CREATE TABLE IF NOT EXISTS capture (enabled INTEGER NOT NULL);
+    INSERT INTO capture (enabled) VALUES (1);
+    CREATE TABLE IF NOT EXISTS path_edits (
+        id INTEGER PRIMARY KEY,
+        pub_id TEXT NOT NULL,
+        field TEXT NOT NULL,
+        value TEXT
+    );
+    CREATE TRIGGER IF NOT EXISTS paths_insert AFTER INSERT ON paths
+    WHEN (SELECT enabled FROM capture)
+    BEGIN
+        INSERT INTO path_edits (pub_id, field) VALUES (NEW.pub_id, 'create');
+    END;
+    CREATE TRIGGER IF NOT EXISTS paths_update_name AFTER UPDATE OF name ON paths
+    WHEN (SELECT enabled FROM capture) AND OLD.name IS NOT NEW.name
+    BEGIN
+        INSERT INTO path_edits (pub_id, field, value) VALUES (NEW.pub_id, 'name', NEW.name);
+    END;
+    CREATE TRIGGER IF NOT EXISTS paths_update_path AFTER UPDATE OF path ON paths
+    WHEN (SELECT enabled FROM capture) AND OLD.path IS NOT NEW.path
+    BEGIN
+        INSERT INTO path_edits (pub_id, field, value) VALUES (NEW.pub_id, 'path', NEW.path);
+    END;
+    CREATE TRIGGER IF NOT EXISTS paths_update_description AFTER UPDATE OF description ON paths
+    WHEN (SELECT enabled FROM capture) AND OLD.description IS NOT NEW.description
+    BEGIN
+        INSERT INTO path_edits (pub_id, field, value)
+        VALUES (NEW.pub_id, 'description', NEW.description);
+    END;",
+        ),
+    ),
+    (
+        // 删除了的条目保留下来，deleted_at 不为空，查询时跳过。
+        // 应用删除 paths 的行或者填上 deleted_at 都记成删除
+        "add deleted_at and deleted_by",
+        Step::Sql(
+            "
+    ALTER TABLE paths ADD COLUMN deleted_at INTEGER;
+    ALTER TABLE paths ADD COLUMN deleted_by TEXT;
+    ALTER TABLE folders ADD COLUMN deleted_at INTEGER;
+    ALTER TABLE folders ADD COLUMN deleted_by TEXT;
+    ALTER TABLE files ADD COLUMN deleted_at INTEGER;
+    ALTER TABLE files ADD COLUMN deleted_by TEXT;
+    CREATE TRIGGER IF NOT EXISTS paths_delete AFTER DELETE ON paths
+    WHEN (SELECT enabled FROM capture) AND OLD.deleted_at IS NULL
+    BEGIN
+        INSERT INTO path_edits (pub_id, field) VALUES (OLD.pub_id, 'delete');
+    END;
+    CREATE TRIGGER IF NOT EXISTS paths_update_deleted AFTER UPDATE OF deleted_at ON paths
+    WHEN (SELECT enabled FROM capture) AND OLD.deleted_at IS NULL AND NEW.deleted_at IS NOT NULL
+    BEGIN
+        INSERT INTO path_edits (pub_id, field) VALUES (NEW.pub_id, 'delete');
+    END;",
+        ),
+    ),
+    (
+        // 截断之后的 doc 里和 base 内容一样的 heads
+        "add rebased to doc_snapshots",
+        Step::Sql("ALTER TABLE doc_snapshots ADD COLUMN rebased TEXT NOT NULL DEFAULT '';"),
+    ),
+    (
+        // 清除过的 doc，peer 再发来旧的数据也不再新建
+        "add purged_docs",
+        Step::Sql(
+            "
+    CREATE TABLE IF NOT EXISTS purged_docs (
+        pub_id TEXT PRIMARY KEY,
+        purged_at INTEGER NOT NULL
+    );",
+        ),
+    ),
+];
+
+// 把每个 doc 快照之后的增量拆成单独的 change 写入 changes
+fn split_chunks(conn: &Connection) -> sqlite::Result<()> {
+    conn.execute(
+        "
+    CREATE TABLE IF NOT EXISTS changes (
+        id INTEGER PRIMARY KEY,
+        doc_id TEXT NOT NULL,
+        hash TEXT NOT NULL,
+        actor TEXT NOT NULL,
+        seq INTEGER NOT NULL,
+        bytes BLOB NOT NULL,
+        UNIQUE (doc_id, hash)
+    );",
+    )?;
+    let invalid = |e: automerge::AutomergeError| sqlite::Error {
+        code: None,
+        message: Some(format!("doc_chunks: {e}")),
+    };
+    let mut stmt = conn.prepare("SELECT pub_id, data FROM doc_snapshots")?;
+    let mut snapshots = vec![];
+    while let State::Row = stmt.next()? {
+        snapshots.push((stmt.read::<String, _>(0)?, stmt.read::<Vec<u8>, _>(1)?));
+    }
+    for (pub_id, data) in snapshots {
+        let mut doc = automerge::AutoCommit::load(&data).map_err(invalid)?;
+        let heads = doc.get_heads();
+        let mut stmt = conn.prepare("SELECT data FROM doc_chunks WHERE pub_id = ? ORDER BY id")?;
+        stmt.bind((1, pub_id.as_str()))?;
+        while let State::Row = stmt.next()? {
+            doc.load_incremental(&stmt.read::<Vec<u8>, _>(0)?)
+                .map_err(invalid)?;
+        }
+        let changes: Vec<_> = doc
+            .get_changes(&heads)
+            .into_iter()
+            .map(db::StoredChange::from)
+            .collect();
+        db::write_changes(conn, &pub_id, &changes)?;
+    }
+    conn.execute("DROP TABLE doc_chunks")
+}
+
+// 最新的版本
+pub fn latest() -> usize {
+    MIGRATIONS.len()
+}
+
+// 数据库当前的版本，没有版本表是 0
+pub fn version(conn: &Connection) -> sqlite::Result<usize> {
+    let mut stmt = conn.prepare(
+        "SELECT name FROM sqlite_master WHERE type = 'table' AND name = 'schema_version'",
+    )?;
+    if let State::Done = stmt.next()? {
+        return Ok(0);
+    }
+    let mut stmt = conn.prepare("SELECT version FROM schema_version")?;
+    match stmt.next()? {
+        State::Row => Ok(stmt.read::<i64, _>(0)? as usize),
+        State::Done => Ok(0),
+    }
+}
+
+// 升级到最新的版本
+pub fn migrate(conn: &Connection) -> sqlite::Result<()> {
+    migrate_to(conn, latest())
+}
+
+// 按顺序执行还没执行的迁移，直到 target 版本，每个迁移在单独的事务里
+pub fn migrate_to(conn: &Connection, target: usize) -> sqlite::Result<()> {
+    conn.execute("CREATE TABLE IF NOT EXISTS schema_version (version INTEGER NOT NULL)")?;
+    let current = version(conn)?;
+    for (i, (_, step)) in MIGRATIONS.iter().enumerate().take(target).skip(current) {
+        conn.execute("BEGIN")?;
+        match apply(conn, step, i + 1) {
+            Ok(()) => conn.execute("COMMIT")?,
+            Err(e) => {
+                conn.execute("ROLLBACK")?;
+                return Err(e);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn apply(conn: &Connection, step: &Step, version: usize) -> sqlite::Result<()> {
+    match step {
+        Step::Sql(sql) => conn.execute(sql)?,
+        Step::Code(f) => f(conn)?,
+    }
+    conn.execute("DELETE FROM schema_version")?;
+    let mut stmt = conn.prepare("INSERT INTO schema_version (version) VALUES (?)")?;
+    stmt.bind((1, version as i64))?;
+    stmt.next()?;
+    Ok(())
+}
@@ -0,0 +1,250 @@
+use std::{collections::BTreeMap, sync::Mutex};
+
+use automerge::{transaction::Transactable, AutoCommit, ReadDoc, ScalarValue};
+use autosurgeon::{hydrate_prop, reconcile_prop, Hydrate, Reconcile};
+use serde::Serialize;
+use sqlite::{Connection, Value};
+use uuid::Uuid;
+
+use crate::{db, text::Text, Deleted, Error};
+
+// 可以同步、并且物化成数据库里一行的记录。
+// 一般用 sync_record! 实现，调用 register 之后 Manager 才能物化这种记录
+pub trait SyncRecord: Reconcile + Hydrate + Serialize {
+    // 表名
+    const TABLE: &'static str;
+    // 除了 pub_id 以外的列，和 values 的顺序一致
+    const COLUMNS: &'static [&'static str];
+
+    fn pub_id(&self) -> Uuid;
+    fn values(&self) -> Vec<Value>;
+    // 从数据库读出来，列不对是 None
+    fn from_values(pub_id: Uuid, values: Vec<Value>) -> Option<Self>
+    where
+        Self: Sized;
+
+    // 删除了的记录物化成 deleted_at 不为空的行，查询时跳过
+    fn deleted(&self) -> Option<&Deleted> {
+        None
+    }
+}
+
+// 字段和数据库的值互相转换
+pub trait Column: Sized {
+    fn to_value(&self) -> Value;
+    fn from_value(value: Value) -> Option<Self>;
+}
+
+impl Column for String {
+    fn to_value(&self) -> Value {
+        Value::from(self.as_str())
+    }
+
+    fn from_value(value: Value) -> Option<Self> {
+        match value {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+impl Column for Text {
+    fn to_value(&self) -> Value {
+        Value::from(self.as_str())
+    }
+
+    fn from_value(value: Value) -> Option<Self> {
+        String::from_value(value).map(Text::from)
+    }
+}
+
+impl Column for Uuid {
+    fn to_value(&self) -> Value {
+        Value::from(self.to_string())
+    }
+
+    fn from_value(value: Value) -> Option<Self> {
+        String::from_value(value)?.parse().ok()
+    }
+}
+
+impl Column for bool {
+    fn to_value(&self) -> Value {
+        Value::from(*self as i64)
+    }
+
+    fn from_value(value: Value) -> Option<Self> {
+        i64::from_value(value).map(|v| v != 0)
+    }
+}
+
+impl Column for i64 {
+    fn to_value(&self) -> Value {
+        Value::from(*self)
+    }
+
+    fn from_value(value: Value) -> Option<Self> {
+        match value {
+            Value::Integer(i) => Some(i),
+            _ => None,
+        }
+    }
+}
+
+macro_rules! integer_column {
+    ($($ty:ty),*) => {
+        $(
+            impl Column for $ty {
+                fn to_value(&self) -> Value {
+                    Value::from(*self as i64)
+                }
+
+                fn from_value(value: Value) -> Option<Self> {
+                    i64::from_value(value)?.try_into().ok()
+                }
+            }
+        )*
+    };
+}
+
+integer_column!(u32, u64, i32);
+
+// 实现 SyncRecord：表名、pub_id 字段，可选的 Option<Deleted> 字段，
+// 以及字段到列的对应，嵌套的字段用 . 连接。
+// 类型需要实现 Default，没有对应到列的字段读出来是默认值
+//
+// sync_record!(Path, "paths", pub_id, deleted, { name => "name", path => "path" });
+#[macro_export]
+macro_rules! sync_record {
+    ($ty:ty, $table:literal, $($id:ident).+, $($deleted:ident,)? { $($($field:ident).+ => $column:literal),* $(,)? }) => {
+        impl $crate::record::SyncRecord for $ty {
+            const TABLE: &'static str = $table;
+            const COLUMNS: &'static [&'static str] = &[$($column),*];
+
+            fn pub_id(&self) -> ::uuid::Uuid {
+                self.$($id).+
+            }
+
+            fn values(&self) -> Vec<::sqlite::Value> {
+                vec![$($crate::record::Column::to_value(&self.$($field).+)),*]
+            }
+
+            fn from_values(pub_id: ::uuid::Uuid, values: Vec<::sqlite::Value>) -> Option<Self> {
+                let mut record = Self::default();
+                record.$($id).+ = pub_id;
+                let mut values = values.into_iter();
+                $(record.$($field).+ = $crate::record::Column::from_value(values.next()?)?;)*
+                Some(record)
+            }
+
+            $(
+                fn deleted(&self) -> Option<&$crate::Deleted> {
+                    self.$deleted.as_ref()
+                }
+            )?
+        }
+    };
+}
+
+// 注册过的记录类型
+#[derive(Clone, Copy)]
+struct RecordType {
+    columns: &'static [&'static str],
+    materialize: fn(&AutoCommit, &Connection) -> Result<(), Error>,
+    json: fn(&AutoCommit) -> Result<serde_json::Value, Error>,
+}
+
+static REGISTRY: Mutex<BTreeMap<&'static str, RecordType>> = Mutex::new(BTreeMap::new());
+
+// 注册记录类型，之后这种记录的 doc 都可以物化，同一个表只能注册一种类型
+pub fn register<T: SyncRecord>() {
+    REGISTRY.lock().unwrap().insert(
+        T::TABLE,
+        RecordType {
+            columns: T::COLUMNS,
+            materialize: materialize_doc::<T>,
+            json: json_doc::<T>,
+        },
+    );
+}
+
+// 注册过的表和列
+pub fn registered() -> Vec<(&'static str, &'static [&'static str])> {
+    let registry = REGISTRY.lock().unwrap();
+    registry.iter().map(|(t, r)| (*t, r.columns)).collect()
+}
+
+fn lookup(doc: &AutoCommit) -> Result<RecordType, Error> {
+    let table = table_of(doc).ok_or_else(|| Error::InvalidInput("record doc".to_string()))?;
+    let registry = REGISTRY.lock().unwrap();
+    registry
+        .get(table.as_str())
+        .copied()
+        .ok_or(Error::InvalidInput(format!("record table {table}")))
+}
+
+// 记录的 doc：根上是表名和记录本身
+pub fn new_doc<T: SyncRecord>(record: &T) -> Result<AutoCommit, Error> {
+    let mut doc = AutoCommit::new();
+    doc.put(automerge::ROOT, "table", T::TABLE)?;
+    reconcile_prop(&mut doc, automerge::ROOT, "record", record)?;
+    Ok(doc)
+}
+
+// doc 里存的是哪个表的记录
+pub fn table_of(doc: &AutoCommit) -> Option<String> {
+    match doc.get(automerge::ROOT, "table").ok()?? {
+        (automerge::Value::Scalar(s), _) => match s.as_ref() {
+            ScalarValue::Str(table) => Some(table.to_string()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+pub fn hydrate_doc<T: SyncRecord>(doc: &AutoCommit) -> Result<T, Error> {
+    match table_of(doc) {
+        Some(table) if table == T::TABLE => Ok(hydrate_prop(doc, automerge::ROOT, "record")?),
+        _ => Err(Error::InvalidInput(format!("record table {}", T::TABLE))),
+    }
+}
+
+pub fn reconcile_doc<T: SyncRecord>(doc: &mut AutoCommit, record: &T) -> Result<(), Error> {
+    Ok(reconcile_prop(doc, automerge::ROOT, "record", record)?)
+}
+
+// 删除整条记录记在根上，和记录有没有删除字段无关
+pub fn deleted(doc: &AutoCommit) -> Result<Option<Deleted>, Error> {
+    if doc.get(automerge::ROOT, "deleted")?.is_none() {
+        return Ok(None);
+    }
+    Ok(Some(hydrate_prop(doc, automerge::ROOT, "deleted")?))
+}
+
+pub fn delete(doc: &mut AutoCommit, deleted: &Deleted) -> Result<(), Error> {
+    Ok(reconcile_prop(doc, automerge::ROOT, "deleted", deleted)?)
+}
+
+fn materialize_doc<T: SyncRecord>(doc: &AutoCommit, conn: &Connection) -> Result<(), Error> {
+    db::ensure_table(conn, T::TABLE, T::COLUMNS)?;
+    let record = hydrate_doc::<T>(doc)?;
+    db::write_record(conn, &record)?;
+    if let Some(deleted) = deleted(doc)? {
+        db::mark_deleted(conn, T::TABLE, &record.pub_id().to_string(), &deleted)?;
+    }
+    Ok(())
+}
+
+fn json_doc<T: SyncRecord>(doc: &AutoCommit) -> Result<serde_json::Value, Error> {
+    Ok(serde_json::json!(hydrate_doc::<T>(doc)?))
+}
+
+// 按注册的类型把记录的 doc 写入数据库
+pub fn materialize(doc: &AutoCommit, conn: &Connection) -> Result<(), Error> {
+    (lookup(doc)?.materialize)(doc, conn)
+}
+
+// 按注册的类型读出记录
+pub fn json(doc: &AutoCommit) -> Result<serde_json::Value, Error> {
+    (lookup(doc)?.json)(doc)
+}
@@ -0,0 +1,109 @@
+use automerge::{AutoCommit, ChangeHash};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::PeerPermission;
+
+// 和一个 peer 的同步情况
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum SyncState {
+    InSync,
+    // 对方还没确认有了的 change 数量
+    Behind { changes: usize },
+    // 正在发给对方
+    Syncing,
+    // 最后一次发送或者合并失败
+    Failed { error: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PeerStatus {
+    pub peer: String,
+    pub permission: PeerPermission,
+    #[serde(flatten)]
+    pub state: SyncState,
+    // 最后一次成功收发的时间，毫秒
+    pub last_synced: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DocStatus {
+    pub id: Uuid,
+    pub name: String,
+    pub peers: Vec<PeerStatus>,
+}
+
+// 和一个 peer 的收发记录，只记在内存里。
+// 发送是单向的，发出去不代表对方收下了，只有对方发来的 doc 里有的 change 才算对方有了
+#[derive(Debug, Clone, Default)]
+pub struct PeerSync {
+    // 对方最后发来的 doc 的 heads
+    pub their_heads: Option<Vec<ChangeHash>>,
+    // 正在发给对方
+    pub in_flight: bool,
+    pub last_synced: Option<i64>,
+    pub error: Option<String>,
+}
+
+impl PeerSync {
+    // 对方确认有了的 heads
+    pub fn acked(&self) -> Option<&[ChangeHash]> {
+        self.their_heads.as_deref()
+    }
+
+    // 合并了对方发来的 doc
+    pub fn received(&mut self, heads: Vec<ChangeHash>, time: i64) {
+        self.their_heads = Some(heads);
+        self.last_synced = Some(time);
+        self.error = None;
+    }
+
+    pub fn sending(&mut self) {
+        self.in_flight = true;
+    }
+
+    // 发送结束。对方可能不收，要等对方发回来才知道对方有了哪些
+    pub fn sent(&mut self, result: Result<(), String>, time: i64) {
+        self.in_flight = false;
+        match result {
+            Ok(()) => {
+                self.last_synced = Some(time);
+                self.error = None;
+            }
+            Err(e) => self.error = Some(e),
+        }
+    }
+
+    pub fn failed(&mut self, error: String) {
+        self.error = Some(error);
+    }
+
+    // 截断历史之后以前的 heads 都没用了
+    pub fn reset(&mut self) {
+        self.their_heads = None;
+    }
+
+    pub fn status(&self, doc: &mut AutoCommit) -> SyncState {
+        if self.in_flight {
+            return SyncState::Syncing;
+        }
+        if let Some(error) = &self.error {
+            return SyncState::Failed {
+                error: error.clone(),
+            };
+        }
+        // 本地没有的 hash 不算
+        let known: Vec<_> = self
+            .acked()
+            .unwrap_or_default()
+            .iter()
+            .filter(|h| doc.get_change_by_hash(h).is_some())
+            .copied()
+            .collect();
+        match doc.get_changes(&known).len() {
+            0 => SyncState::InSync,
+            changes => SyncState::Behind { changes },
+        }
+    }
+}
@@ -1,55 +1,29 @@
 use std::time::Duration;
 
 use automerge::ActorId;
+use serde_json::json;
 use tokio::time::sleep;
-use uhlc::{Timestamp, NTP64};
 use uuid::Uuid;
 
-// 多生产，多消费 广播
-use tokio::sync::broadcast;
+use autosurgeon::{hydrate, reconcile};
 
-// 控制并发数， crdt太多，cpu会爆炸
-use tokio::sync::Semaphore;
-
-use autosurgeon::{hydrate, reconcile, Hydrate, Reconcile};
-
-#[derive(Debug, Clone, Reconcile, Hydrate, PartialEq)]
-struct AssetObject {
-    hash: String,
-    size: u64,
-    mime_type: String,
-    file_path: FilePath,
-    media_data: MediaData,
-}
-
-#[derive(Debug, Clone, Reconcile, Hydrate, PartialEq)]
-struct FilePath {
-    name: String,
-    description: String,
-}
-
-#[derive(Debug, Clone, Reconcile, Hydrate, PartialEq)]
-struct MediaData {
-    width: u32,
-    height: u32,
-    duration: u32,
-    bitrate: u32,
-    has_audio: bool,
-}
+use crate::folder::{AssetObject, FilePath, Folder, MediaData};
 
 #[tokio::test]
 async fn test_asset_object_merge() {
     let uuid: Uuid = Uuid::new_v4();
-    println!("uuid: {}", uuid.to_string());
+    println!("uuid: {}", uuid);
 
     // 如果是共享的是文件 就不包含路径
     let mut object1 = AssetObject {
+        pub_id: Uuid::new_v4(),
         hash: "hash".to_string(),
         size: 1024,
         mime_type: "image/png".to_string(),
         file_path: FilePath {
             name: "test".to_string(),
-            description: "test".to_string(),
+            description: "test".into(),
+            materialized_path: "/".to_string(),
         },
         media_data: MediaData {
             width: 1920,
@@ -86,22 +60,11 @@ async fn test_asset_object_merge() {
     doc.merge(&mut doc3).unwrap();
 
     // 从文档中恢复对象
-    let object4: AssetObject = hydrate(&doc).unwrap();
+    let _object4: AssetObject = hydrate(&doc).unwrap();
     // println!("file_path4: {:#?}", object4);
     println!("doc: {:?}", doc);
 }
 
-// 分享文件夹
-#[derive(Debug, Clone, Reconcile, Hydrate, PartialEq)]
-struct Folder {
-    pub_id: Uuid,
-    name: String,
-    description: String,
-    materialized_path: String,
-    files: Vec<AssetObject>,
-    folders: Vec<Folder>,
-}
-
 #[tokio::test]
 async fn test_folder_merge() {
     // 如果分享的是一个文件夹，就包含路径
@@ -109,14 +72,16 @@ async fn test_folder_merge() {
         pub_id: Uuid::new_v4(),             // 分布式全局唯一
         materialized_path: "/".to_string(), // 自己的路径不需要，只需要子文件夹的路径
         name: "test1".to_string(),
-        description: "test1".to_string(),
+        description: "test1".into(),
         files: vec![AssetObject {
+            pub_id: Uuid::new_v4(),
             hash: "hash".to_string(),
             size: 1024,
             mime_type: "image/png".to_string(),
             file_path: FilePath {
                 name: "test".to_string(),
-                description: "test".to_string(),
+                description: "test".into(),
+                materialized_path: "/test1".to_string(),
             },
             media_data: MediaData {
                 width: 1920,
@@ -130,10 +95,12 @@ async fn test_folder_merge() {
             pub_id: Uuid::new_v4(),
             materialized_path: "/test1".to_string(),
             name: "test2".to_string(),
-            description: "test2".to_string(),
+            description: "test2".into(),
             files: vec![],
             folders: vec![],
+            tombstones: vec![],
         }],
+        tombstones: vec![],
     };
 
     // 创建一个新的文档 文档是一个自动提交的文档 文档是automerge的一个最小单元
@@ -147,12 +114,14 @@ async fn test_folder_merge() {
     let mut folder2: Folder = hydrate(&doc2).unwrap();
 
     folder2.files.push(AssetObject {
+        pub_id: Uuid::new_v4(),
         hash: "hash2".to_string(),
         size: 1024,
         mime_type: "image/png".to_string(),
         file_path: FilePath {
             name: "test".to_string(),
-            description: "test".to_string(),
+            description: "test".into(),
+            materialized_path: "/test1".to_string(),
         },
         media_data: MediaData {
             width: 1920,
@@ -168,12 +137,14 @@ async fn test_folder_merge() {
     // 第一个object 再修改了一次
     sleep(Duration::from_secs(1)).await;
     folder1.files.push(AssetObject {
+        pub_id: Uuid::new_v4(),
         hash: "hash3".to_string(),
         size: 1024,
         mime_type: "image/png".to_string(),
         file_path: FilePath {
             name: "test".to_string(),
-            description: "test".to_string(),
+            description: "test".into(),
+            materialized_path: "/test1".to_string(),
         },
         media_data: MediaData {
             width: 1920,
@@ -188,7 +159,2511 @@ async fn test_folder_merge() {
     // 合并文档
     doc.merge(&mut doc2).unwrap();
 
-    // 从文档中恢复对象
+    // 从文档中恢复对象，两边新加的文件都在，原来的文件没有被覆盖
     let folder3: Folder = hydrate(&doc).unwrap();
-    println!("folder3: {:#?}", folder3);
+    let mut hashes: Vec<&str> = folder3.files.iter().map(|f| f.hash.as_str()).collect();
+    hashes.sort();
+    assert_eq!(hashes, vec!["hash", "hash2", "hash3"]);
+    assert_eq!(folder3.folders, folder1.folders);
+}
+
+#[test]
+fn test_folder_merge_keyed_items() {
+    let mut folder = Folder::new("shared", "");
+    let a = folder
+        .add_file(AssetObject::new("a.png", "a", 1, "image/png"))
+        .pub_id;
+    let b = folder
+        .add_file(AssetObject::new("b.png", "b", 1, "image/png"))
+        .pub_id;
+    let c = folder
+        .add_file(AssetObject::new("c.png", "c", 1, "image/png"))
+        .pub_id;
+    let sub = folder.add_folder(Folder::new("sub", "")).pub_id;
+
+    let mut doc1 = automerge::AutoCommit::new();
+    reconcile(&mut doc1, &folder).unwrap();
+    let mut doc2 = doc1.fork().with_actor(ActorId::random());
+
+    // peer1 删除 a，在最后加上 d
+    let mut folder1: Folder = hydrate(&doc1).unwrap();
+    folder1.files.retain(|f| f.pub_id != a);
+    let d = folder1
+        .add_file(AssetObject::new("d.png", "d", 1, "image/png"))
+        .clone();
+    reconcile(&mut doc1, &folder1).unwrap();
+
+    // peer2 修改 b 的描述和 c 的名字，删除子文件夹，新建另一个子文件夹
+    let mut folder2: Folder = hydrate(&doc2).unwrap();
+    folder2.files[1].file_path.description.update("edited");
+    folder2.files[2].file_path.name = "c2.png".to_string();
+    folder2.folders.retain(|f| f.pub_id != sub);
+    let sub2 = folder2.add_folder(Folder::new("sub2", "")).pub_id;
+    reconcile(&mut doc2, &folder2).unwrap();
+
+    doc1.merge(&mut doc2).unwrap();
+    let merged: Folder = hydrate(&doc1).unwrap();
+
+    let ids: Vec<Uuid> = merged.files.iter().map(|f| f.pub_id).collect();
+    assert_eq!(ids, vec![b, c, d.pub_id]);
+    assert_eq!(merged.files[0].file_path.name, "b.png");
+    assert_eq!(merged.files[0].file_path.description, "edited");
+    assert_eq!(merged.files[1].file_path.name, "c2.png");
+    assert_eq!(merged.files[1].hash, "c");
+    assert_eq!(merged.files[2], d);
+    let folders: Vec<Uuid> = merged.folders.iter().map(|f| f.pub_id).collect();
+    assert_eq!(folders, vec![sub2]);
+
+    // 两边合并后的结果一样
+    let mut doc2 = doc2.fork();
+    doc2.merge(&mut doc1).unwrap();
+    assert_eq!(hydrate::<_, Folder>(&doc2).unwrap(), merged);
+}
+
+#[test]
+fn test_folder_materialized_path_and_db() {
+    let mut root = Folder::new("photos", "shared photos");
+    let trip = root.add_folder(Folder::new("trip", ""));
+    trip.add_file(AssetObject::new("beach.png", "hash1", 2048, "image/png"));
+    let trip_id = trip.pub_id;
+    root.add_file(AssetObject::new("cover.png", "hash2", 1024, "image/png"));
+
+    assert_eq!(root.folders[0].materialized_path, "/photos");
+    assert_eq!(
+        root.folders[0].files[0].file_path.materialized_path,
+        "/photos/trip"
+    );
+    assert_eq!(root.files[0].file_path.materialized_path, "/photos");
+
+    // 重命名根文件夹后重新计算路径
+    root.name = "pictures".to_string();
+    root.materialize();
+    assert_eq!(
+        root.find_folder(&trip_id).unwrap().files[0]
+            .file_path
+            .materialized_path,
+        "/pictures/trip"
+    );
+
+    // 通过文档同步之后写入数据库
+    let mut doc = automerge::AutoCommit::new();
+    reconcile(&mut doc, &root).unwrap();
+    let hydrated: Folder = hydrate(&doc).unwrap();
+    assert_eq!(hydrated, root);
+
+    let conn = sqlite::open(":memory:").unwrap();
+    crate::migrate::migrate(&conn).unwrap();
+    crate::db::sync_folder_db(&conn, &hydrated).unwrap();
+    // 重复写入不会产生重复记录
+    crate::db::sync_folder_db(&conn, &hydrated).unwrap();
+
+    let mut stmt = conn
+        .prepare("SELECT name, materialized_path FROM files ORDER BY name")
+        .unwrap();
+    let mut files = vec![];
+    while let sqlite::State::Row = stmt.next().unwrap() {
+        files.push((
+            stmt.read::<String, _>(0).unwrap(),
+            stmt.read::<String, _>(1).unwrap(),
+        ));
+    }
+    assert_eq!(
+        files,
+        vec![
+            ("beach.png".to_string(), "/pictures/trip".to_string()),
+            ("cover.png".to_string(), "/pictures".to_string()),
+        ]
+    );
+
+    let mut stmt = conn
+        .prepare("SELECT parent_pub_id FROM folders WHERE name = 'trip'")
+        .unwrap();
+    stmt.next().unwrap();
+    assert_eq!(stmt.read::<String, _>(0).unwrap(), root.pub_id.to_string());
+}
+
+fn temp_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("crdt-{name}-{}", Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn test_watcher_scan_and_translate() {
+    use crate::watcher::{scan, translate};
+    use crate::{CrdtOperation, Update};
+    use notify::event::{CreateKind, ModifyKind, RemoveKind, RenameMode};
+    use notify::{Event, EventKind};
+
+    let root = temp_dir("watch");
+    std::fs::create_dir(root.join("trip")).unwrap();
+    std::fs::write(root.join("trip/beach.png"), b"beach").unwrap();
+    std::fs::write(root.join(".crdt-trash"), b"ignored").unwrap();
+
+    // 启动时扫描
+    let mut folder = Folder::new("photos", "");
+    let ops = scan(&root, &mut folder, true).unwrap();
+    assert_eq!(ops.len(), 2);
+    let paths: Vec<String> = folder.entries().into_iter().map(|e| e.0).collect();
+    assert_eq!(paths, vec!["trip", "trip/beach.png"]);
+    let beach = folder.folders[0].files[0].pub_id;
+    // 再扫描一次没有变化
+    assert!(scan(&root, &mut folder, true).unwrap().is_empty());
+
+    // 改名，移动，修改内容，新建，删除
+    std::fs::rename(root.join("trip/beach.png"), root.join("trip/sea.png")).unwrap();
+    std::fs::create_dir(root.join("2024")).unwrap();
+    std::fs::rename(root.join("trip"), root.join("2024/trip")).unwrap();
+    std::fs::write(root.join("2024/trip/sea.png"), b"sea").unwrap();
+    std::fs::write(root.join("notes.txt"), b"notes").unwrap();
+    let events = vec![
+        Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::From)))
+            .add_path(root.join("trip/beach.png"))
+            .set_tracker(1),
+        Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::To)))
+            .add_path(root.join("trip/sea.png"))
+            .set_tracker(1),
+        Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::Both)))
+            .add_path(root.join("trip/beach.png"))
+            .add_path(root.join("trip/sea.png"))
+            .set_tracker(1),
+        Event::new(EventKind::Create(CreateKind::Folder)).add_path(root.join("2024")),
+        Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::Both)))
+            .add_path(root.join("trip"))
+            .add_path(root.join("2024/trip"))
+            .set_tracker(2),
+        Event::new(EventKind::Modify(ModifyKind::Data(
+            notify::event::DataChange::Content,
+        )))
+        .add_path(root.join("2024/trip/sea.png")),
+        Event::new(EventKind::Create(CreateKind::File)).add_path(root.join("notes.txt")),
+    ];
+    let ops = translate(&root, &mut folder, &events);
+    assert_eq!(ops.len(), 5);
+    assert_eq!(
+        ops[0],
+        CrdtOperation::Update(beach, Update::Name("sea.png".to_string()))
+    );
+    assert!(matches!(&ops[2], CrdtOperation::Update(_, Update::Path(p)) if p == "2024/trip"));
+    assert!(
+        matches!(&ops[3], CrdtOperation::Update(id, Update::Content { size: 3, .. }) if *id == beach)
+    );
+    let mut paths: Vec<String> = folder.entries().into_iter().map(|e| e.0).collect();
+    paths.sort();
+    assert_eq!(
+        paths,
+        vec!["2024", "2024/trip", "2024/trip/sea.png", "notes.txt"]
+    );
+    assert_eq!(
+        folder
+            .find_file(&beach)
+            .unwrap()
+            .file_path
+            .materialized_path,
+        "/photos/2024/trip"
+    );
+
+    // 移出共享文件夹算删除
+    let events = vec![
+        Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::From)))
+            .add_path(root.join("notes.txt"))
+            .set_tracker(3),
+        Event::new(EventKind::Remove(RemoveKind::Folder)).add_path(root.join("2024")),
+    ];
+    let ops = translate(&root, &mut folder, &events);
+    assert_eq!(ops.len(), 2);
+    assert!(folder.entries().is_empty());
+
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[tokio::test]
+async fn test_watcher_inotify_events() {
+    use crate::watcher::FolderWatcher;
+
+    let root = temp_dir("inotify");
+    let node = test_manager();
+    let id = node.create_folder(&Folder::new("shared", "")).unwrap();
+
+    let mut watcher = FolderWatcher::new(&root, Duration::from_millis(200)).unwrap();
+    std::fs::write(root.join("a.txt"), b"a").unwrap();
+    std::fs::rename(root.join("a.txt"), root.join("b.txt")).unwrap();
+
+    // 新建之后马上改名，防抖之后合并成一个新建操作，提交成一个 change
+    let ops = tokio::time::timeout(Duration::from_secs(5), watcher.sync(&node, &id))
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert_eq!(ops.len(), 1);
+    let folder = node.folder(&id).unwrap();
+    assert_eq!(folder.files.len(), 1);
+    assert_eq!(folder.files[0].file_path.name, "b.txt");
+    assert_eq!(node.history(&id).unwrap().len(), 2);
+
+    // 只读的文件夹提交失败，返回错误
+    let readonly = test_manager();
+    let peer = libp2p::PeerId::random();
+    node.share(&id, peer, crate::PeerPermission::ReadOnly)
+        .unwrap();
+    let (_, invite) = node.invites(&id).unwrap().pop().unwrap();
+    readonly.receive(peer, invite).unwrap();
+    std::fs::write(root.join("c.txt"), b"c").unwrap();
+    let result = tokio::time::timeout(Duration::from_secs(5), watcher.sync(&readonly, &id))
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(matches!(result, Err(crate::Error::PermissionDenied(_))));
+
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_materialize_remote_changes() {
+    use crate::materialize::{Conflict, Materializer};
+    use crate::watcher::{hash_file, scan};
+    use crate::{CrdtOperation, Update};
+
+    let root = temp_dir("materialize");
+    std::fs::create_dir(root.join("trip")).unwrap();
+    std::fs::write(root.join("trip/beach.png"), b"beach").unwrap();
+    std::fs::write(root.join("old.txt"), b"old").unwrap();
+    std::fs::write(root.join("notes.txt"), b"notes").unwrap();
+    std::fs::write(root.join("todo.txt"), b"todo").unwrap();
+
+    let mut old = Folder::new("shared", "");
+    scan(&root, &mut old, false).unwrap();
+    let id = |folder: &Folder, path: &str| {
+        folder
+            .entries()
+            .into_iter()
+            .find(|e| e.0 == path)
+            .unwrap()
+            .1
+    };
+
+    // 远端：trip 改名成 2024，beach.png 改名，删除 old.txt 和 todo.txt，修改 notes.txt
+    let mut new = old.clone();
+    let beach = id(&old, "trip/beach.png");
+    let notes = id(&old, "notes.txt");
+    let todo = id(&old, "todo.txt");
+    for op in [
+        CrdtOperation::Update(id(&old, "trip"), Update::Name("2024".to_string())),
+        CrdtOperation::Update(beach, Update::Path("2024/sea.png".to_string())),
+        CrdtOperation::Delete(id(&old, "old.txt")),
+        CrdtOperation::Delete(todo),
+        CrdtOperation::Update(
+            notes,
+            Update::Content {
+                hash: "remote".to_string(),
+                size: 6,
+            },
+        ),
+    ] {
+        new.apply(&op).unwrap();
+    }
+
+    // 本地同时修改了 notes.txt 和 todo.txt
+    std::fs::write(root.join("notes.txt"), b"local notes").unwrap();
+    std::fs::write(root.join("todo.txt"), b"local todo").unwrap();
+
+    let report = Materializer::new(&root).apply(&old, &new).unwrap();
+
+    assert!(root.join("2024/sea.png").exists());
+    assert!(!root.join("trip").exists());
+    assert!(!root.join("old.txt").exists());
+    assert!(root.join(".crdt-trash").read_dir().unwrap().count() >= 2);
+    assert_eq!(hash_file(&root.join("2024/sea.png")).unwrap().1, 5);
+
+    assert_eq!(report.missing, vec![(notes, "remote".to_string())]);
+    assert_eq!(report.conflicts.len(), 2);
+    for conflict in report.conflicts.iter() {
+        match conflict {
+            Conflict::DeletedButModified { pub_id, kept_as } => {
+                assert_eq!(*pub_id, todo);
+                assert_eq!(std::fs::read(kept_as).unwrap(), b"local todo");
+            }
+            Conflict::BothModified { pub_id, kept_as } => {
+                assert_eq!(*pub_id, notes);
+                assert_eq!(std::fs::read(kept_as).unwrap(), b"local notes");
+                assert!(kept_as.starts_with(&root) && !root.join("notes.txt").exists());
+            }
+            other => panic!("unexpected conflict {other:?}"),
+        }
+    }
+
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_materialize_rejects_unsafe_names() {
+    use crate::folder::FolderError;
+    use crate::materialize::Materializer;
+    use crate::watcher::scan;
+    use crate::{tree, CrdtOperation, Error, Update};
+
+    let root = temp_dir("unsafe");
+    std::fs::create_dir(root.join("sub")).unwrap();
+    std::fs::write(root.join("sub/b.txt"), b"b").unwrap();
+    std::fs::write(root.join("a.txt"), b"a").unwrap();
+    let mut old = Folder::new("shared", "");
+    scan(&root, &mut old, false).unwrap();
+    let id = |path: &str| old.entries().into_iter().find(|e| e.0 == path).unwrap().1;
+    let (a, sub, b) = (id("a.txt"), id("sub"), id("sub/b.txt"));
+
+    // 本地的修改不能用会跑到外面的名字
+    let mut doc = tree::new_doc(&old).unwrap();
+    for name in ["", ".", "..", "../../.bashrc", "/etc/passwd"] {
+        let op = CrdtOperation::Update(a, Update::Name(name.to_string()));
+        let result = tree::edit_folder(&mut doc, |f| Ok(f.apply(&op)?));
+        assert!(matches!(
+            result,
+            Err(Error::Folder(FolderError::InvalidName(_)))
+        ));
+    }
+    let op = CrdtOperation::Update(a, Update::Path("sub/..".to_string()));
+    assert_eq!(
+        old.clone().apply(&op),
+        Err(FolderError::InvalidName("..".to_string()))
+    );
+
+    // 远端不检查名字，直接改了 doc
+    let escape = format!("escape-{}.txt", Uuid::new_v4().simple());
+    let mut remote = doc.fork();
+    tree::edit_folder(&mut remote, |f| {
+        f.files
+            .iter_mut()
+            .find(|x| x.pub_id == a)
+            .unwrap()
+            .file_path
+            .name = format!("../{escape}");
+        f.folders[0].name = "..".to_string();
+        Ok(())
+    })
+    .unwrap();
+    doc.merge(&mut remote).unwrap();
+    let new = tree::hydrate_folder(&doc).unwrap();
+
+    // 路径不安全的条目跳过，本地保持原样
+    let report = Materializer::new(&root).apply(&old, &new).unwrap();
+    let mut expected = vec![
+        (a, format!("../{escape}")),
+        (sub, "..".to_string()),
+        (b, "../b.txt".to_string()),
+    ];
+    expected.sort();
+    assert_eq!(report.rejected, expected);
+    assert!(report.missing.is_empty() && report.conflicts.is_empty());
+    assert!(!root.parent().unwrap().join(&escape).exists());
+    assert!(root.join("a.txt").exists());
+    assert!(root.join("sub/b.txt").exists());
+
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[tokio::test]
+async fn test_materialize_ingested_changes() {
+    use crate::materialize::Materializer;
+    use crate::{CrdtOperation, Entry, EntryKind, PeerPermission, Update};
+    use sha2::Digest;
+
+    let root = temp_dir("materialize-ingested");
+    let node1 = test_manager();
+    let node2 = std::sync::Arc::new(test_manager());
+    let peer1 = libp2p::PeerId::random();
+    let peer2 = libp2p::PeerId::random();
+    let id = node1.create_folder(&Folder::new("shared", "")).unwrap();
+    node1.share(&id, peer2, PeerPermission::ReadWrite).unwrap();
+    let send = |node1: &crate::Manager| {
+        let (_, invite) = node1.invites(&id).unwrap().pop().unwrap();
+        node2.receive(peer1, invite).unwrap();
+    };
+    send(&node1);
+
+    let (tx, mut reports) = tokio::sync::mpsc::unbounded_channel();
+    Materializer::new(&root)
+        .with_last(node2.folder(&id).unwrap())
+        .spawn(node2.clone(), id, tx);
+
+    // 远端新建的文件夹落地到本地，文件内容需要下载
+    let (trip, beach) = (Uuid::new_v4(), Uuid::new_v4());
+    let create = |pub_id, path: &str, kind| {
+        CrdtOperation::Create(Entry {
+            pub_id,
+            path: path.to_string(),
+            kind,
+        })
+    };
+    let hash = hex::encode(sha2::Sha256::digest(b"beach"));
+    let file = EntryKind::File {
+        hash: hash.clone(),
+        size: 5,
+        mime_type: "image/png".to_string(),
+    };
+    node1
+        .update_all(
+            &id,
+            &[
+                create(trip, "trip", EntryKind::Folder),
+                create(beach, "trip/beach.png", file),
+            ],
+        )
+        .unwrap();
+    send(&node1);
+    let report = tokio::time::timeout(Duration::from_secs(5), reports.recv())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert!(root.join("trip").is_dir());
+    assert_eq!(report.missing, vec![(beach, hash)]);
+    std::fs::write(root.join("trip/beach.png"), b"beach").unwrap();
+
+    // 从上一次落地的状态开始，改名之后移动本地的文件
+    node1
+        .update(
+            &id,
+            CrdtOperation::Update(trip, Update::Name("2024".to_string())),
+        )
+        .unwrap();
+    send(&node1);
+    tokio::time::timeout(Duration::from_secs(5), reports.recv())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert!(!root.join("trip").exists());
+    assert_eq!(
+        std::fs::read(root.join("2024/beach.png")).unwrap(),
+        b"beach"
+    );
+
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[tokio::test]
+async fn test_transfer_chunked_with_resume() {
+    use crate::transfer::{download, part_path, serve, ContentStore, CHUNK_SIZE};
+    use crate::watcher::hash_file;
+    use tokio_util::compat::TokioAsyncReadCompatExt;
+
+    let dir = temp_dir("transfer");
+    let data: Vec<u8> = (0..CHUNK_SIZE * 2 + 100).map(|i| (i % 251) as u8).collect();
+    std::fs::write(dir.join("source.bin"), &data).unwrap();
+    let (hash, _) = hash_file(&dir.join("source.bin")).unwrap();
+
+    let store = std::sync::Arc::new(ContentStore::new());
+    store.insert(&hash, dir.join("source.bin"));
+
+    // 已经下载了一块多，续传时丢掉不完整的块
+    let download_dir = dir.join("download");
+    std::fs::create_dir(&download_dir).unwrap();
+    let part = part_path(&download_dir, &hash);
+    std::fs::write(&part, &data[..CHUNK_SIZE + 10]).unwrap();
+
+    let (client, server) = tokio::io::duplex(1024);
+    let server_store = store.clone();
+    let server = tokio::spawn(async move {
+        serve(&server_store, &mut server.compat()).await.unwrap();
+    });
+    let size = data.len() as u64;
+    download(&mut client.compat(), &hash, size, &part)
+        .await
+        .unwrap();
+    server.await.unwrap();
+
+    assert_eq!(std::fs::read(&part).unwrap(), data);
+
+    // 没有的文件
+    let (client, server) = tokio::io::duplex(1024);
+    let server = tokio::spawn(async move {
+        serve(&store, &mut server.compat()).await.unwrap();
+    });
+    let err = download(
+        &mut client.compat(),
+        "missing",
+        1,
+        &dir.join("missing.part"),
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+    server.await.unwrap();
+
+    // 对方发来的内容和 doc 里的 hash 不一样，块 hash 对得上也不要，已经下载的部分清空
+    let fake = dir.join("fake.bin");
+    std::fs::write(&fake, vec![0u8; data.len()]).unwrap();
+    let store = ContentStore::new();
+    store.insert(&hash, &fake);
+    let part = part_path(&dir, &hash);
+    std::fs::write(&part, &data[..CHUNK_SIZE]).unwrap();
+    let (client, server) = tokio::io::duplex(1024);
+    let server = tokio::spawn(async move {
+        serve(&store, &mut server.compat()).await.unwrap();
+    });
+    let err = download(&mut client.compat(), &hash, size, &part)
+        .await
+        .unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    assert_eq!(std::fs::metadata(&part).unwrap().len(), 0);
+    server.await.unwrap();
+
+    // 远端的名字指到共享文件夹外面的文件不登记，不会提供给别人
+    let mut folder = Folder::new("shared", "");
+    folder.add_file(AssetObject::new("../source.bin", &hash, size, ""));
+    std::fs::write(download_dir.join("a.bin"), b"a").unwrap();
+    folder.add_file(AssetObject::new("a.bin", "a", 1, ""));
+    let store = ContentStore::new();
+    store.index_folder(&download_dir, &folder);
+    assert_eq!(store.get(&hash), None);
+    assert_eq!(store.get("a"), Some(download_dir.join("a.bin")));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+fn test_manager() -> crate::Manager {
+    let control = libp2p_stream::Behaviour::new().new_control();
+    crate::Manager::new(sqlite::open(":memory:").unwrap(), control).unwrap()
+}
+
+#[test]
+fn test_manager_multiple_docs() {
+    use crate::{CrdtOperation, DocKind, Error, PeerPermission, Update};
+
+    let manager = test_manager();
+    let a = manager.create_path("a", "a.txt", "").unwrap();
+    let b = manager.create_path("b", "b.txt", "").unwrap();
+    let folder = manager.create_folder(&Folder::new("photos", "")).unwrap();
+    assert_eq!(manager.list().len(), 3);
+    assert_eq!(manager.kind(&folder).unwrap(), DocKind::Folder);
+
+    // 按 uuid 修改，只影响对应的 doc
+    manager
+        .update(&b, CrdtOperation::Update(b, Update::Name("b2".to_string())))
+        .unwrap();
+    assert_eq!(manager.path(&a).unwrap().name, "a");
+    assert_eq!(manager.path(&b).unwrap().name, "b2");
+
+    // 每个 doc 都有自己的一行
+    {
+        let conn = manager.db.lock().unwrap();
+        let rows = crate::db::query_records::<crate::Path>(&conn).unwrap();
+        let names: Vec<_> = rows.iter().map(|(_, p)| p.name.as_str()).collect();
+        assert_eq!(names, vec!["a", "b2"]);
+    }
+
+    // 每个 doc 有自己的 peer
+    let peer = libp2p::PeerId::random();
+    manager.share(&a, peer, PeerPermission::ReadOnly).unwrap();
+    let summaries = manager.list();
+    let peers = |id| summaries.iter().find(|d| d.id == id).unwrap().peers.len();
+    assert_eq!(peers(a), 1);
+    assert_eq!(peers(b), 0);
+
+    let missing = Uuid::new_v4();
+    assert!(matches!(
+        manager.update(&missing, CrdtOperation::Delete(missing)),
+        Err(Error::NotFound(_))
+    ));
+}
+
+#[test]
+fn test_manager_updates_accumulate() {
+    use crate::{CrdtOperation, Update};
+
+    let manager = test_manager();
+    let id = manager.create_path("a", "a.txt", "").unwrap();
+    let created = manager.heads(&id).unwrap();
+
+    let mut heads = vec![];
+    for name in ["b", "c", "d"] {
+        let op = CrdtOperation::Update(id, Update::Name(name.to_string()));
+        heads.push(manager.update(&id, op).unwrap());
+    }
+    let op = CrdtOperation::Update(id, Update::Description("desc".to_string()));
+    let last = manager.update(&id, op).unwrap();
+
+    // 每次修改都是新的 heads，之前的修改都保留
+    assert_ne!(created, heads[0]);
+    assert_ne!(heads[0], heads[1]);
+    let path = manager.path(&id).unwrap();
+    assert_eq!(path.name, "d");
+    assert_eq!(path.description, "desc");
+
+    // 内存里的 doc、数据库和发送出去的数据是同一个 heads
+    let shared = manager.shared.lock().unwrap();
+    let info = shared.get(&id).unwrap();
+    assert_eq!(info.heads, last);
+    assert_eq!(info.crdt.clone().get_heads(), last);
+    let conn = manager.db.lock().unwrap();
+    let db_heads = crate::db::query_doc_heads(&conn, &id.to_string())
+        .unwrap()
+        .unwrap();
+    let expected: Vec<String> = last.iter().map(|h| h.to_string()).collect();
+    assert_eq!(db_heads, expected);
+    let (_, row) = crate::db::query_records::<crate::Path>(&conn)
+        .unwrap()
+        .pop()
+        .unwrap();
+    assert_eq!(row, path);
+
+    let mut sent = automerge::AutoCommit::load(&info.crdt.clone().save()).unwrap();
+    assert_eq!(sent.get_heads(), last);
+    // 创建加上 4 次修改，每次一个带说明的 change
+    let changes = sent.get_changes(&[]);
+    assert_eq!(changes.len(), 5);
+    assert_eq!(
+        changes[4].message().unwrap(),
+        &format!("update description of {id}")
+    );
+    assert!(changes[4].timestamp() > 0);
+}
+
+#[test]
+fn test_manager_concurrent_edits_merge() {
+    use crate::{CrdtOperation, PeerPermission, Update};
+
+    let node1 = test_manager();
+    let node2 = test_manager();
+    let peer1 = libp2p::PeerId::random();
+    let peer2 = libp2p::PeerId::random();
+
+    let id = node1.create_path("test", "test.txt", "").unwrap();
+    node1.share(&id, peer2, PeerPermission::ReadWrite).unwrap();
+    let (_, invite) = node1.invites(&id).unwrap().pop().unwrap();
+    node2.receive(peer1, invite).unwrap();
+
+    // 两边同时修改不同的字段
+    let op = CrdtOperation::Update(id, Update::Name("renamed".to_string()));
+    node1.update(&id, op).unwrap();
+    let op = CrdtOperation::Update(id, Update::Description("described".to_string()));
+    node2.update(&id, op).unwrap();
+
+    // 互相同步
+    let (_, invite1) = node1.invites(&id).unwrap().pop().unwrap();
+    let (_, invite2) = node2.invites(&id).unwrap().pop().unwrap();
+    node2.receive(peer1, invite1).unwrap();
+    node1.receive(peer2, invite2).unwrap();
+
+    for node in [&node1, &node2] {
+        let path = node.path(&id).unwrap();
+        assert_eq!(path.name, "renamed");
+        assert_eq!(path.description, "described");
+    }
+    assert_eq!(node1.heads(&id).unwrap(), node2.heads(&id).unwrap());
+    assert_eq!(node1.heads(&id).unwrap().len(), 2);
+
+    // 再同步一次不会产生新的修改
+    let heads = node1.heads(&id).unwrap();
+    let (_, invite2) = node2.invites(&id).unwrap().pop().unwrap();
+    node1.receive(peer2, invite2).unwrap();
+    assert_eq!(node1.heads(&id).unwrap(), heads);
+}
+
+#[test]
+fn test_manager_conflicts_and_resolve() {
+    use crate::{CrdtOperation, PeerPermission, Update};
+
+    let node1 = test_manager();
+    let node2 = test_manager();
+    let peer1 = libp2p::PeerId::random();
+    let peer2 = libp2p::PeerId::random();
+
+    let id = node1.create_path("test", "test.txt", "").unwrap();
+    node1.share(&id, peer2, PeerPermission::ReadWrite).unwrap();
+    let (_, invite) = node1.invites(&id).unwrap().pop().unwrap();
+    node2.receive(peer1, invite).unwrap();
+    assert!(node1.conflicts(&id).unwrap().is_empty());
+
+    // 两边同时修改 name
+    let op = CrdtOperation::Update(id, Update::Name("one".to_string()));
+    node1.update(&id, op).unwrap();
+    let op = CrdtOperation::Update(id, Update::Name("two".to_string()));
+    node2.update(&id, op).unwrap();
+    let (_, invite2) = node2.invites(&id).unwrap().pop().unwrap();
+    node1.receive(peer2, invite2).unwrap();
+
+    let conflicts = node1.conflicts(&id).unwrap();
+    assert_eq!(conflicts.len(), 1);
+    assert_eq!(conflicts[0].field, "name");
+    let candidates = &conflicts[0].candidates;
+    let mut values: Vec<_> = candidates.iter().map(|c| c.value.clone()).collect();
+    values.sort_by_key(|v| v.to_string());
+    assert_eq!(values, vec!["one", "two"]);
+    assert_ne!(candidates[0].actor, candidates[1].actor);
+    assert!(candidates.iter().all(|c| c.timestamp > 0));
+    // hydrate 出来的是胜出的值
+    let winner = candidates.iter().find(|c| c.winner).unwrap();
+    assert_eq!(json!(node1.path(&id).unwrap().name), winner.value);
+
+    // 选择没有胜出的值
+    let choice = candidates.iter().position(|c| !c.winner).unwrap();
+    let chosen = candidates[choice].value.clone();
+    node1.resolve(&id, "name", choice).unwrap();
+    assert!(node1.conflicts(&id).unwrap().is_empty());
+    assert_eq!(json!(node1.path(&id).unwrap().name), chosen);
+    assert!(node1.resolve(&id, "name", 0).is_err());
+
+    // 同步后对方的冲突也解决了
+    let (_, invite1) = node1.invites(&id).unwrap().pop().unwrap();
+    node2.receive(peer1, invite1).unwrap();
+    assert!(node2.conflicts(&id).unwrap().is_empty());
+    assert_eq!(json!(node2.path(&id).unwrap().name), chosen);
+}
+
+#[test]
+fn test_manager_history_and_time_travel() {
+    use crate::{CrdtOperation, PeerPermission, Update};
+
+    let node1 = test_manager();
+    let node2 = test_manager();
+    let peer1 = libp2p::PeerId::random();
+    let peer2 = libp2p::PeerId::random();
+
+    let id = node1.create_path("test", "test.txt", "").unwrap();
+    let v1 = node1.heads(&id).unwrap();
+    node1.share(&id, peer2, PeerPermission::ReadWrite).unwrap();
+    let (_, invite) = node1.invites(&id).unwrap().pop().unwrap();
+    node2.receive(peer1, invite).unwrap();
+
+    // node2 改名，node1 改描述
+    let op = CrdtOperation::Update(id, Update::Name("renamed".to_string()));
+    let v2 = node2.update(&id, op).unwrap();
+    let (_, invite2) = node2.invites(&id).unwrap().pop().unwrap();
+    node1.receive(peer2, invite2).unwrap();
+    let op = CrdtOperation::Update(id, Update::Description("desc".to_string()));
+    let v3 = node1.update(&id, op).unwrap();
+
+    let history = node1.history(&id).unwrap();
+    assert_eq!(history.len(), 3);
+    assert_eq!(history[0].message.as_deref(), Some("create"));
+    assert!(history[0].fields.contains(&"name".to_string()));
+    // 谁在什么时候改的名字
+    let rename = history
+        .iter()
+        .find(|e| e.fields == vec!["name".to_string()])
+        .unwrap();
+    assert_eq!(rename.hash, v2[0].to_string());
+    assert_ne!(rename.actor, history[0].actor);
+    assert!(rename.time > 0);
+    assert_eq!(history[2].fields, vec!["description".to_string()]);
+    assert!(history[2].deps.contains(&v2[0].to_string()));
+
+    // 读取历史版本
+    let old: crate::Path = node1.hydrate_at(&id, &v1).unwrap();
+    assert_eq!(old.name, "test");
+    let old: crate::Path = node1.hydrate_at(&id, &v2).unwrap();
+    assert_eq!(
+        (old.name.as_str(), old.description.as_str()),
+        ("renamed", "")
+    );
+    let unknown = crate::history::parse_heads(&"0".repeat(64)).unwrap();
+    assert!(node1.hydrate_at::<crate::Path>(&id, &unknown).is_err());
+
+    // 比较两个版本
+    let diff = node1.diff(&id, &v1, &v3).unwrap();
+    let mut fields: Vec<_> = diff.iter().map(|d| d.field.as_str()).collect();
+    fields.sort();
+    assert_eq!(fields, vec!["description", "name"]);
+    let name = diff.iter().find(|d| d.field == "name").unwrap();
+    assert_eq!(name.before, Some(json!("test")));
+    assert_eq!(name.after, Some(json!("renamed")));
+}
+
+#[test]
+fn test_manager_revert_and_undo() {
+    use crate::{CrdtOperation, Entry, EntryKind, PeerPermission, Update};
+
+    let node1 = test_manager();
+    let node2 = test_manager();
+    let peer1 = libp2p::PeerId::random();
+    let peer2 = libp2p::PeerId::random();
+
+    let id = node1.create_path("test", "test.txt", "").unwrap();
+    node1.share(&id, peer2, PeerPermission::ReadWrite).unwrap();
+    let (_, invite) = node1.invites(&id).unwrap().pop().unwrap();
+    node2.receive(peer1, invite).unwrap();
+
+    let op = CrdtOperation::Update(id, Update::Name("renamed".to_string()));
+    let rename = node1.update(&id, op).unwrap();
+    let op = CrdtOperation::Update(id, Update::Description("desc".to_string()));
+    node1.update(&id, op).unwrap();
+
+    // 撤销改名，之后的描述保留，历史不变只是多了一个 change
+    node1.revert(&id, &rename).unwrap();
+    let path = node1.path(&id).unwrap();
+    assert_eq!(
+        (path.name.as_str(), path.description.as_str()),
+        ("test", "desc")
+    );
+    let history = node1.history(&id).unwrap();
+    assert_eq!(history.len(), 4);
+    assert!(history[3].message.as_ref().unwrap().starts_with("revert"));
+
+    // 撤销同步给其他 peer
+    let (_, invite1) = node1.invites(&id).unwrap().pop().unwrap();
+    node2.receive(peer1, invite1).unwrap();
+    assert_eq!(node2.path(&id).unwrap(), path);
+
+    // 撤销本地最近的两个 change：撤销改名和描述
+    node1.undo(&id, 2).unwrap();
+    let path = node1.path(&id).unwrap();
+    assert_eq!(
+        (path.name.as_str(), path.description.as_str()),
+        ("renamed", "")
+    );
+    // 撤销几个 change 也只有一个带时间的 change
+    let history = node1.history(&id).unwrap();
+    assert_eq!(history.len(), 5);
+    assert!(history[4].message.as_ref().unwrap().starts_with("revert"));
+    assert!(history[4].time > 0);
+    assert!(node1
+        .revert(&id, &crate::history::parse_heads(&"0".repeat(64)).unwrap())
+        .is_err());
+
+    // 撤销文件夹里新建的条目
+    let folder = node1.create_folder(&Folder::new("root", "")).unwrap();
+    let entry = Entry {
+        pub_id: Uuid::new_v4(),
+        path: "sub".to_string(),
+        kind: EntryKind::Folder,
+    };
+    let created = node1
+        .update(&folder, CrdtOperation::Create(entry.clone()))
+        .unwrap();
+    assert!(node1
+        .folder(&folder)
+        .unwrap()
+        .find_folder(&entry.pub_id)
+        .is_some());
+    node1.revert(&folder, &created).unwrap();
+    assert!(node1
+        .folder(&folder)
+        .unwrap()
+        .find_folder(&entry.pub_id)
+        .is_none());
+    // 再撤销一次撤销，条目回来了
+    node1.undo(&folder, 1).unwrap();
+    let root = node1.folder(&folder).unwrap();
+    assert_eq!(root.find_folder(&entry.pub_id).unwrap().name, "sub");
+}
+
+#[test]
+fn test_manager_concurrent_description_edits() {
+    use crate::{CrdtOperation, Entry, EntryKind, PeerPermission, Update};
+
+    let node1 = test_manager();
+    let node2 = test_manager();
+    let peer1 = libp2p::PeerId::random();
+    let peer2 = libp2p::PeerId::random();
+
+    let id = node1
+        .create_path("test", "test.txt", "hello world")
+        .unwrap();
+    node1.share(&id, peer2, PeerPermission::ReadWrite).unwrap();
+    let (_, invite) = node1.invites(&id).unwrap().pop().unwrap();
+    node2.receive(peer1, invite).unwrap();
+
+    // 两边同时在同一个描述里打字
+    let splice = |index, delete, insert: &str| {
+        let update = Update::SpliceDescription {
+            index,
+            delete,
+            insert: insert.to_string(),
+        };
+        CrdtOperation::Update(id, update)
+    };
+    node1.update(&id, splice(5, 0, ",")).unwrap();
+    node1.update(&id, splice(7, 5, "there")).unwrap();
+    node2.update(&id, splice(11, 0, "!")).unwrap();
+    let op = CrdtOperation::Update(id, Update::Description("oh hello world!".to_string()));
+    node2.update(&id, op).unwrap();
+    assert!(node2.update(&id, splice(100, 0, "x")).is_err());
+    // 相加溢出也是超出范围
+    assert!(node2.update(&id, splice(1, usize::MAX, "x")).is_err());
+
+    let (_, invite1) = node1.invites(&id).unwrap().pop().unwrap();
+    let (_, invite2) = node2.invites(&id).unwrap().pop().unwrap();
+    node2.receive(peer1, invite1).unwrap();
+    node1.receive(peer2, invite2).unwrap();
+    for node in [&node1, &node2] {
+        assert_eq!(node.path(&id).unwrap().description, "oh hello, there!");
+        assert!(node.conflicts(&id).unwrap().is_empty());
+    }
+    let conn = node1.db.lock().unwrap();
+    let (_, row) = crate::db::query_records::<crate::Path>(&conn)
+        .unwrap()
+        .pop()
+        .unwrap();
+    assert_eq!(row.description, "oh hello, there!");
+    drop(conn);
+
+    // 文件夹里的条目也可以编辑描述，移动后描述还在
+    let folder = node1.create_folder(&Folder::new("root", "")).unwrap();
+    let sub = Uuid::new_v4();
+    for (pub_id, path) in [(sub, "a"), (Uuid::new_v4(), "b")] {
+        let entry = Entry {
+            pub_id,
+            path: path.to_string(),
+            kind: EntryKind::Folder,
+        };
+        node1.update(&folder, CrdtOperation::Create(entry)).unwrap();
+    }
+    let op = CrdtOperation::Update(sub, Update::Description("photos".to_string()));
+    node1.update(&folder, op).unwrap();
+    let op = CrdtOperation::Update(sub, Update::Path("b/a".to_string()));
+    node1.update(&folder, op).unwrap();
+    let root = node1.folder(&folder).unwrap();
+    let moved = root.find_folder(&sub).unwrap();
+    assert_eq!(moved.materialized_path, "/root/b");
+    assert_eq!(moved.description, "photos");
+}
+
+#[test]
+fn test_manager_reload_from_storage() {
+    use crate::{manager::CompactionPolicy, CrdtOperation, Manager, PeerPermission, Update};
+
+    let dir = temp_dir("storage");
+    let file = dir.join("crdt.db");
+    let open = || {
+        let control = libp2p_stream::Behaviour::new().new_control();
+        let policy = CompactionPolicy {
+            snapshot_every: 3,
+            history_horizon: None,
+        };
+        Manager::new(sqlite::open(&file).unwrap(), control)
+            .unwrap()
+            .with_policy(policy)
+    };
+    let peer = libp2p::PeerId::random();
+
+    let node = open();
+    let id = node.create_path("test", "test.txt", "hello").unwrap();
+    node.share(&id, peer, PeerPermission::ReadWrite).unwrap();
+    for i in 0..5 {
+        let op = CrdtOperation::Update(id, Update::Name(format!("name{i}")));
+        node.update(&id, op).unwrap();
+    }
+    // 新建时写快照，之后攒到 3 个 change 合并一次，中间的 change 逐个写入
+    let conn = node.db.lock().unwrap();
+    assert_eq!(crate::db::count_changes(&conn, &id.to_string()).unwrap(), 2);
+    let mut stmt = conn
+        .prepare("SELECT seq FROM changes WHERE doc_id = ? ORDER BY id")
+        .unwrap();
+    stmt.bind((1, id.to_string().as_str())).unwrap();
+    let mut seqs = vec![];
+    while let sqlite::State::Row = stmt.next().unwrap() {
+        seqs.push(stmt.read::<i64, _>(0).unwrap());
+    }
+    assert_eq!(seqs, vec![5, 6]);
+    drop(stmt);
+    drop(conn);
+    let folder = node.create_folder(&Folder::new("root", "")).unwrap();
+    let heads = node.heads(&id).unwrap();
+    let history = node.history(&id).unwrap();
+    drop(node);
+
+    let node = open();
+    assert_eq!(node.list().len(), 2);
+    assert_eq!(node.path(&id).unwrap().name, "name4");
+    assert_eq!(node.folder(&folder).unwrap().name, "root");
+    assert_eq!(node.heads(&id).unwrap(), heads);
+    assert_eq!(node.history(&id).unwrap(), history);
+    let summary = node.list().into_iter().find(|doc| doc.id == id).unwrap();
+    assert_eq!(
+        summary.peers,
+        vec![(peer.to_string(), PeerPermission::ReadWrite)]
+    );
+
+    // actor 还是原来的，可以撤销重新打开之前的修改
+    node.undo(&id, 1).unwrap();
+    assert_eq!(node.path(&id).unwrap().name, "name3");
+
+    // 手动压缩只写快照，没有设置 horizon 不截断历史
+    assert!(!node.compact(&id).unwrap());
+    let conn = node.db.lock().unwrap();
+    assert_eq!(crate::db::count_changes(&conn, &id.to_string()).unwrap(), 0);
+    drop(conn);
+    drop(node);
+    assert_eq!(open().path(&id).unwrap().name, "name3");
+
+    // 物化失败时修改已经写入存储，重新打开还在，reindex 之后表也恢复
+    let node = open();
+    let execute = |node: &Manager, sql: &str| node.db.lock().unwrap().execute(sql).unwrap();
+    execute(&node, "ALTER TABLE paths RENAME TO paths_away");
+    let op = CrdtOperation::Update(id, Update::Name("name5".to_string()));
+    node.update(&id, op).unwrap();
+    execute(&node, "ALTER TABLE paths_away RENAME TO paths");
+    assert!(!node.check().unwrap().is_empty());
+    drop(node);
+    let node = open();
+    assert_eq!(node.path(&id).unwrap().name, "name5");
+    node.reindex().unwrap();
+    assert!(node.check().unwrap().is_empty());
+    drop(node);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_manager_truncate_history() {
+    use crate::{manager::CompactionPolicy, CrdtOperation, Error, PeerPermission, Update};
+
+    let policy = CompactionPolicy {
+        history_horizon: Some(Duration::ZERO),
+        ..Default::default()
+    };
+    let node1 = test_manager().with_policy(policy.clone());
+    let node2 = test_manager().with_policy(policy);
+    let peer1 = libp2p::PeerId::random();
+    let peer2 = libp2p::PeerId::random();
+    let epoch = |node: &crate::Manager, id| node.shared.lock().unwrap()[&id].epoch;
+
+    let id = node1.create_path("a", "test.txt", "").unwrap();
+    let rename = |name: &str| CrdtOperation::Update(id, Update::Name(name.to_string()));
+    node1.share(&id, peer2, PeerPermission::ReadWrite).unwrap();
+    node1.update(&id, rename("b")).unwrap();
+
+    // peer 还没有收到，不能截断
+    assert!(!node1.compact(&id).unwrap());
+    assert_eq!(node1.history(&id).unwrap().len(), 2);
+
+    let (_, invite) = node1.invites(&id).unwrap().pop().unwrap();
+    node2.receive(peer1, invite).unwrap();
+    node2.update(&id, rename("c")).unwrap();
+    let (_, old) = node2.invites(&id).unwrap().pop().unwrap();
+    let (_, invite2) = node2.invites(&id).unwrap().pop().unwrap();
+
+    // 收到 node2 的 doc 之后两边的修改一样了，owner 截断历史
+    node1.receive(peer2, invite2).unwrap();
+    assert_eq!(epoch(&node1, id), 1);
+    let history = node1.history(&id).unwrap();
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0].message.as_deref(), Some("truncate history"));
+    assert_eq!(node1.path(&id).unwrap().name, "c");
+
+    // node2 没有新的修改，整个换成截断后的 doc
+    let (_, invite) = node1.invites(&id).unwrap().pop().unwrap();
+    assert_eq!(invite.epoch, 1);
+    node2.receive(peer1, invite).unwrap();
+    assert_eq!(epoch(&node2, id), 1);
+    assert_eq!(node2.history(&id).unwrap().len(), 1);
+    assert_eq!(node2.heads(&id).unwrap(), node1.heads(&id).unwrap());
+    assert_eq!(node2.path(&id).unwrap().name, "c");
+
+    // 截断之前的历史不会再合并进来
+    assert!(matches!(
+        node1.receive(peer2, old),
+        Err(Error::Truncated(_))
+    ));
+
+    // 之后的修改照常合并
+    node2.update(&id, rename("d")).unwrap();
+    let (_, invite2) = node2.invites(&id).unwrap().pop().unwrap();
+    node1.receive(peer2, invite2).unwrap();
+    assert_eq!(node1.path(&id).unwrap().name, "d");
+}
+
+#[test]
+fn test_manager_rebase_old_epoch_edits() {
+    use crate::{manager::CompactionPolicy, CrdtOperation, Error, PeerPermission, Update};
+
+    let policy = CompactionPolicy {
+        history_horizon: Some(Duration::ZERO),
+        ..Default::default()
+    };
+    let node1 = test_manager().with_policy(policy.clone());
+    let node2 = test_manager().with_policy(policy);
+    let peer1 = libp2p::PeerId::random();
+    let peer2 = libp2p::PeerId::random();
+
+    let id = node1.create_path("a", "test.txt", "hello").unwrap();
+    let rename = |name: &str| CrdtOperation::Update(id, Update::Name(name.to_string()));
+    node1.share(&id, peer2, PeerPermission::ReadWrite).unwrap();
+    node1.update(&id, rename("a1")).unwrap();
+    let (_, invite) = node1.invites(&id).unwrap().pop().unwrap();
+    node2.receive(peer1, invite).unwrap();
+    let (_, invite2) = node2.invites(&id).unwrap().pop().unwrap();
+    node1.receive(peer2, invite2).unwrap();
+    assert_eq!(node1.shared.lock().unwrap()[&id].epoch, 1);
+
+    // node2 还在旧的 epoch 上修改，owner 那边收不进来
+    let edit = CrdtOperation::Update(id, Update::Description("hello world".to_string()));
+    node2.update(&id, edit).unwrap();
+    let (_, old) = node2.invites(&id).unwrap().pop().unwrap();
+    assert!(matches!(
+        node1.receive(peer2, old),
+        Err(Error::Truncated(_))
+    ));
+
+    // node2 收到截断后的 doc，把自己的修改接上去，owner 再收到就有了
+    node1
+        .update(
+            &id,
+            CrdtOperation::Update(id, Update::Name("b".to_string())),
+        )
+        .unwrap();
+    let (_, invite) = node1.invites(&id).unwrap().pop().unwrap();
+    node2.receive(peer1, invite).unwrap();
+    assert_eq!(node2.shared.lock().unwrap()[&id].epoch, 1);
+    assert_eq!(node2.path(&id).unwrap().name, "b");
+    assert_eq!(node2.path(&id).unwrap().description, "hello world");
+
+    let (_, invite2) = node2.invites(&id).unwrap().pop().unwrap();
+    node1.receive(peer2, invite2).unwrap();
+    assert_eq!(node1.path(&id).unwrap().description, "hello world");
+    assert_eq!(node1.path(&id).unwrap().name, "b");
+}
+
+#[test]
+fn test_manager_independent_truncations() {
+    use crate::history::parse_heads;
+    use crate::{CrdtOperation, PeerPermission, Update};
+
+    let node1 = test_manager();
+    let node2 = test_manager();
+    let peer1 = libp2p::PeerId::random();
+    let peer2 = libp2p::PeerId::random();
+    let actor = |node: &crate::Manager, id| {
+        node.shared.lock().unwrap()[&id]
+            .crdt
+            .get_actor()
+            .to_hex_string()
+    };
+
+    let id = node1.create_path("a", "test.txt", "").unwrap();
+    let rename = |name: &str| CrdtOperation::Update(id, Update::Name(name.to_string()));
+    node1.share(&id, peer2, PeerPermission::Owner).unwrap();
+    let (_, invite) = node1.invites(&id).unwrap().pop().unwrap();
+    node2.receive(peer1, invite).unwrap();
+    for (i, name) in ["b", "c", "d", "e"].into_iter().enumerate() {
+        let (from, to, peer) = if i % 2 == 0 {
+            (&node2, &node1, peer2)
+        } else {
+            (&node1, &node2, peer1)
+        };
+        from.update(&id, rename(name)).unwrap();
+        let (_, invite) = from.invites(&id).unwrap().pop().unwrap();
+        to.receive(peer, invite).unwrap();
+    }
+    assert_eq!(node1.heads(&id).unwrap(), node2.heads(&id).unwrap());
+
+    // 两个 owner 在不同的位置截断，重放的 change 都用自己的 actor 写
+    let history = node1.history(&id).unwrap();
+    let truncate = |node: &crate::Manager, at: usize| {
+        let base = parse_heads(&history[at].hash).unwrap();
+        let mut shared = node.shared.lock().unwrap();
+        let info = shared.get_mut(&id).unwrap();
+        let conn = node.db.lock().unwrap();
+        info.truncate(&base).unwrap();
+        info.materialize(&conn).unwrap();
+        info.snapshot(&conn).unwrap();
+    };
+    truncate(&node1, 1);
+    truncate(&node2, 3);
+    let truncated = node1.history(&id).unwrap();
+    assert_eq!(truncated.len(), 4);
+    // 原来的作者还在
+    assert_eq!(truncated[2].actor, actor(&node2, id));
+    assert_eq!(truncated[2].message, history[3].message);
+
+    // 两边的历史能合并，内容一样
+    let (_, invite) = node1.invites(&id).unwrap().pop().unwrap();
+    node2.receive(peer1, invite).unwrap();
+    let (_, invite) = node2.invites(&id).unwrap().pop().unwrap();
+    node1.receive(peer2, invite).unwrap();
+    assert_eq!(node1.heads(&id).unwrap(), node2.heads(&id).unwrap());
+    assert_eq!(node1.path(&id).unwrap().name, "e");
+    assert_eq!(node2.path(&id).unwrap().name, "e");
+
+    // 之后的修改照常合并
+    node2.update(&id, rename("f")).unwrap();
+    let (_, invite) = node2.invites(&id).unwrap().pop().unwrap();
+    node1.receive(peer2, invite).unwrap();
+    assert_eq!(node1.path(&id).unwrap().name, "f");
+}
+
+#[test]
+fn test_migrate_from_each_version() {
+    use crate::{migrate, Manager, Path};
+
+    let path = Path {
+        pub_id: Uuid::new_v4(),
+        name: "test".to_string(),
+        path: "test.txt".to_string(),
+        description: "hello".into(),
+        deleted: None,
+    };
+    // 快照之后还有一个增量
+    let mut doc = automerge::AutoCommit::new();
+    reconcile(&mut doc, &path).unwrap();
+    let snapshot = doc.save();
+    let mut edited = path.clone();
+    edited.name = "edited".to_string();
+    reconcile(&mut doc, &edited).unwrap();
+    let chunk = doc.save_incremental();
+    let heads = doc.get_heads();
+
+    // 从每个旧版本升级，最后一个是没有版本表、表已经建好的旧数据库，按版本 0 处理
+    let mut setups: Vec<_> = (0..migrate::latest()).map(|v| (v, false)).collect();
+    setups.push((3, true));
+
+    for (from, unversioned) in setups {
+        let conn = sqlite::open(":memory:").unwrap();
+        migrate::migrate_to(&conn, from).unwrap();
+        if unversioned {
+            conn.execute("DROP TABLE schema_version").unwrap();
+        }
+        let version = migrate::version(&conn).unwrap();
+        let seeded = conn.execute("SELECT 1 FROM paths").is_ok();
+        if seeded {
+            conn.execute(format!(
+                "INSERT INTO paths (pub_id, name, path, description) VALUES ('{}', 'test', 'test.txt', 'hello')",
+                path.pub_id
+            ))
+            .unwrap();
+        }
+        if version == 4 {
+            conn.execute(format!(
+                "INSERT INTO doc_snapshots VALUES ('{}', '\"Path\"', '\"Owner\"', '{{}}', '{}', 0, '', x'{}');
+                 INSERT INTO doc_chunks (pub_id, data) VALUES ('{}', x'{}');",
+                path.pub_id,
+                doc.get_actor().to_hex_string(),
+                hex::encode(&snapshot),
+                path.pub_id,
+                hex::encode(&chunk),
+            ))
+            .unwrap();
+        }
+
+        migrate::migrate(&conn).unwrap();
+        assert_eq!(migrate::version(&conn).unwrap(), migrate::latest());
+        // 再执行一次什么也不做
+        migrate::migrate(&conn).unwrap();
+        let rows = crate::db::query_records::<crate::Path>(&conn).unwrap();
+        assert_eq!(rows.len(), usize::from(seeded));
+
+        if version == 4 {
+            // 旧的增量拆成了 change，读出来的 doc 不变
+            assert_eq!(
+                crate::db::count_changes(&conn, &path.pub_id.to_string()).unwrap(),
+                1
+            );
+            let control = libp2p_stream::Behaviour::new().new_control();
+            let manager = Manager::new(conn, control).unwrap();
+            assert_eq!(manager.heads(&path.pub_id).unwrap(), heads);
+            assert_eq!(manager.path(&path.pub_id).unwrap(), edited);
+        }
+    }
+}
+
+#[test]
+fn test_manager_check_and_reindex() {
+    use crate::{CrdtOperation, Entry, EntryKind};
+
+    let node = test_manager();
+    let id = node.create_path("test", "test.txt", "hello").unwrap();
+    let folder = node.create_folder(&Folder::new("root", "")).unwrap();
+    let file = Uuid::new_v4();
+    let entry = Entry {
+        pub_id: file,
+        path: "a.png".to_string(),
+        kind: EntryKind::File {
+            hash: "hash".to_string(),
+            size: 1,
+            mime_type: "image/png".to_string(),
+        },
+    };
+    node.update(&folder, CrdtOperation::Create(entry)).unwrap();
+    assert!(node.check().unwrap().is_empty());
+
+    // 数据库和 doc 不一致：改了一列，少了一行，多了一行，heads 不对
+    let conn = node.db.lock().unwrap();
+    conn.execute(format!(
+        "UPDATE paths SET name = 'drifted' WHERE pub_id = '{id}';
+         DELETE FROM files WHERE pub_id = '{file}';
+         INSERT INTO folders (pub_id, root_pub_id, name, description, materialized_path)
+         VALUES ('stale', '{folder}', 'stale', '', '/');
+         UPDATE docs SET heads = '' WHERE pub_id = '{folder}';"
+    ))
+    .unwrap();
+    drop(conn);
+
+    let mismatches = node.check().unwrap();
+    let found: Vec<_> = mismatches
+        .iter()
+        .map(|m| (m.table.as_str(), m.pub_id.clone(), m.column.as_deref()))
+        .collect();
+    assert_eq!(
+        found,
+        vec![
+            ("paths", id.to_string(), Some("name")),
+            ("folders", "stale".to_string(), None),
+            ("files", file.to_string(), None),
+            ("docs", folder.to_string(), Some("heads")),
+        ]
+    );
+    assert_eq!(mismatches[0].expected.as_deref(), Some("test"));
+    assert_eq!(mismatches[0].found.as_deref(), Some("drifted"));
+    assert!(mismatches[1].expected.is_none());
+    assert!(mismatches[2].found.is_none());
+
+    assert_eq!(node.reindex().unwrap(), 2);
+    assert!(node.check().unwrap().is_empty());
+    let conn = node.db.lock().unwrap();
+    let (_, row) = crate::db::query_records::<crate::Path>(&conn)
+        .unwrap()
+        .pop()
+        .unwrap();
+    assert_eq!(row.name, "test");
+}
+
+#[test]
+fn test_manager_ingest_sql_edits() {
+    use crate::PeerPermission;
+
+    let node1 = test_manager();
+    let id = node1.create_path("test", "test.txt", "hello").unwrap();
+
+    // 应用直接改 paths 表，变成 doc 的操作
+    let changed = node1
+        .execute(&format!(
+            "UPDATE paths SET name = 'renamed', description = 'hello sql' WHERE pub_id = '{id}'"
+        ))
+        .unwrap();
+    assert_eq!(changed, vec![id]);
+    let path = node1.path(&id).unwrap();
+    assert_eq!(path.name, "renamed");
+    assert_eq!(path.description, "hello sql");
+    // 一条 UPDATE 改了两列只生成一个 change
+    let history = node1.history(&id).unwrap();
+    assert_eq!(history.len(), 2);
+    assert!(node1.check().unwrap().is_empty());
+    // 物化时自己写入的不会再记录
+    assert!(node1.ingest().unwrap().is_empty());
+
+    // 插入的行新建一个 doc
+    let new = Uuid::new_v4();
+    let changed = node1
+        .execute(&format!(
+            "INSERT INTO paths (pub_id, name, path, description) VALUES ('{new}', 'new', 'new.txt', '')"
+        ))
+        .unwrap();
+    assert_eq!(changed, vec![new]);
+    assert_eq!(node1.path(&new).unwrap().name, "new");
+    assert_eq!(
+        crate::db::query_records::<crate::Path>(&node1.db.lock().unwrap())
+            .unwrap()
+            .len(),
+        2
+    );
+
+    // 只读的 doc 改了也会恢复
+    let node2 = test_manager();
+    let peer1 = libp2p::PeerId::random();
+    let peer2 = libp2p::PeerId::random();
+    node1.share(&id, peer2, PeerPermission::ReadOnly).unwrap();
+    let (_, invite) = node1.invites(&id).unwrap().pop().unwrap();
+    node2.receive(peer1, invite).unwrap();
+    let changed = node2
+        .execute(&format!(
+            "UPDATE paths SET name = 'nope' WHERE pub_id = '{id}'"
+        ))
+        .unwrap();
+    assert!(changed.is_empty());
+    let row =
+        crate::db::query_record::<crate::Path>(&node2.db.lock().unwrap(), &id.to_string()).unwrap();
+    assert_eq!(row.unwrap().name, "renamed");
+}
+
+#[test]
+fn test_manager_sync_records() {
+    use autosurgeon::{Hydrate, Reconcile};
+    use serde::Serialize;
+
+    use crate::{db, record, CrdtOperation, Error, PeerPermission};
+
+    #[derive(Debug, Clone, Default, PartialEq, Reconcile, Hydrate, Serialize)]
+    struct Tag {
+        pub_id: Uuid,
+        label: String,
+        count: u32,
+    }
+    crate::sync_record!(Tag, "tags", pub_id, { label => "label", count => "count" });
+    record::register::<Tag>();
+    record::register::<AssetObject>();
+
+    let node1 = test_manager();
+    let node2 = test_manager();
+    let peer1 = libp2p::PeerId::random();
+    let peer2 = libp2p::PeerId::random();
+
+    let asset = AssetObject {
+        pub_id: Uuid::new_v4(),
+        hash: "hash".to_string(),
+        size: 1024,
+        mime_type: "video/mp4".to_string(),
+        file_path: FilePath {
+            name: "clip.mp4".to_string(),
+            description: "holiday".into(),
+            materialized_path: "/".to_string(),
+        },
+        media_data: MediaData {
+            width: 1920,
+            height: 1080,
+            duration: 60,
+            bitrate: 1024,
+            has_audio: true,
+        },
+    };
+    let id = node1.create_record(&asset).unwrap();
+    let tag = Tag {
+        pub_id: Uuid::new_v4(),
+        label: "travel".to_string(),
+        count: 1,
+    };
+    let tag_id = node1.create_record(&tag).unwrap();
+
+    // 嵌套的字段展开成列，读回来还是原来的记录
+    let conn = node1.db.lock().unwrap();
+    let row: AssetObject = db::query_record(&conn, &id.to_string()).unwrap().unwrap();
+    assert_eq!(row, asset);
+    let (_, row) = db::query_records::<Tag>(&conn).unwrap().pop().unwrap();
+    assert_eq!(row, tag);
+    drop(conn);
+
+    node1
+        .update_record(&id, |a: &mut AssetObject| a.media_data.width = 1280)
+        .unwrap();
+    node1
+        .update_record(&tag_id, |t: &mut Tag| t.count += 1)
+        .unwrap();
+    assert!(node1
+        .update_record(&id, |a: &mut AssetObject| a.pub_id = Uuid::new_v4())
+        .is_err());
+    assert_eq!(node1.record_json(&tag_id).unwrap()["count"], json!(2));
+
+    // 同步到其他 peer 之后一样物化
+    node1.share(&id, peer2, PeerPermission::ReadWrite).unwrap();
+    let (_, invite) = node1.invites(&id).unwrap().pop().unwrap();
+    node2.receive(peer1, invite).unwrap();
+    let received: AssetObject = node2.record(&id).unwrap();
+    assert_eq!(received.media_data.width, 1280);
+    let conn = node2.db.lock().unwrap();
+    let row: AssetObject = db::query_record(&conn, &id.to_string()).unwrap().unwrap();
+    assert_eq!(row, received);
+    drop(conn);
+
+    // 检查和重建也包括记录的表
+    assert!(node1.check().unwrap().is_empty());
+    node1
+        .db
+        .lock()
+        .unwrap()
+        .execute("DELETE FROM tags")
+        .unwrap();
+    let mismatches = node1.check().unwrap();
+    assert_eq!(mismatches.len(), 1);
+    assert_eq!(mismatches[0].table, "tags");
+    node1.reindex().unwrap();
+    assert!(node1.check().unwrap().is_empty());
+
+    // 删除记录：行还在，填上删除列，不能再修改
+    node1.update(&id, CrdtOperation::Delete(id)).unwrap();
+    assert!(matches!(
+        node1.update_record(&id, |a: &mut AssetObject| a.size = 1),
+        Err(Error::NotFound(_))
+    ));
+    let (_, invite) = node1.invites(&id).unwrap().pop().unwrap();
+    node2.receive(peer1, invite).unwrap();
+    for node in [&node1, &node2] {
+        let conn = node.db.lock().unwrap();
+        let mut stmt = conn
+            .prepare("SELECT COUNT(*) FROM assets WHERE deleted_at IS NOT NULL")
+            .unwrap();
+        stmt.next().unwrap();
+        assert_eq!(stmt.read::<i64, _>(0).unwrap(), 1);
+    }
+
+    // peer 都收到之后清除，之后再发来也不会重新出现
+    let (_, invite) = node2.invites(&id).unwrap().pop().unwrap();
+    node1.receive(peer2, invite).unwrap();
+    assert_eq!(node1.purge(&id).unwrap(), 1);
+    assert!(matches!(node1.record_json(&id), Err(Error::NotFound(_))));
+    let (_, invite) = node2.invites(&id).unwrap().pop().unwrap();
+    node1.receive(peer2, invite).unwrap();
+    assert!(matches!(node1.record_json(&id), Err(Error::NotFound(_))));
+    let conn = node1.db.lock().unwrap();
+    assert!(db::query_record::<AssetObject>(&conn, &id.to_string())
+        .unwrap()
+        .is_none());
+}
+
+#[test]
+fn test_manager_delete_tombstones() {
+    use crate::{db, CrdtOperation, Entry, EntryKind, Error, PeerPermission, Update};
+
+    let node1 = test_manager();
+    let node2 = test_manager();
+    let peer1 = libp2p::PeerId::random();
+    let peer2 = libp2p::PeerId::random();
+    let count = |node: &crate::Manager, sql: &str| {
+        let conn = node.db.lock().unwrap();
+        let mut stmt = conn.prepare(sql).unwrap();
+        stmt.next().unwrap();
+        stmt.read::<i64, _>(0).unwrap()
+    };
+
+    let folder = Folder::new("shared", "");
+    let id = node1.create_folder(&folder).unwrap();
+    let (x, y, file) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+    for (pub_id, path, kind) in [
+        (x, "x", EntryKind::Folder),
+        (y, "y", EntryKind::Folder),
+        (
+            file,
+            "x/a.txt",
+            EntryKind::File {
+                hash: "hash".to_string(),
+                size: 1,
+                mime_type: "text/plain".to_string(),
+            },
+        ),
+    ] {
+        let entry = Entry {
+            pub_id,
+            path: path.to_string(),
+            kind,
+        };
+        node1.update(&id, CrdtOperation::Create(entry)).unwrap();
+    }
+    node1.share(&id, peer2, PeerPermission::ReadWrite).unwrap();
+    let (_, invite) = node1.invites(&id).unwrap().pop().unwrap();
+    node2.receive(peer1, invite).unwrap();
+
+    // node1 删除 x，node2 同时把 x 移到 y 里、修改里面的文件
+    node1.update(&id, CrdtOperation::Delete(x)).unwrap();
+    let rename = CrdtOperation::Update(file, Update::Name("b.txt".to_string()));
+    node2
+        .update(
+            &id,
+            CrdtOperation::Update(x, Update::Path("y/x".to_string())),
+        )
+        .unwrap();
+    node2.update(&id, rename.clone()).unwrap();
+    // 对方还没有收到删除，不能清除
+    assert_eq!(node1.purge(&id).unwrap(), 0);
+
+    let (_, invite) = node1.invites(&id).unwrap().pop().unwrap();
+    node2.receive(peer1, invite).unwrap();
+    let (_, invite) = node2.invites(&id).unwrap().pop().unwrap();
+    node1.receive(peer2, invite).unwrap();
+
+    // 两边都删除了，移动没有让 x 重新出现
+    for node in [&node1, &node2] {
+        let merged = node.folder(&id).unwrap();
+        assert!(merged.find_folder(&x).is_none());
+        assert!(merged.find_file(&file).is_none());
+        assert!(merged.is_deleted(&x));
+        assert!(matches!(
+            node.update(&id, rename.clone()),
+            Err(Error::Folder(_))
+        ));
+        // 删除了的行还在，查询时跳过
+        assert_eq!(count(node, "SELECT COUNT(*) FROM folders"), 3);
+        assert_eq!(
+            count(
+                node,
+                "SELECT COUNT(*) FROM folders WHERE deleted_at IS NULL"
+            ),
+            2
+        );
+        assert_eq!(
+            count(node, "SELECT COUNT(*) FROM files WHERE deleted_at IS NULL"),
+            0
+        );
+        assert!(node.check().unwrap().is_empty());
+    }
+    assert_eq!(node1.heads(&id).unwrap(), node2.heads(&id).unwrap());
+
+    // 对方都收到之后清除，删除了的行也去掉
+    assert_eq!(node1.purge(&id).unwrap(), 1);
+    assert!(node1.folder(&id).unwrap().tombstones.is_empty());
+    assert_eq!(count(&node1, "SELECT COUNT(*) FROM folders"), 2);
+    assert_eq!(count(&node1, "SELECT COUNT(*) FROM files"), 0);
+
+    // 应用删除 paths 的行，变成 doc 的删除，并发的修改不会恢复
+    let path = node1.create_path("test", "test.txt", "").unwrap();
+    node1
+        .share(&path, peer2, PeerPermission::ReadWrite)
+        .unwrap();
+    let (_, invite) = node1.invites(&path).unwrap().pop().unwrap();
+    node2.receive(peer1, invite).unwrap();
+    let changed = node1
+        .execute(&format!("DELETE FROM paths WHERE pub_id = '{path}'"))
+        .unwrap();
+    assert_eq!(changed, vec![path]);
+    let rename = CrdtOperation::Update(path, Update::Name("renamed".to_string()));
+    node2.update(&path, rename.clone()).unwrap();
+    let (_, invite) = node1.invites(&path).unwrap().pop().unwrap();
+    node2.receive(peer1, invite).unwrap();
+    let (_, invite) = node2.invites(&path).unwrap().pop().unwrap();
+    node1.receive(peer2, invite).unwrap();
+    for node in [&node1, &node2] {
+        assert!(node.path(&path).unwrap().deleted.is_some());
+        assert!(matches!(
+            node.update(&path, rename.clone()),
+            Err(Error::NotFound(_))
+        ));
+        let conn = node.db.lock().unwrap();
+        assert!(db::query_record::<crate::Path>(&conn, &path.to_string())
+            .unwrap()
+            .is_none());
+    }
+    assert_eq!(
+        count(
+            &node1,
+            "SELECT COUNT(*) FROM paths WHERE deleted_by IS NOT NULL"
+        ),
+        1
+    );
+
+    assert_eq!(node1.purge(&path).unwrap(), 1);
+    assert!(matches!(node1.path(&path), Err(Error::NotFound(_))));
+    assert_eq!(count(&node1, "SELECT COUNT(*) FROM paths"), 0);
+    assert!(node1.ingest().unwrap().is_empty());
+    assert!(node1.check().unwrap().is_empty());
+
+    // 清除之后 peer 再发来也不会重新出现
+    let (_, invite) = node2.invites(&path).unwrap().pop().unwrap();
+    node1.receive(peer2, invite).unwrap();
+    assert!(matches!(node1.path(&path), Err(Error::NotFound(_))));
+    assert_eq!(count(&node1, "SELECT COUNT(*) FROM paths"), 0);
+}
+
+#[test]
+fn test_manager_concurrent_moves() {
+    use crate::{tree, CrdtOperation, Entry, EntryKind, PeerPermission, Update};
+
+    let node1 = test_manager();
+    let node2 = test_manager();
+    let peer1 = libp2p::PeerId::random();
+    let peer2 = libp2p::PeerId::random();
+    let actor = |node: &crate::Manager, id| {
+        node.shared.lock().unwrap()[&id]
+            .crdt
+            .get_actor()
+            .to_hex_string()
+    };
+    let exchange = |id| {
+        let (_, invite) = node1.invites(&id).unwrap().pop().unwrap();
+        node2.receive(peer1, invite).unwrap();
+        let (_, invite) = node2.invites(&id).unwrap().pop().unwrap();
+        node1.receive(peer2, invite).unwrap();
+        let folder = node1.folder(&id).unwrap();
+        assert_eq!(node2.folder(&id).unwrap(), folder);
+        folder
+    };
+    let mv = |pub_id, path: &str| CrdtOperation::Update(pub_id, Update::Path(path.to_string()));
+
+    let id = node1.create_folder(&Folder::new("shared", "")).unwrap();
+    let (a, b, c, file) = (
+        Uuid::new_v4(),
+        Uuid::new_v4(),
+        Uuid::new_v4(),
+        Uuid::new_v4(),
+    );
+    for (pub_id, path) in [(a, "a"), (b, "b"), (c, "c")] {
+        let entry = Entry {
+            pub_id,
+            path: path.to_string(),
+            kind: EntryKind::Folder,
+        };
+        node1.update(&id, CrdtOperation::Create(entry)).unwrap();
+    }
+    let entry = Entry {
+        pub_id: file,
+        path: "f.txt".to_string(),
+        kind: EntryKind::File {
+            hash: "hash".to_string(),
+            size: 1,
+            mime_type: "text/plain".to_string(),
+        },
+    };
+    node1.update(&id, CrdtOperation::Create(entry)).unwrap();
+    let op = CrdtOperation::Update(a, Update::Description("album".to_string()));
+    node1.update(&id, op).unwrap();
+    node1.share(&id, peer2, PeerPermission::ReadWrite).unwrap();
+    exchange(id);
+
+    // 同时把 a 移到 b 里、b 移到 a 里，按 (clock, actor) 排在后面的移动不生效
+    node1.update(&id, mv(a, "b/a")).unwrap();
+    node2.update(&id, mv(b, "a/b")).unwrap();
+    let merged = exchange(id);
+    let entries: Vec<String> = merged.entries().into_iter().map(|(p, _, _)| p).collect();
+    if actor(&node1, id) < actor(&node2, id) {
+        assert!(entries.contains(&"b/a".to_string()));
+        assert!(merged.folders.iter().any(|f| f.pub_id == b));
+    } else {
+        assert!(entries.contains(&"a/b".to_string()));
+        assert!(merged.folders.iter().any(|f| f.pub_id == a));
+    }
+    assert_eq!(entries.len(), 4);
+    // 移动不会重新写入描述
+    assert_eq!(merged.find_folder(&a).unwrap().description, "album");
+
+    // 同一个条目同时移到两个地方，两边选的一样
+    node1.update(&id, mv(file, "c/f.txt")).unwrap();
+    node2.update(&id, mv(file, "f2.txt")).unwrap();
+    let merged = exchange(id);
+    assert!(merged.find_file(&file).is_some());
+    assert_eq!(merged.entries().len(), 4);
+
+    // 移到同时删除了的文件夹里的移动不生效，条目留在原来的位置
+    node1.update(&id, mv(file, "f.txt")).unwrap();
+    exchange(id);
+    node1.update(&id, CrdtOperation::Delete(c)).unwrap();
+    node2.update(&id, mv(file, "c/g.txt")).unwrap();
+    let merged = exchange(id);
+    assert!(merged.is_deleted(&c));
+    assert_eq!(merged.files.len(), 1);
+    assert_eq!(merged.files[0].file_path.name, "f.txt");
+    assert!(node1.check().unwrap().is_empty());
+
+    // 清除时只留下生效的移动，树不变
+    assert!(node1.purge(&id).unwrap() > 0);
+    let stored: tree::FolderDoc = hydrate(&node1.shared.lock().unwrap()[&id].crdt).unwrap();
+    assert_eq!(stored.moves.len(), 3);
+    assert_eq!(
+        stored.tree().entries(),
+        node1.folder(&id).unwrap().entries()
+    );
+
+    // 以前嵌套存储的文件夹改成平铺的，内容不变
+    let mut nested = node1.folder(&id).unwrap();
+    nested.fresh_text();
+    let mut doc = automerge::AutoCommit::new();
+    reconcile(&mut doc, &nested).unwrap();
+    assert!(tree::upgrade(&mut doc).unwrap());
+    assert!(!tree::upgrade(&mut doc).unwrap());
+    assert_eq!(tree::hydrate_folder(&doc).unwrap(), nested);
+}
+
+#[test]
+fn test_manager_create_in_deleted_folder() {
+    use crate::{CrdtOperation, Entry, EntryKind, PeerPermission};
+
+    let node1 = test_manager();
+    let node2 = test_manager();
+    let peer1 = libp2p::PeerId::random();
+    let peer2 = libp2p::PeerId::random();
+    let exchange = |id| {
+        let (_, invite) = node1.invites(&id).unwrap().pop().unwrap();
+        node2.receive(peer1, invite).unwrap();
+        let (_, invite) = node2.invites(&id).unwrap().pop().unwrap();
+        node1.receive(peer2, invite).unwrap();
+        let folder = node1.folder(&id).unwrap();
+        assert_eq!(node2.folder(&id).unwrap(), folder);
+        folder
+    };
+    let create = |node: &crate::Manager, id, path: &str, kind| {
+        let entry = Entry {
+            pub_id: Uuid::new_v4(),
+            path: path.to_string(),
+            kind,
+        };
+        let pub_id = entry.pub_id;
+        node.update(&id, CrdtOperation::Create(entry)).unwrap();
+        pub_id
+    };
+    let file = || EntryKind::File {
+        hash: "hash".to_string(),
+        size: 1,
+        mime_type: "image/png".to_string(),
+    };
+    let path = |folder: &Folder, pub_id| {
+        folder
+            .entries()
+            .into_iter()
+            .find(|(_, id, _)| *id == pub_id)
+            .map(|(path, _, _)| path)
+    };
+
+    let id = node1.create_folder(&Folder::new("shared", "")).unwrap();
+    let photos = create(&node1, id, "photos", EntryKind::Folder);
+    let trip = create(&node1, id, "photos/trip", EntryKind::Folder);
+    node1.share(&id, peer2, PeerPermission::ReadWrite).unwrap();
+    exchange(id);
+
+    // 删除 trip 的同时在 trip 里新建，新建的条目放到 trip 所在的 photos 里
+    node1.update(&id, CrdtOperation::Delete(trip)).unwrap();
+    let beach = create(&node2, id, "photos/trip/beach.png", file());
+    let day1 = create(&node2, id, "photos/trip/day1", EntryKind::Folder);
+    let sea = create(&node2, id, "photos/trip/day1/sea.png", file());
+    let merged = exchange(id);
+    assert!(merged.is_deleted(&trip));
+    assert_eq!(path(&merged, beach).as_deref(), Some("photos/beach.png"));
+    assert_eq!(path(&merged, sea).as_deref(), Some("photos/day1/sea.png"));
+
+    // 被删的文件夹所在的文件夹也删了，继续往上找，最后放在根目录
+    node1.update(&id, CrdtOperation::Delete(photos)).unwrap();
+    let moon = create(&node2, id, "photos/day1/moon.png", file());
+    let merged = exchange(id);
+    assert!(merged.is_deleted(&photos));
+    assert_eq!(path(&merged, moon).as_deref(), Some("moon.png"));
+    assert_eq!(path(&merged, day1), None);
+    assert!(node1.check().unwrap().is_empty());
+
+    // 清除删除记录之后位置不变
+    assert!(node1.purge(&id).unwrap() > 0);
+    assert_eq!(node1.folder(&id).unwrap().entries(), merged.entries());
+}
+
+#[test]
+fn test_manager_concurrent_deletes() {
+    use crate::{CrdtOperation, Entry, EntryKind, PeerPermission};
+
+    let node1 = test_manager();
+    let node2 = test_manager();
+    let peer1 = libp2p::PeerId::random();
+    let peer2 = libp2p::PeerId::random();
+    let exchange = |id| {
+        let (_, invite) = node1.invites(&id).unwrap().pop().unwrap();
+        node2.receive(peer1, invite).unwrap();
+        let (_, invite) = node2.invites(&id).unwrap().pop().unwrap();
+        node1.receive(peer2, invite).unwrap();
+        let folder = node1.folder(&id).unwrap();
+        assert_eq!(node2.folder(&id).unwrap(), folder);
+        folder
+    };
+    let create = |id, path: &str, kind| {
+        let entry = Entry {
+            pub_id: Uuid::new_v4(),
+            path: path.to_string(),
+            kind,
+        };
+        let pub_id = entry.pub_id;
+        node1.update(&id, CrdtOperation::Create(entry)).unwrap();
+        pub_id
+    };
+    let file = || EntryKind::File {
+        hash: "hash".to_string(),
+        size: 1,
+        mime_type: "image/png".to_string(),
+    };
+
+    let id = node1.create_folder(&Folder::new("shared", "")).unwrap();
+    let a = create(id, "a.png", file());
+    let photos = create(id, "photos", EntryKind::Folder);
+    let b = create(id, "photos/b.png", file());
+    node1.share(&id, peer2, PeerPermission::ReadWrite).unwrap();
+    exchange(id);
+
+    // 两边同时删除同一个文件，只留一条删除记录
+    node1.update(&id, CrdtOperation::Delete(a)).unwrap();
+    node2.update(&id, CrdtOperation::Delete(a)).unwrap();
+    let merged = exchange(id);
+    assert_eq!(merged.tombstones.len(), 1);
+    assert!(merged.is_deleted(&a));
+    assert!(node1.check().unwrap().is_empty());
+    assert!(node2.check().unwrap().is_empty());
+
+    // 一边删除文件夹，另一边删除里面的文件，文件只记在文件夹的删除记录里
+    node1.update(&id, CrdtOperation::Delete(photos)).unwrap();
+    node2.update(&id, CrdtOperation::Delete(b)).unwrap();
+    let merged = exchange(id);
+    assert_eq!(merged.tombstones.len(), 2);
+    assert!(merged.tombstones.iter().all(|t| t.pub_id != b));
+    assert!(merged.is_deleted(&photos));
+    assert!(merged.is_deleted(&b));
+    assert!(merged.entries().is_empty());
+    assert!(node1.check().unwrap().is_empty());
+    assert!(node2.check().unwrap().is_empty());
+
+    // 之后的修改照样能写入数据库
+    create(id, "c.png", file());
+    assert_eq!(exchange(id).entries().len(), 1);
+    assert!(node2.check().unwrap().is_empty());
+}
+
+#[test]
+fn test_manager_name_collisions() {
+    use crate::{CrdtOperation, Entry, EntryKind, PeerPermission, Update};
+
+    let node1 = test_manager();
+    let node2 = test_manager();
+    let peer1 = libp2p::PeerId::random();
+    let peer2 = libp2p::PeerId::random();
+    let exchange = |id| {
+        let (_, invite) = node1.invites(&id).unwrap().pop().unwrap();
+        node2.receive(peer1, invite).unwrap();
+        let (_, invite) = node2.invites(&id).unwrap().pop().unwrap();
+        node1.receive(peer2, invite).unwrap();
+        let folder = node1.folder(&id).unwrap();
+        assert_eq!(node2.folder(&id).unwrap(), folder);
+        assert_eq!(
+            node2.collisions(&id).unwrap(),
+            node1.collisions(&id).unwrap()
+        );
+        folder
+    };
+    let create = |node: &crate::Manager, id, path: &str| {
+        let entry = Entry {
+            pub_id: Uuid::new_v4(),
+            path: path.to_string(),
+            kind: EntryKind::File {
+                hash: path.to_string(),
+                size: 1,
+                mime_type: "image/png".to_string(),
+            },
+        };
+        let pub_id = entry.pub_id;
+        node.update(&id, CrdtOperation::Create(entry)).unwrap();
+        pub_id
+    };
+
+    let id = node1.create_folder(&Folder::new("shared", "")).unwrap();
+    let b = create(&node1, id, "b.png");
+    let c = create(&node1, id, "c.png");
+    node1.share(&id, peer2, PeerPermission::ReadWrite).unwrap();
+    exchange(id);
+
+    // 同时新建同名的文件，同时改成同一个名字
+    let a1 = create(&node1, id, "a.png");
+    let a2 = create(&node2, id, "a.png");
+    let rename = |pub_id| CrdtOperation::Update(pub_id, Update::Name("d".to_string()));
+    node1.update(&id, rename(b)).unwrap();
+    node2.update(&id, rename(c)).unwrap();
+    let merged = exchange(id);
+
+    // 条目都还在，读出来的名字不重复，actor 排在后面的加上 actor 的前 8 位
+    let actor = |node: &crate::Manager| {
+        node.shared.lock().unwrap()[&id]
+            .crdt
+            .get_actor()
+            .to_hex_string()
+    };
+    let (actor1, actor2) = (actor(&node1), actor(&node2));
+    let (later, tag) = if actor1 < actor2 {
+        ((a2, c), &actor2[..8])
+    } else {
+        ((a1, b), &actor1[..8])
+    };
+    assert_eq!(merged.files.len(), 4);
+    let name = |pub_id| merged.find_file(&pub_id).unwrap().file_path.name.clone();
+    assert_eq!(name(later.0), format!("a ({tag}).png"));
+    assert_eq!(name(later.1), format!("d ({tag})"));
+    let collisions = node1.collisions(&id).unwrap();
+    assert_eq!(collisions.len(), 2);
+    assert_eq!(collisions[0].pub_id, later.0);
+    assert_eq!(collisions[0].name, "a.png");
+    assert_eq!(collisions[1].resolved, format!("d ({tag})"));
+
+    let conn = node1.db.lock().unwrap();
+    let mut stmt = conn
+        .prepare("SELECT COUNT(DISTINCT name) FROM files WHERE deleted_at IS NULL")
+        .unwrap();
+    stmt.next().unwrap();
+    assert_eq!(stmt.read::<i64, _>(0).unwrap(), 4);
+    drop(stmt);
+    drop(conn);
+
+    // 之后不相关的修改不会把加了后缀的名字写回 doc
+    let op = CrdtOperation::Update(b, Update::Description("edited".to_string()));
+    node1.update(&id, op).unwrap();
+    let merged = exchange(id);
+    let stored: crate::tree::FolderDoc = hydrate(&node1.shared.lock().unwrap()[&id].crdt).unwrap();
+    let stored_name = |pub_id: Uuid| {
+        let node = &stored.nodes[&pub_id.to_string()];
+        node.file.as_ref().unwrap().file_path.name.clone()
+    };
+    assert_eq!(stored_name(later.0), "a.png");
+    assert_eq!(stored_name(later.1), "d");
+    assert_eq!(
+        merged.find_file(&later.0).unwrap().file_path.name,
+        format!("a ({tag}).png")
+    );
+
+    // 重名的另一个删除之后恢复原来的名字
+    let earlier = if later.0 == a1 { a2 } else { a1 };
+    node1.update(&id, CrdtOperation::Delete(earlier)).unwrap();
+    let merged = exchange(id);
+    assert_eq!(merged.find_file(&later.0).unwrap().file_path.name, "a.png");
+    assert_eq!(node1.collisions(&id).unwrap().len(), 1);
+    assert!(node1.check().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_manager_bounded_merges() {
+    use crate::{incoming::IncomingLimits, PeerPermission};
+    use std::{future::ready, sync::Arc};
+
+    let limits = IncomingLimits {
+        concurrency: 1,
+        per_peer: 2,
+        capacity: 3,
+    };
+    let node1 = test_manager();
+    let node2 = Arc::new(test_manager().with_incoming(limits));
+    let peer1 = libp2p::PeerId::random();
+    let peer2 = libp2p::PeerId::random();
+
+    let a = node1.create_path("a", "a.txt", "").unwrap();
+    let b = node1.create_path("b", "b.txt", "").unwrap();
+    let mut data = Vec::new();
+    for id in [a, b] {
+        node1.share(&id, peer2, PeerPermission::ReadWrite).unwrap();
+        let (_, invite) = node1.invites(&id).unwrap().pop().unwrap();
+        data.push(serde_json::to_vec(&invite).unwrap());
+    }
+
+    // 每个 peer 最多排两个，一共最多排三个
+    assert!(node2.incoming.admit(&peer1));
+    assert!(node2.incoming.admit(&peer1));
+    assert!(!node2.incoming.admit(&peer1));
+    assert!(node2.incoming.admit(&peer2));
+    assert!(!node2.incoming.admit(&peer2));
+    let stats = node2.incoming_stats();
+    assert_eq!((stats.queued, stats.accepted, stats.refused), (3, 3, 2));
+    assert_eq!(stats.peers.len(), 2);
+
+    // 一次只合并一个，两个都能合并
+    let (r1, r2) = tokio::join!(
+        node2.clone().merge(peer1, ready(Ok(data.pop().unwrap()))),
+        node2.clone().merge(peer1, ready(Ok(data.pop().unwrap()))),
+    );
+    r1.unwrap();
+    r2.unwrap();
+    assert_eq!(node2.path(&a).unwrap().name, "a");
+    assert_eq!(node2.path(&b).unwrap().name, "b");
+
+    // 解析不了的数据算失败，排队的位置也会释放
+    assert!(node2
+        .clone()
+        .merge(peer2, ready(Ok(b"oops".to_vec())))
+        .await
+        .is_err());
+    let stats = node2.incoming_stats();
+    assert_eq!((stats.queued, stats.max_queued, stats.active), (0, 3, 0));
+    assert_eq!((stats.merged, stats.failed), (2, 1));
+    assert!(stats.peers.is_empty());
+    assert_eq!(node2.timestamp_lock.available_permits(), 1);
+
+    // 没有 admit 过直接合并、读取失败，统计不会出错
+    assert!(node2
+        .clone()
+        .merge(peer2, ready(Ok(b"oops".to_vec())))
+        .await
+        .is_err());
+    let reset = ready(Err(std::io::Error::other("reset")));
+    assert!(node2.clone().merge(peer2, reset).await.is_err());
+    let stats = node2.incoming_stats();
+    assert_eq!((stats.queued, stats.active, stats.failed), (0, 0, 3));
+    assert_eq!(node2.timestamp_lock.available_permits(), 1);
+    assert!(node2.incoming.admit(&peer1));
+}
+
+#[tokio::test]
+async fn test_manager_batched_edits() {
+    use crate::{batch::BatchPolicy, CrdtOperation, Error, Update};
+    use std::time::Duration;
+
+    let manager = test_manager().with_batch(BatchPolicy {
+        window: Duration::from_secs(60),
+        max_ops: 4,
+    });
+    let a = manager.create_path("a", "a.txt", "").unwrap();
+    let b = manager.create_path("b", "b.txt", "").unwrap();
+    let heads = manager.heads(&a).unwrap();
+    assert!(manager.batch.due().is_none());
+
+    // 攒起来的修改还没有提交
+    let splice = |index, insert: &str| {
+        let update = Update::SpliceDescription {
+            index,
+            delete: 0,
+            insert: insert.to_string(),
+        };
+        CrdtOperation::Update(a, update)
+    };
+    assert!(!manager.queue(&a, splice(0, "he")).unwrap());
+    assert!(!manager.queue(&a, splice(2, "llo")).unwrap());
+    // 超出范围的修改提交时丢掉，其他的照样提交
+    assert!(!manager.queue(&a, splice(99, "!")).unwrap());
+    let rename = CrdtOperation::Update(b, Update::Name("b2".to_string()));
+    assert!(manager.queue(&b, rename).unwrap());
+    assert!(manager.batch.due().is_some());
+    assert_eq!(manager.heads(&a).unwrap(), heads);
+    assert_eq!(manager.path(&a).unwrap().description, "");
+
+    let missing = Uuid::new_v4();
+    assert!(matches!(
+        manager.queue(&missing, CrdtOperation::Delete(missing)),
+        Err(Error::NotFound(_))
+    ));
+
+    // 每个 doc 一个 change
+    let flushed = manager.flush().await;
+    assert_eq!(flushed.len(), 2);
+    let fa = flushed.iter().find(|f| f.id == a).unwrap();
+    assert_eq!((fa.applied, fa.failed.len(), fa.sent), (2, 1, 0));
+    assert_eq!(manager.path(&a).unwrap().description, "hello");
+    assert_eq!(manager.path(&b).unwrap().name, "b2");
+    let history = manager.history(&a).unwrap();
+    assert_eq!(history.len(), 2);
+    assert!(manager.batch.is_empty());
+    assert!(manager.batch.due().is_none());
+    assert!(manager.flush().await.is_empty());
+
+    // 数据库里也是提交之后的内容
+    let conn = manager.db.lock().unwrap();
+    let path = crate::db::query_record::<crate::Path>(&conn, &a.to_string())
+        .unwrap()
+        .unwrap();
+    assert_eq!(path.description, "hello");
+}
+
+#[tokio::test]
+async fn test_manager_events() {
+    use crate::{DocKind, PeerPermission, SyncMessage, Update};
+    use std::sync::Arc;
+
+    let node1 = test_manager();
+    let node2 = Arc::new(test_manager());
+    let peer1 = libp2p::PeerId::random();
+    let peer2 = libp2p::PeerId::random();
+    let mut events1 = node1.subscribe();
+    let mut events2 = node2.subscribe();
+
+    let id = node1.create_path("a", "a.txt", "").unwrap();
+    assert_eq!(
+        events1.try_recv().unwrap(),
+        SyncMessage::Created {
+            id,
+            kind: DocKind::Path
+        }
+    );
+
+    // 权限没变不算
+    node1.share(&id, peer2, PeerPermission::ReadWrite).unwrap();
+    node1.share(&id, peer2, PeerPermission::ReadWrite).unwrap();
+    assert_eq!(
+        events1.try_recv().unwrap(),
+        SyncMessage::PermissionChanged {
+            id,
+            peer: peer2,
+            permission: PeerPermission::ReadWrite
+        }
+    );
+    assert!(events1.try_recv().is_err());
+
+    // 第一次收到的 doc
+    let (_, invite) = node1.invites(&id).unwrap().pop().unwrap();
+    node2.receive(peer1, invite).unwrap();
+    let heads = node1.heads(&id).unwrap();
+    assert_eq!(
+        events2.try_recv().unwrap(),
+        SyncMessage::Created {
+            id,
+            kind: DocKind::Path
+        }
+    );
+    assert_eq!(
+        events2.try_recv().unwrap(),
+        SyncMessage::Ingested {
+            id,
+            peer: peer1,
+            heads: heads.clone()
+        }
+    );
+
+    // 没有新修改不算
+    let (_, invite) = node1.invites(&id).unwrap().pop().unwrap();
+    node2.receive(peer1, invite).unwrap();
+    assert!(events2.try_recv().is_err());
+
+    let op = crate::CrdtOperation::Update(id, Update::Name("b".to_string()));
+    node1.update(&id, op).unwrap();
+    let (_, invite) = node1.invites(&id).unwrap().pop().unwrap();
+    node2.receive(peer1, invite).unwrap();
+    assert_eq!(
+        events2.try_recv().unwrap(),
+        SyncMessage::Ingested {
+            id,
+            peer: peer1,
+            heads: node1.heads(&id).unwrap()
+        }
+    );
+
+    // 对方改了分享给本地的权限，存下来，之后本地不能再修改
+    node1.share(&id, peer2, PeerPermission::ReadOnly).unwrap();
+    events1.try_recv().unwrap();
+    let (_, invite) = node1.invites(&id).unwrap().pop().unwrap();
+    node2.receive(peer1, invite).unwrap();
+    assert_eq!(
+        events2.try_recv().unwrap(),
+        SyncMessage::PermissionChanged {
+            id,
+            peer: peer1,
+            permission: PeerPermission::ReadOnly
+        }
+    );
+    assert!(events2.try_recv().is_err());
+    assert_eq!(node2.list()[0].permission, PeerPermission::ReadOnly);
+    let op = crate::CrdtOperation::Update(id, Update::Name("c".to_string()));
+    assert!(matches!(
+        node2.update(&id, op),
+        Err(crate::Error::PermissionDenied(_))
+    ));
+    let stored = crate::db::load_docs(&node2.db.lock().unwrap()).unwrap();
+    assert_eq!(stored[0].permission, "\"ReadOnly\"");
+
+    // 没有写权限的 peer 发来的修改合并失败
+    let stranger = libp2p::PeerId::random();
+    let (_, invite) = node1.invites(&id).unwrap().pop().unwrap();
+    let data = serde_json::to_vec(&invite).unwrap();
+    assert!(node2.incoming.admit(&stranger));
+    assert!(node2
+        .clone()
+        .merge(stranger, std::future::ready(Ok(data)))
+        .await
+        .is_err());
+    assert!(matches!(
+        events2.try_recv().unwrap(),
+        SyncMessage::SyncFailed { id: failed, peer, .. } if failed == id && peer == stranger
+    ));
+
+    node1.add_known_peer(peer2);
+    node1.add_known_peer(peer2);
+    node1.remove_known_peer(&peer2);
+    node1.remove_known_peer(&peer2);
+    assert_eq!(events1.try_recv().unwrap(), SyncMessage::PeerJoined(peer2));
+    assert_eq!(events1.try_recv().unwrap(), SyncMessage::PeerLeft(peer2));
+    assert!(events1.try_recv().is_err());
+}
+
+#[test]
+fn test_manager_sync_status() {
+    use crate::{
+        status::{PeerStatus, SyncState},
+        CrdtOperation, PeerPermission, Update,
+    };
+
+    let node1 = test_manager();
+    let node2 = test_manager();
+    let peer1 = libp2p::PeerId::random();
+    let peer2 = libp2p::PeerId::random();
+
+    // 还没有发过，对方什么都没有
+    let id = node1.create_path("a", "a.txt", "").unwrap();
+    node1.share(&id, peer2, PeerPermission::ReadWrite).unwrap();
+    let status = node1.status(&id).unwrap();
+    assert_eq!(
+        status,
+        vec![PeerStatus {
+            peer: peer2.to_string(),
+            permission: PeerPermission::ReadWrite,
+            state: SyncState::Behind { changes: 1 },
+            last_synced: None,
+        }]
+    );
+
+    // 收到的 doc 和对方一致
+    let (_, invite) = node1.invites(&id).unwrap().pop().unwrap();
+    node2.receive(peer1, invite).unwrap();
+    let status = node2.status(&id).unwrap();
+    assert_eq!(status[0].state, SyncState::InSync);
+    assert!(status[0].last_synced.is_some());
+
+    // 对方发回来之后知道对方有了
+    let (_, invite) = node2.invites(&id).unwrap().pop().unwrap();
+    node1.receive(peer2, invite).unwrap();
+    assert_eq!(node1.status(&id).unwrap()[0].state, SyncState::InSync);
+
+    for name in ["b", "c"] {
+        let op = CrdtOperation::Update(id, Update::Name(name.to_string()));
+        node1.update(&id, op).unwrap();
+    }
+    let state = |node: &crate::Manager| node.status(&id).unwrap()[0].state.clone();
+    assert_eq!(state(&node1), SyncState::Behind { changes: 2 });
+
+    // 发送中、失败、成功
+    let with_sync = |f: &dyn Fn(&mut crate::status::PeerSync)| {
+        let mut shared = node1.shared.lock().unwrap();
+        f(shared
+            .get_mut(&id)
+            .unwrap()
+            .peer_sync
+            .get_mut(&peer2)
+            .unwrap());
+    };
+    with_sync(&|sync| sync.sending());
+    assert_eq!(state(&node1), SyncState::Syncing);
+    with_sync(&|sync| sync.sent(Err("closed".to_string()), 1));
+    assert_eq!(
+        state(&node1),
+        SyncState::Failed {
+            error: "closed".to_string()
+        }
+    );
+    // 发出去了对方不一定收下，对方发回来之前还是落后
+    with_sync(&|sync| sync.sent(Ok(()), 2));
+    assert_eq!(state(&node1), SyncState::Behind { changes: 2 });
+    assert_eq!(node1.status(&id).unwrap()[0].last_synced, Some(2));
+
+    // 对方不收，比如权限不够，还是落后
+    let (_, invite) = node1.invites(&id).unwrap().pop().unwrap();
+    node2.share(&id, peer1, PeerPermission::ReadOnly).unwrap();
+    assert!(node2.receive(peer1, invite).is_err());
+    let (_, invite) = node2.invites(&id).unwrap().pop().unwrap();
+    node1.receive(peer2, invite).unwrap();
+    assert_eq!(state(&node1), SyncState::Behind { changes: 2 });
+
+    // 对方收下之后发回来就同步了
+    node2.share(&id, peer1, PeerPermission::ReadWrite).unwrap();
+    let (_, invite) = node1.invites(&id).unwrap().pop().unwrap();
+    node2.receive(peer1, invite).unwrap();
+    let (_, invite) = node2.invites(&id).unwrap().pop().unwrap();
+    node1.receive(peer2, invite).unwrap();
+    assert_eq!(state(&node1), SyncState::InSync);
+
+    // 所有的 doc，没有分享的 doc 没有 peer
+    node1.create_path("b", "b.txt", "").unwrap();
+    let statuses = node1.statuses();
+    assert_eq!(statuses.len(), 2);
+    let doc = statuses.iter().find(|d| d.id == id).unwrap();
+    assert_eq!(doc.name, "c");
+    assert_eq!(doc.peers.len(), 1);
+    assert!(statuses.iter().any(|d| d.peers.is_empty()));
+
+    let json = serde_json::to_value(&doc.peers[0]).unwrap();
+    assert_eq!(json["state"], "in_sync");
+    assert!(json["last_synced"].as_i64().unwrap() > 2);
+}
+
+#[test]
+fn test_manager_revert_text_edits() {
+    use crate::{CrdtOperation, Update};
+
+    let manager = test_manager();
+    let id = manager.create_path("a", "a.txt", "hello").unwrap();
+    let splice = |index, delete, insert: &str| {
+        let update = Update::SpliceDescription {
+            index,
+            delete,
+            insert: insert.to_string(),
+        };
+        manager
+            .update(&id, CrdtOperation::Update(id, update))
+            .unwrap()
+    };
+    let world = splice(5, 0, " world");
+    splice(0, 0, "Oh, ");
+    assert_eq!(manager.path(&id).unwrap().description, "Oh, hello world");
+
+    // 之后在前面插入的文字不影响撤销的位置
+    manager.revert(&id, &world).unwrap();
+    assert_eq!(manager.path(&id).unwrap().description, "Oh, hello");
+
+    // 撤销删除，删掉的文字回到原来的位置
+    let deleted = splice(4, 5, "");
+    splice(0, 0, "Well. ");
+    assert_eq!(manager.path(&id).unwrap().description, "Well. Oh, ");
+    manager.revert(&id, &deleted).unwrap();
+    assert_eq!(manager.path(&id).unwrap().description, "Well. Oh, hello");
+
+    // 一次撤销最近的三个 change：撤销、插入和删除，只多了一个 change
+    let before = manager.history(&id).unwrap().len();
+    manager.undo(&id, 3).unwrap();
+    assert_eq!(manager.path(&id).unwrap().description, "Oh, hello");
+    let history = manager.history(&id).unwrap();
+    assert_eq!(history.len(), before + 1);
+    assert!(history.iter().all(|entry| entry.time > 0));
+}
+
+#[test]
+fn test_manager_truncate_keeps_recent_changes() {
+    use crate::{
+        manager::CompactionPolicy, CrdtOperation, DocKind, Invite, Path, PeerPermission, Update,
+    };
+    use automerge::{transaction::CommitOptions, transaction::Transactable, AutoCommit, ReadDoc};
+
+    let policy = CompactionPolicy {
+        history_horizon: Some(Duration::from_secs(3600)),
+        ..Default::default()
+    };
+    let node = test_manager().with_policy(policy);
+    let peer = libp2p::PeerId::random();
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as i64;
+    let commit = |doc: &mut AutoCommit, message: &str, time: i64| {
+        doc.commit_with(
+            CommitOptions::default()
+                .with_message(message)
+                .with_time(time),
+        );
+    };
+
+    // 两个早于 horizon 的 change，两个最近的 change
+    let id = Uuid::new_v4();
+    let mut doc = AutoCommit::new();
+    let path = Path {
+        pub_id: id,
+        name: "a".to_string(),
+        path: "a.txt".to_string(),
+        description: "hello".into(),
+        deleted: None,
+    };
+    reconcile(&mut doc, &path).unwrap();
+    commit(&mut doc, "create", now - 3 * 3600 * 1000);
+    doc.put(automerge::ROOT, "name", "b").unwrap();
+    commit(&mut doc, "old rename", now - 2 * 3600 * 1000);
+    let (_, text) = doc.get(automerge::ROOT, "description").unwrap().unwrap();
+    doc.splice_text(&text, 5, 0, " world").unwrap();
+    commit(&mut doc, "recent edit", now - 60 * 1000);
+    doc.put(automerge::ROOT, "path", "b.txt").unwrap();
+    commit(&mut doc, "recent move", now - 30 * 1000);
+
+    let invite = Invite {
+        id,
+        kind: DocKind::Path,
+        data: doc.save(),
+        permission: PeerPermission::Owner,
+        epoch: 0,
+        base: vec![],
+        rebased: vec![],
+    };
+    node.receive(peer, invite).unwrap();
+
+    // 早于 horizon 的合成一个快照，最近的 change 保留
+    assert_eq!(node.shared.lock().unwrap()[&id].epoch, 1);
+    let history = node.history(&id).unwrap();
+    let messages: Vec<_> = history
+        .iter()
+        .map(|e| e.message.clone().unwrap_or_default())
+        .collect();
+    assert_eq!(messages, ["truncate history", "recent edit", "recent move"]);
+    assert_eq!(history[0].time, now - 2 * 3600 * 1000);
+    assert_eq!(history[1].time, now - 60 * 1000);
+    assert_eq!(history[1].actor, doc.get_actor().to_hex_string());
+    assert_eq!(history[1].fields, ["description"]);
+    let path = node.path(&id).unwrap();
+    assert_eq!(
+        (
+            path.name.as_str(),
+            path.path.as_str(),
+            path.description.as_str()
+        ),
+        ("b", "b.txt", "hello world")
+    );
+
+    // 之后的本地修改照常
+    let op = CrdtOperation::Update(id, Update::Name("c".to_string()));
+    node.update(&id, op).unwrap();
+    let history = node.history(&id).unwrap();
+    assert_eq!(history.len(), 4);
+    assert_ne!(history[3].actor, doc.get_actor().to_hex_string());
+    assert_eq!(node.path(&id).unwrap().name, "c");
 }
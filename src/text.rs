@@ -0,0 +1,188 @@
+use std::fmt;
+
+use automerge::ChangeHash;
+use autosurgeon::{
+    reconcile::{NoKey, StaleHeads, TextReconciler},
+    Hydrate, HydrateError, ReadDoc, Reconcile, Reconciler,
+};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+// 协同编辑的文本，存成 automerge 的 Text，并发的修改按字符合并。
+// 位置和长度都按字符算，和 automerge 一致
+#[derive(Clone, Default)]
+pub struct Text {
+    value: String,
+    edits: Vec<Splice>,
+    // 从 doc 读出来时的 heads，新建的文本是 None
+    heads: Option<Vec<ChangeHash>>,
+}
+
+#[derive(Debug, Clone)]
+struct Splice {
+    index: usize,
+    delete: usize,
+    insert: String,
+}
+
+impl Text {
+    pub fn new(value: &str) -> Self {
+        Text {
+            value: value.to_string(),
+            edits: vec![],
+            heads: None,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.value
+    }
+
+    pub fn len(&self) -> usize {
+        self.value.chars().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.value.is_empty()
+    }
+
+    fn byte_offset(&self, index: usize) -> usize {
+        self.value
+            .char_indices()
+            .nth(index)
+            .map(|(i, _)| i)
+            .unwrap_or(self.value.len())
+    }
+
+    // 从 index 开始删除 delete 个字符，再插入 insert，超出范围返回 false
+    pub fn splice(&mut self, index: usize, delete: usize, insert: &str) -> bool {
+        // 参数来自请求，相加可能溢出
+        let Some(end) = index.checked_add(delete).filter(|end| *end <= self.len()) else {
+            return false;
+        };
+        if delete == 0 && insert.is_empty() {
+            return true;
+        }
+        let start = self.byte_offset(index);
+        let end = self.byte_offset(end);
+        self.value.replace_range(start..end, insert);
+        self.edits.push(Splice {
+            index,
+            delete,
+            insert: insert.to_string(),
+        });
+        true
+    }
+
+    // 整个替换成新的值，只修改不同的部分
+    pub fn update(&mut self, value: &str) {
+        let old: Vec<char> = self.value.chars().collect();
+        let new: Vec<char> = value.chars().collect();
+        let prefix = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+        let suffix = old[prefix..]
+            .iter()
+            .rev()
+            .zip(new[prefix..].iter().rev())
+            .take_while(|(a, b)| a == b)
+            .count();
+        let insert: String = new[prefix..new.len() - suffix].iter().collect();
+        self.splice(prefix, old.len() - prefix - suffix, &insert);
+    }
+
+    // 当作新建的文本，移动到新的位置时需要整个重新写入
+    pub fn fresh(&self) -> Self {
+        Text::new(&self.value)
+    }
+
+    // 不是从 doc 读出来的，写入时会整个重新写入
+    pub fn is_fresh(&self) -> bool {
+        self.heads.is_none()
+    }
+}
+
+impl From<&str> for Text {
+    fn from(value: &str) -> Self {
+        Text::new(value)
+    }
+}
+
+impl From<String> for Text {
+    fn from(value: String) -> Self {
+        Text::new(&value)
+    }
+}
+
+impl PartialEq for Text {
+    fn eq(&self, other: &Self) -> bool {
+        self.value == other.value
+    }
+}
+
+impl PartialEq<&str> for Text {
+    fn eq(&self, other: &&str) -> bool {
+        self.value == *other
+    }
+}
+
+impl fmt::Debug for Text {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.value, f)
+    }
+}
+
+impl fmt::Display for Text {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.value)
+    }
+}
+
+impl Serialize for Text {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.value)
+    }
+}
+
+impl<'de> Deserialize<'de> for Text {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(Text::new(&String::deserialize(deserializer)?))
+    }
+}
+
+impl Reconcile for Text {
+    type Key<'a> = NoKey;
+
+    fn reconcile<R: Reconciler>(&self, mut reconciler: R) -> Result<(), R::Error> {
+        let Some(heads) = &self.heads else {
+            // 新建的文本不知道 doc 里原来的内容，先清掉再整个写入新的 Text
+            reconciler.str("")?;
+            return reconciler.text()?.splice(0, 0, &self.value);
+        };
+        // 从 doc 读出来的只写入编辑，和其他 peer 的编辑按字符合并
+        let mut text = reconciler.text()?;
+        if text.heads() != heads.as_slice() {
+            return Err(StaleHeads {
+                expected: heads.clone(),
+                found: text.heads().to_vec(),
+            }
+            .into());
+        }
+        for edit in &self.edits {
+            text.splice(edit.index, edit.delete as isize, &edit.insert)?;
+        }
+        Ok(())
+    }
+}
+
+impl Hydrate for Text {
+    fn hydrate_text<D: ReadDoc>(doc: &D, obj: &automerge::ObjId) -> Result<Self, HydrateError> {
+        Ok(Text {
+            value: doc.text(obj)?,
+            edits: vec![],
+            heads: Some(doc.get_heads()),
+        })
+    }
+
+    // 以前存成字符串的，下次写入时变成 Text
+    fn hydrate_string(value: &'_ str) -> Result<Self, HydrateError> {
+        Ok(Text::new(value))
+    }
+}
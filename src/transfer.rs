@@ -0,0 +1,316 @@
+use std::{
+    collections::HashMap,
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use libp2p::{
+    futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, StreamExt},
+    PeerId, StreamProtocol,
+};
+use libp2p_stream as stream;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{folder::Folder, materialize::local_path};
+
+// 按 hash 传输文件内容的协议
+pub const TRANSFER_PROTOCOL: StreamProtocol = StreamProtocol::new("/transfer");
+
+// 每块的大小，每块单独校验
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+// 一帧最大的长度，防止对方发送超大的长度
+const MAX_FRAME: usize = CHUNK_SIZE + 1024;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FetchRequest {
+    pub hash: String,
+    // 从哪里开始传，用于断点续传
+    pub offset: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FetchResponse {
+    // 文件大小，没有这个文件是 None
+    pub size: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ChunkHeader {
+    offset: u64,
+    hash: String,
+}
+
+// 本地已有的文件内容，hash => 本地路径
+#[derive(Debug, Default)]
+pub struct ContentStore {
+    files: Mutex<HashMap<String, PathBuf>>,
+}
+
+impl ContentStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&self, hash: &str, path: impl Into<PathBuf>) {
+        self.files
+            .lock()
+            .unwrap()
+            .insert(hash.to_string(), path.into());
+    }
+
+    pub fn get(&self, hash: &str) -> Option<PathBuf> {
+        self.files.lock().unwrap().get(hash).cloned()
+    }
+
+    // 登记共享文件夹里本地已经存在的文件
+    pub fn index_folder(&self, root: &Path, folder: &Folder) {
+        for (rel, id, is_dir) in folder.entries() {
+            if is_dir {
+                continue;
+            }
+            // 远端的名字可能指到共享文件夹外面，这样的文件不能提供给别人
+            let Some(path) = local_path(root, &rel) else {
+                continue;
+            };
+            if path.exists() {
+                self.insert(&folder.find_file(&id).unwrap().hash, path);
+            }
+        }
+    }
+}
+
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+fn chunk_hash(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+// 文件操作都是阻塞的，放到单独的线程里
+async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> io::Result<T> + Send + 'static,
+) -> io::Result<T> {
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(io::Error::other)?
+}
+
+// 帧：4 字节大端长度 + 内容，长度为 0 表示结束
+async fn write_frame<S: AsyncWrite + Unpin>(stream: &mut S, data: &[u8]) -> io::Result<()> {
+    stream.write_all(&(data.len() as u32).to_be_bytes()).await?;
+    stream.write_all(data).await
+}
+
+async fn read_frame<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Vec<u8>> {
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len).await?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME {
+        return Err(invalid(format!("frame too large: {len}")));
+    }
+    let mut buf = vec![0u8; len];
+    stream.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+async fn write_json<S: AsyncWrite + Unpin, T: Serialize>(
+    stream: &mut S,
+    value: &T,
+) -> io::Result<()> {
+    let data = serde_json::to_vec(value).map_err(|e| invalid(e.to_string()))?;
+    write_frame(stream, &data).await
+}
+
+async fn read_json<S: AsyncRead + Unpin, T: for<'de> Deserialize<'de>>(
+    stream: &mut S,
+) -> io::Result<T> {
+    let data = read_frame(stream).await?;
+    serde_json::from_slice(&data).map_err(|e| invalid(e.to_string()))
+}
+
+// 处理一个下载请求
+pub async fn serve<S: AsyncRead + AsyncWrite + Unpin>(
+    store: &ContentStore,
+    stream: &mut S,
+) -> io::Result<()> {
+    let request: FetchRequest = read_json(stream).await?;
+    let Some(path) = store.get(&request.hash) else {
+        write_json(stream, &FetchResponse { size: None }).await?;
+        return stream.close().await;
+    };
+    let offset = request.offset;
+    let (mut file, size, mut offset) = blocking(move || {
+        let mut file = std::fs::File::open(&path)?;
+        let size = file.metadata()?.len();
+        let offset = offset.min(size);
+        file.seek(SeekFrom::Start(offset))?;
+        Ok((file, size, offset))
+    })
+    .await?;
+    write_json(stream, &FetchResponse { size: Some(size) }).await?;
+
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    loop {
+        let n;
+        (file, buf, n) = blocking(move || {
+            let n = file.read(&mut buf)?;
+            Ok((file, buf, n))
+        })
+        .await?;
+        if n == 0 {
+            break;
+        }
+        let header = ChunkHeader {
+            offset,
+            hash: chunk_hash(&buf[..n]),
+        };
+        write_json(stream, &header).await?;
+        write_frame(stream, &buf[..n]).await?;
+        offset += n as u64;
+    }
+    write_frame(stream, &[]).await?;
+    stream.close().await
+}
+
+// 未下载完的文件
+pub fn part_path(dir: &Path, hash: &str) -> PathBuf {
+    dir.join(format!(".crdt-{hash}.part"))
+}
+
+// 从 stream 下载到 part 文件，已经下载的部分不会重新下载。
+// 对方发来的块 hash 只能发现传输出错，内容对不对按 doc 里的 hash 和大小判断：
+// 已经下载的部分和新收到的块一起算 hash，不对的话清空 part 文件
+pub async fn download<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    hash: &str,
+    size: u64,
+    part: &Path,
+) -> io::Result<()> {
+    let path = part.to_path_buf();
+    let (mut file, mut hasher, offset) = blocking(move || {
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(path)?;
+        // 只保留完整的块
+        let offset = file.metadata()?.len() / CHUNK_SIZE as u64 * CHUNK_SIZE as u64;
+        let offset = offset.min(size);
+        file.set_len(offset)?;
+        let mut hasher = Sha256::new();
+        io::copy(&mut (&file).take(offset), &mut hasher)?;
+        file.seek(SeekFrom::Start(offset))?;
+        Ok((file, hasher, offset))
+    })
+    .await?;
+
+    write_json(
+        stream,
+        &FetchRequest {
+            hash: hash.to_string(),
+            offset,
+        },
+    )
+    .await?;
+    let response: FetchResponse = read_json(stream).await?;
+    let actual = response
+        .size
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{hash} not found")))?;
+    if actual != size {
+        return Err(invalid(format!(
+            "{hash}: expected {size} bytes, peer has {actual}"
+        )));
+    }
+
+    let mut expected = offset;
+    loop {
+        let header = read_frame(stream).await?;
+        if header.is_empty() {
+            break;
+        }
+        let header: ChunkHeader =
+            serde_json::from_slice(&header).map_err(|e| invalid(e.to_string()))?;
+        let data = read_frame(stream).await?;
+        if header.offset != expected
+            || chunk_hash(&data) != header.hash
+            || expected + data.len() as u64 > size
+        {
+            return Err(invalid(format!("bad chunk at {}", header.offset)));
+        }
+        hasher.update(&data);
+        expected += data.len() as u64;
+        file = blocking(move || {
+            file.write_all(&data)?;
+            Ok(file)
+        })
+        .await?;
+    }
+    if expected != size {
+        blocking(move || file.flush()).await?;
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("{hash}: got {expected} of {size} bytes"),
+        ));
+    }
+    let actual = hex::encode(hasher.finalize());
+    if actual != hash {
+        // 不知道哪一块不对，整个重新下载
+        blocking(move || file.set_len(0)).await?;
+        return Err(invalid(format!("{hash}: hash mismatch {actual}")));
+    }
+    blocking(move || file.flush()).await
+}
+
+// 从任意一个有这个文件的 peer 下载，hash 和大小都和 doc 里的一样才放到 target
+pub async fn fetch(
+    control: &mut stream::Control,
+    peers: &[PeerId],
+    hash: &str,
+    size: u64,
+    target: &Path,
+) -> io::Result<()> {
+    let dir = target.parent().unwrap_or(Path::new(".")).to_path_buf();
+    let part = part_path(&dir, hash);
+    blocking(move || std::fs::create_dir_all(dir)).await?;
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, "no peers");
+    for peer in peers {
+        let mut stream = match control.open_stream(*peer, TRANSFER_PROTOCOL).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                last_error = io::Error::other(e.to_string());
+                continue;
+            }
+        };
+        match download(&mut stream, hash, size, &part).await {
+            Ok(()) => {
+                let target = target.to_path_buf();
+                return blocking(move || std::fs::rename(part, target)).await;
+            }
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+// 接收其他 peer 的下载请求
+pub fn listen(
+    mut control: stream::Control,
+    store: Arc<ContentStore>,
+) -> Result<tokio::task::JoinHandle<()>, stream::AlreadyRegistered> {
+    let mut incoming = control.accept(TRANSFER_PROTOCOL)?;
+    Ok(tokio::spawn(async move {
+        while let Some((peer, mut stream)) = incoming.next().await {
+            let store = store.clone();
+            tokio::spawn(async move {
+                if let Err(e) = serve(&store, &mut stream).await {
+                    eprintln!("transfer to {peer} failed: {e}");
+                }
+            });
+        }
+    }))
+}
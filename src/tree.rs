@@ -0,0 +1,464 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use automerge::{transaction::Transactable, AutoCommit};
+use autosurgeon::{hydrate, reconcile, Hydrate, ReadDoc, Reconcile};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::{
+    folder::{AssetObject, Folder, Tombstone},
+    text::Text,
+    Error,
+};
+
+// 文件夹 doc 的存储格式。条目平铺在 nodes 里，所在的文件夹和名字由 moves 决定：
+// 按 (clock, actor) 的顺序重放所有移动，移到不存在的文件夹、或者会形成环的移动跳过，
+// 新建在并发删除了的文件夹里的条目放到被删的文件夹最后所在的地方。
+// 合并之后每个 peer 重放的结果一样，并发地把 A 移到 B 里、B 移到 A 里，后一个移动不生效
+#[derive(Debug, Clone, Reconcile, Hydrate, PartialEq)]
+pub struct FolderDoc {
+    pub pub_id: Uuid,
+    pub name: String,
+    pub description: Text,
+    // pub_id -> 条目，文件夹不带子项
+    #[autosurgeon(missing = "BTreeMap::new")]
+    pub nodes: BTreeMap<String, Node>,
+    #[autosurgeon(missing = "Vec::new")]
+    pub moves: Vec<Move>,
+    #[autosurgeon(missing = "Vec::new")]
+    pub tombstones: Vec<Tombstone>,
+}
+
+// 文件夹和文件只有一个
+#[derive(Debug, Clone, Reconcile, Hydrate, PartialEq)]
+pub struct Node {
+    pub folder: Option<Folder>,
+    pub file: Option<AssetObject>,
+}
+
+// 把条目放到 parent 文件夹里，叫 name。新建和改名也是一次移动
+#[derive(Debug, Clone, Reconcile, Hydrate, PartialEq)]
+pub struct Move {
+    pub pub_id: Uuid,
+    pub parent: Uuid,
+    pub name: String,
+    // lamport 时钟，比移动时 doc 里已有的都大，相同时按 actor 排
+    pub clock: u64,
+    pub actor: String,
+}
+
+// 同一个文件夹里重名的条目。按移动的顺序，先放进来的保留名字，
+// 后面的在名字后加上移动它的 actor 的前 8 位，只改读出来的树，doc 里的名字不变
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Collision {
+    pub pub_id: Uuid,
+    // 所在的文件夹
+    pub folder: Uuid,
+    pub name: String,
+    pub resolved: String,
+}
+
+// 每个文件夹里的条目和生效的移动
+type Children<'a> = HashMap<Uuid, Vec<(&'a Move, Uuid)>>;
+
+// "a.png" + "1a2b" => "a (1a2b).png"，没有扩展名的直接加在后面
+fn suffixed(name: &str, tag: &str) -> String {
+    match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => format!("{stem} ({tag}).{ext}"),
+        _ => format!("{name} ({tag})"),
+    }
+}
+
+impl Node {
+    fn description(&self) -> Option<&Text> {
+        match (&self.folder, &self.file) {
+            (Some(folder), _) => Some(&folder.description),
+            (_, Some(file)) => Some(&file.file_path.description),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &str {
+        match (&self.folder, &self.file) {
+            (Some(folder), _) => &folder.name,
+            (_, Some(file)) => &file.file_path.name,
+            _ => "",
+        }
+    }
+
+    fn set_name(&mut self, name: &str) {
+        match (&mut self.folder, &mut self.file) {
+            (Some(folder), _) => folder.name = name.to_string(),
+            (_, Some(file)) => file.file_path.name = name.to_string(),
+            _ => {}
+        }
+    }
+
+    fn description_mut(&mut self) -> Option<&mut Text> {
+        match (&mut self.folder, &mut self.file) {
+            (Some(folder), _) => Some(&mut folder.description),
+            (_, Some(file)) => Some(&mut file.file_path.description),
+            _ => None,
+        }
+    }
+}
+
+// 树里所有的条目，(pub_id, 所在的文件夹, 条目)，路径不存，读出来时重新计算
+fn flatten(root: &Folder) -> Vec<(Uuid, Uuid, Node)> {
+    let mut items = vec![];
+    root.walk(&mut |parent, folder| {
+        if let Some(parent) = parent {
+            let node = Folder {
+                pub_id: folder.pub_id,
+                name: folder.name.clone(),
+                description: folder.description.clone(),
+                materialized_path: String::new(),
+                files: vec![],
+                folders: vec![],
+                tombstones: vec![],
+            };
+            items.push((
+                folder.pub_id,
+                *parent,
+                Node {
+                    folder: Some(node),
+                    file: None,
+                },
+            ));
+        }
+        for file in folder.files.iter() {
+            let mut node = file.clone();
+            node.file_path.materialized_path = String::new();
+            items.push((
+                file.pub_id,
+                folder.pub_id,
+                Node {
+                    folder: None,
+                    file: Some(node),
+                },
+            ));
+        }
+    });
+    items
+}
+
+impl FolderDoc {
+    pub fn new(root: &Folder, actor: &str) -> Self {
+        let empty = Folder {
+            pub_id: root.pub_id,
+            name: root.name.clone(),
+            description: root.description.clone(),
+            materialized_path: root.materialized_path.clone(),
+            files: vec![],
+            folders: vec![],
+            tombstones: vec![],
+        };
+        let mut doc = FolderDoc {
+            pub_id: root.pub_id,
+            name: root.name.clone(),
+            description: root.description.clone(),
+            nodes: BTreeMap::new(),
+            moves: vec![],
+            tombstones: vec![],
+        };
+        doc.update(&empty, root, actor);
+        doc
+    }
+
+    fn is_deleted(&self, pub_id: &Uuid) -> bool {
+        self.tombstones.iter().any(|t| &t.pub_id == pub_id)
+    }
+
+    // 读出来的删除记录。同一个条目并发删除了几次只留最早的，
+    // 已经包含在删除了的文件夹里的条目不再单独记
+    fn tombstones(&self) -> Vec<Tombstone> {
+        let mut earliest: Vec<Tombstone> = vec![];
+        for t in self.tombstones.iter() {
+            let key = |t: &Tombstone| (t.deleted.time, t.deleted.actor.clone());
+            match earliest.iter_mut().find(|e| e.pub_id == t.pub_id) {
+                Some(e) if key(t) < key(e) => *e = t.clone(),
+                Some(_) => {}
+                None => earliest.push(t.clone()),
+            }
+        }
+        let contains = |t: &Tombstone, id: &Uuid| {
+            t.folder
+                .as_ref()
+                .is_some_and(|f| f.entries().iter().any(|(_, e, _)| e == id))
+        };
+        // 并发移动之后两个删除的文件夹可能互相包含，这时两个都留下
+        let covered: HashSet<Uuid> = earliest
+            .iter()
+            .filter(|t| {
+                earliest
+                    .iter()
+                    .any(|u| contains(u, &t.pub_id) && !contains(t, &u.pub_id))
+            })
+            .map(|t| t.pub_id)
+            .collect();
+        earliest.retain(|t| !covered.contains(&t.pub_id));
+        earliest
+    }
+
+    fn node(&self, pub_id: &Uuid) -> Option<&Node> {
+        if self.is_deleted(pub_id) {
+            return None;
+        }
+        self.nodes.get(&pub_id.to_string())
+    }
+
+    fn is_folder(&self, pub_id: &Uuid) -> bool {
+        pub_id == &self.pub_id || self.node(pub_id).is_some_and(|n| n.folder.is_some())
+    }
+
+    // 重放移动，返回每个条目所在的文件夹和生效的移动
+    fn placement(&self) -> HashMap<Uuid, (Uuid, &Move)> {
+        let mut moves: Vec<&Move> = self.moves.iter().collect();
+        moves.sort_by(|a, b| (a.clock, &a.actor).cmp(&(b.clock, &b.actor)));
+        let mut placed: HashMap<Uuid, (Uuid, &Move)> = HashMap::new();
+        for m in moves {
+            if m.pub_id == self.pub_id || self.node(&m.pub_id).is_none() {
+                continue;
+            }
+            let parent = match self.node(&m.parent) {
+                _ if self.is_folder(&m.parent) => m.parent,
+                // 移到文件里的跳过
+                Some(_) => continue,
+                // 移到删除了的文件夹里的移动不生效，条目留在原来的位置
+                None if placed.contains_key(&m.pub_id) => continue,
+                // 新建在删除了的文件夹里
+                None => self.rescue(m.parent),
+            };
+            // 移到自己或者自己的子文件夹里会形成环
+            let mut ancestor = Some(parent);
+            let mut cycle = false;
+            while let Some(a) = ancestor {
+                if a == m.pub_id {
+                    cycle = true;
+                    break;
+                }
+                ancestor = placed.get(&a).map(|(p, _)| *p);
+            }
+            if !cycle {
+                placed.insert(m.pub_id, (parent, m));
+            }
+        }
+        placed
+    }
+
+    // 新建时所在的文件夹被并发删除了，放到它最后所在的文件夹里，那里也删了就继续往上找，
+    // 找不到放在根目录
+    fn rescue(&self, mut parent: Uuid) -> Uuid {
+        let mut seen = HashSet::new();
+        while !self.is_folder(&parent) && seen.insert(parent) {
+            let last = self
+                .moves
+                .iter()
+                .filter(|m| m.pub_id == parent)
+                .max_by(|a, b| (a.clock, &a.actor).cmp(&(b.clock, &b.actor)));
+            match last {
+                Some(m) => parent = m.parent,
+                None => return self.pub_id,
+            }
+        }
+        if self.is_folder(&parent) {
+            parent
+        } else {
+            self.pub_id
+        }
+    }
+
+    // 按移动记录组装成树，文件夹里的条目按生效的移动的顺序排列
+    pub fn tree(&self) -> Folder {
+        self.tree_with_collisions().0
+    }
+
+    // 组装成树，同时返回重名改了名字的条目
+    pub fn tree_with_collisions(&self) -> (Folder, Vec<Collision>) {
+        let placed = self.placement();
+        let mut children: Children = HashMap::new();
+        for (id, (parent, m)) in placed.iter() {
+            children.entry(*parent).or_default().push((m, *id));
+        }
+        for items in children.values_mut() {
+            items.sort_by(|(a, _), (b, _)| (a.clock, &a.actor).cmp(&(b.clock, &b.actor)));
+        }
+        let mut root = Folder {
+            pub_id: self.pub_id,
+            name: self.name.clone(),
+            description: self.description.clone(),
+            materialized_path: "/".to_string(),
+            files: vec![],
+            folders: vec![],
+            tombstones: self.tombstones(),
+        };
+        let mut collisions = vec![];
+        self.build(&mut root, &children, &mut collisions);
+        root.materialize();
+        (root, collisions)
+    }
+
+    fn build(&self, folder: &mut Folder, children: &Children, collisions: &mut Vec<Collision>) {
+        // 文件和文件夹也不能重名
+        let mut names = HashSet::new();
+        for (m, id) in children.get(&folder.pub_id).into_iter().flatten() {
+            let Some(node) = self.node(id) else {
+                continue;
+            };
+            let mut name = m.name.clone();
+            if names.contains(&name) {
+                let short = |s: &str| s.chars().take(8).collect::<String>();
+                let mut resolved = suffixed(&name, &short(&m.actor));
+                if names.contains(&resolved) {
+                    resolved = suffixed(&name, &short(&id.simple().to_string()));
+                }
+                collisions.push(Collision {
+                    pub_id: *id,
+                    folder: folder.pub_id,
+                    name,
+                    resolved: resolved.clone(),
+                });
+                name = resolved;
+            }
+            names.insert(name.clone());
+            if let Some(child) = &node.folder {
+                let mut child = child.clone();
+                child.name = name;
+                self.build(&mut child, children, collisions);
+                folder.folders.push(child);
+            } else if let Some(file) = &node.file {
+                let mut file = file.clone();
+                file.file_path.name = name;
+                folder.files.push(file);
+            }
+        }
+    }
+
+    // 把树的修改写回来：old 是修改前 tree() 的结果，位置或名字变了的条目追加一次移动
+    pub fn update(&mut self, old: &Folder, new: &Folder, actor: &str) {
+        self.name = new.name.clone();
+        self.description = new.description.clone();
+        // 读出来的删除记录去过重，只追加新删除的，doc 里已有的不动
+        for t in new.tombstones.iter() {
+            if !self.is_deleted(&t.pub_id) {
+                self.tombstones.push(t.clone());
+            }
+        }
+
+        let before: HashMap<Uuid, (Uuid, String)> = flatten(old)
+            .into_iter()
+            .map(|(id, parent, node)| (id, (parent, node.name().to_string())))
+            .collect();
+        let names: HashMap<Uuid, String> = self
+            .placement()
+            .into_iter()
+            .map(|(id, (_, m))| (id, m.name.clone()))
+            .collect();
+        let mut clock = self.moves.iter().map(|m| m.clock).max().unwrap_or(0);
+        let mut live = HashSet::new();
+        for (id, parent, mut node) in flatten(new) {
+            live.insert(id);
+            let key = id.to_string();
+            // 移动时条目重新写入了文本，这里不需要，保留原来的文本对象
+            if let (Some(existing), Some(text)) = (self.nodes.get(&key), node.description_mut()) {
+                if text.is_fresh() {
+                    if let Some(kept) = existing.description() {
+                        let mut kept = kept.clone();
+                        kept.update(text.as_str());
+                        *text = kept;
+                    }
+                }
+            }
+            let mut name = node.name().to_string();
+            let moved = match before.get(&id) {
+                Some((p, n)) if n == &name => {
+                    // 没改名的话读出来的名字可能加了重名的后缀，存的还是生效的移动里的名字
+                    if let Some(stored) = names.get(&id) {
+                        name = stored.clone();
+                        node.set_name(&name);
+                    }
+                    p != &parent
+                }
+                _ => true,
+            };
+            self.nodes.insert(key, node);
+            if moved {
+                clock += 1;
+                self.moves.push(Move {
+                    pub_id: id,
+                    parent,
+                    name,
+                    clock,
+                    actor: actor.to_string(),
+                });
+            }
+        }
+        for id in before.keys().filter(|id| !live.contains(id)) {
+            self.nodes.remove(&id.to_string());
+        }
+    }
+
+    // 只留下每个条目生效的那次移动，返回去掉的数量。放到别处的新建改成直接移到那里，
+    // 被删的文件夹的移动去掉之后位置不变。
+    // 只能在所有 peer 都收到之后做，之后的移动时钟都比留下的大
+    pub fn compact(&mut self) -> usize {
+        let placed = self.placement();
+        let kept: Vec<Move> = self
+            .moves
+            .iter()
+            .filter_map(|m| {
+                let (parent, effective) = placed.get(&m.pub_id)?;
+                (*effective == m).then(|| Move {
+                    parent: *parent,
+                    ..m.clone()
+                })
+            })
+            .collect();
+        let dropped = self.moves.len() - kept.len();
+        self.moves = kept;
+        dropped
+    }
+}
+
+// 新建文件夹的 doc
+pub fn new_doc(root: &Folder) -> Result<AutoCommit, Error> {
+    let mut doc = AutoCommit::new();
+    let stored = FolderDoc::new(root, &doc.get_actor().to_hex_string());
+    reconcile(&mut doc, &stored)?;
+    Ok(doc)
+}
+
+// 读出 doc 里的文件夹
+pub fn hydrate_folder<D: ReadDoc>(doc: &D) -> Result<Folder, Error> {
+    Ok(hydrate::<_, FolderDoc>(doc)?.tree())
+}
+
+// 修改 doc 里的文件夹，返回 f 的结果。f 出错时 doc 不变
+pub fn edit_folder<T>(
+    doc: &mut AutoCommit,
+    f: impl FnOnce(&mut Folder) -> Result<T, Error>,
+) -> Result<T, Error> {
+    let mut stored: FolderDoc = hydrate(doc)?;
+    let old = stored.tree();
+    let mut folder = old.clone();
+    let result = f(&mut folder)?;
+    stored.update(&old, &folder, &doc.get_actor().to_hex_string());
+    reconcile(doc, &stored)?;
+    Ok(result)
+}
+
+// 以前嵌套存储的文件夹改成平铺的，返回是否改了
+pub fn upgrade(doc: &mut AutoCommit) -> Result<bool, Error> {
+    if automerge::ReadDoc::get(doc, automerge::ROOT, "folders")?.is_none() {
+        return Ok(false);
+    }
+    // 条目都写到新的位置，文本要整个重新写入
+    let mut nested: Folder = hydrate(doc)?;
+    nested.fresh_text();
+    for key in ["files", "folders", "materialized_path"] {
+        doc.delete(automerge::ROOT, key)?;
+    }
+    let stored = FolderDoc::new(&nested, &doc.get_actor().to_hex_string());
+    reconcile(doc, &stored)?;
+    Ok(true)
+}
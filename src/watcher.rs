@@ -0,0 +1,349 @@
+use std::{
+    collections::HashSet,
+    fs, io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use notify::{
+    event::{ModifyKind, RenameMode},
+    Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher,
+};
+use sha2::{Digest, Sha256};
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+use crate::{
+    folder::{split_path, Folder},
+    CrdtOperation, Entry, EntryKind, Error, Manager, Update,
+};
+
+// 以 .crdt 开头的文件是自己生成的（回收站，下载中的文件），不需要同步
+pub const IGNORED_PREFIX: &str = ".crdt";
+
+// 监听共享文件夹的本地变更，Linux 上用 inotify
+pub struct FolderWatcher {
+    root: PathBuf,
+    // 防抖时间，这段时间内的事件合并成一批处理
+    debounce: Duration,
+    // 停止监听时 watcher 会被 drop
+    _watcher: RecommendedWatcher,
+    events: mpsc::UnboundedReceiver<notify::Result<Event>>,
+}
+
+impl FolderWatcher {
+    pub fn new(root: impl Into<PathBuf>, debounce: Duration) -> notify::Result<Self> {
+        let root = root.into();
+        let (tx, events) = mpsc::unbounded_channel();
+        let mut watcher = notify::recommended_watcher(move |event| {
+            let _ = tx.send(event);
+        })?;
+        watcher.watch(&root, RecursiveMode::Recursive)?;
+        Ok(FolderWatcher {
+            root,
+            debounce,
+            _watcher: watcher,
+            events,
+        })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    // 等待下一批事件，第一个事件之后 debounce 时间内没有新事件就返回
+    pub async fn next_batch(&mut self) -> Option<Vec<Event>> {
+        let mut batch = vec![];
+        let first = self.events.recv().await?;
+        batch.extend(first.ok());
+        while let Ok(Some(event)) = tokio::time::timeout(self.debounce, self.events.recv()).await {
+            match event {
+                Ok(event) => batch.push(event),
+                Err(e) => eprintln!("watch error: {e}"),
+            }
+        }
+        Some(batch)
+    }
+
+    // 等待下一批事件，转换成操作之后合成一个 change 提交到共享文件夹的 doc。
+    // 有操作提交失败时返回第一个错误，其他的操作已经提交
+    pub async fn sync(
+        &mut self,
+        manager: &Manager,
+        id: &Uuid,
+    ) -> Option<Result<Vec<CrdtOperation>, Error>> {
+        let events = self.next_batch().await?;
+        let result = manager.folder(id).and_then(|mut folder| {
+            let ops = translate(&self.root, &mut folder, &events);
+            if ops.is_empty() {
+                return Ok(ops);
+            }
+            match manager.update_all(id, &ops)?.into_iter().next() {
+                Some(e) => Err(e),
+                None => Ok(ops),
+            }
+        });
+        Some(result)
+    }
+}
+
+// 本地路径转换成相对共享文件夹的路径，共享文件夹外和忽略的文件返回 None
+pub fn relative(root: &Path, path: &Path) -> Option<String> {
+    let rel = path.strip_prefix(root).ok()?;
+    let mut parts = vec![];
+    for part in rel.components() {
+        let part = part.as_os_str().to_str()?;
+        if part.starts_with(IGNORED_PREFIX) {
+            return None;
+        }
+        parts.push(part);
+    }
+    if parts.is_empty() {
+        return None;
+    }
+    Some(parts.join("/"))
+}
+
+// 计算文件内容的 hash
+pub fn hash_file(path: &Path) -> io::Result<(String, u64)> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let size = io::copy(&mut file, &mut hasher)?;
+    Ok((hex::encode(hasher.finalize()), size))
+}
+
+pub fn mime_type(path: &Path) -> &'static str {
+    let ext = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase());
+    match ext.as_deref() {
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("mp4") => "video/mp4",
+        Some("mp3") => "audio/mpeg",
+        Some("txt" | "md") => "text/plain",
+        Some("json") => "application/json",
+        _ => "application/octet-stream",
+    }
+}
+
+fn lookup(folder: &Folder, rel: &str) -> Option<(Uuid, bool)> {
+    folder
+        .entries()
+        .into_iter()
+        .find(|(path, _, _)| path == rel)
+        .map(|(_, id, is_dir)| (id, is_dir))
+}
+
+// 转换一批事件时的状态
+struct Translator<'a> {
+    root: &'a Path,
+    folder: &'a mut Folder,
+    ops: Vec<CrdtOperation>,
+    // 这批事件里改名的目标路径，扫描新文件夹时跳过，交给改名事件处理
+    rename_targets: HashSet<PathBuf>,
+}
+
+impl Translator<'_> {
+    // 应用成功的操作才记录下来
+    fn push(&mut self, op: CrdtOperation) {
+        match self.folder.apply(&op) {
+            Ok(()) => self.ops.push(op),
+            Err(e) => eprintln!("skip {op:?}: {e}"),
+        }
+    }
+
+    // 新建或更新磁盘上的条目，文件夹会递归扫描里面的内容
+    fn upsert(&mut self, path: &Path) {
+        let Some(rel) = relative(self.root, path) else {
+            return;
+        };
+        let Ok(meta) = fs::metadata(path) else {
+            return;
+        };
+        match lookup(self.folder, &rel) {
+            Some((id, false)) if meta.is_file() => {
+                let Ok((hash, size)) = hash_file(path) else {
+                    return;
+                };
+                let file = self.folder.find_file(&id).unwrap();
+                if file.hash != hash || file.size != size {
+                    self.push(CrdtOperation::Update(id, Update::Content { hash, size }));
+                }
+            }
+            Some((_, true)) if meta.is_dir() => {}
+            existing => {
+                // 类型变了，先删掉旧的
+                if let Some((id, _)) = existing {
+                    self.push(CrdtOperation::Delete(id));
+                }
+                let kind = if meta.is_dir() {
+                    EntryKind::Folder
+                } else {
+                    let Ok((hash, size)) = hash_file(path) else {
+                        return;
+                    };
+                    EntryKind::File {
+                        hash,
+                        size,
+                        mime_type: mime_type(path).to_string(),
+                    }
+                };
+                self.push(CrdtOperation::Create(Entry {
+                    pub_id: Uuid::new_v4(),
+                    path: rel,
+                    kind,
+                }));
+            }
+        }
+        if meta.is_dir() {
+            if let Ok(children) = fs::read_dir(path) {
+                let mut children: Vec<_> = children
+                    .flatten()
+                    .map(|c| c.path())
+                    .filter(|c| !self.rename_targets.contains(c))
+                    .collect();
+                children.sort();
+                for child in children {
+                    self.upsert(&child);
+                }
+            }
+        }
+    }
+
+    fn remove(&mut self, path: &Path) {
+        let Some(rel) = relative(self.root, path) else {
+            return;
+        };
+        if let Some((id, _)) = lookup(self.folder, &rel) {
+            self.push(CrdtOperation::Delete(id));
+        }
+    }
+
+    fn rename(&mut self, from: &Path, to: &Path) {
+        let (Some(from_rel), Some(to_rel)) = (relative(self.root, from), relative(self.root, to))
+        else {
+            // 从忽略的文件改名过来，或者改名成忽略的文件
+            self.remove(from);
+            self.upsert(to);
+            return;
+        };
+        let Some((id, _)) = lookup(self.folder, &from_rel) else {
+            self.upsert(to);
+            return;
+        };
+        // 目标位置已经有条目，会被覆盖
+        if let Some((existing, _)) = lookup(self.folder, &to_rel) {
+            self.push(CrdtOperation::Delete(existing));
+        }
+        let (from_parent, _) = split_path(&from_rel);
+        let (to_parent, to_name) = split_path(&to_rel);
+        let update = if from_parent == to_parent {
+            Update::Name(to_name.to_string())
+        } else {
+            Update::Path(to_rel.clone())
+        };
+        self.push(CrdtOperation::Update(id, update));
+    }
+}
+
+// 把一批文件系统事件转换成操作，同时应用到 folder 上
+pub fn translate(root: &Path, folder: &mut Folder, events: &[Event]) -> Vec<CrdtOperation> {
+    let both = |e: &&Event| {
+        matches!(
+            e.kind,
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both))
+        )
+    };
+    // inotify 的改名会同时发出 From，To 和 Both 三个事件，有 Both 的时候只处理 Both
+    let renamed: HashSet<usize> = events
+        .iter()
+        .filter(both)
+        .filter_map(|e| e.tracker())
+        .collect();
+    let rename_targets = events
+        .iter()
+        .filter(both)
+        .filter_map(|e| e.paths.get(1).cloned())
+        .collect();
+    let mut t = Translator {
+        root,
+        folder,
+        ops: vec![],
+        rename_targets,
+    };
+
+    for event in events {
+        match event.kind {
+            EventKind::Create(_) | EventKind::Modify(ModifyKind::Data(_)) => {
+                for path in event.paths.iter() {
+                    t.upsert(path);
+                }
+            }
+            EventKind::Remove(_) => {
+                for path in event.paths.iter() {
+                    t.remove(path);
+                }
+            }
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => {
+                if let [from, to] = &event.paths[..] {
+                    t.rename(from, to);
+                }
+            }
+            // 已经由 Both 处理的改名，没有 tracker 的 From 是被移动的文件夹自己发出的
+            EventKind::Modify(ModifyKind::Name(RenameMode::From | RenameMode::To))
+                if event.tracker().is_none_or(|t| renamed.contains(&t)) => {}
+            // 移动到共享文件夹外，算是删除
+            EventKind::Modify(ModifyKind::Name(RenameMode::From)) => {
+                for path in event.paths.iter() {
+                    t.remove(path);
+                }
+            }
+            // 从共享文件夹外移进来，算是新建
+            EventKind::Modify(ModifyKind::Name(RenameMode::To)) => {
+                for path in event.paths.iter() {
+                    t.upsert(path);
+                }
+            }
+            EventKind::Modify(ModifyKind::Name(_)) | EventKind::Modify(ModifyKind::Any) => {
+                for path in event.paths.iter() {
+                    if path.exists() {
+                        t.upsert(path);
+                    } else {
+                        t.remove(path);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    t.ops
+}
+
+// 启动时扫描共享文件夹，prune 为 true 时删除磁盘上已经不存在的条目
+// 注意：还没下载到本地的远端文件也会被当成已删除
+pub fn scan(root: &Path, folder: &mut Folder, prune: bool) -> io::Result<Vec<CrdtOperation>> {
+    let mut children: Vec<_> = fs::read_dir(root)?.flatten().map(|c| c.path()).collect();
+    children.sort();
+    let mut t = Translator {
+        root,
+        folder,
+        ops: vec![],
+        rename_targets: HashSet::new(),
+    };
+    for child in children {
+        t.upsert(&child);
+    }
+    if prune {
+        for (rel, id, _) in t.folder.entries() {
+            // 父文件夹已经删掉的条目会跟着一起删掉
+            let exists = t.folder.find_folder(&id).is_some() || t.folder.find_file(&id).is_some();
+            if exists && !root.join(&rel).exists() {
+                t.push(CrdtOperation::Delete(id));
+            }
+        }
+    }
+    Ok(t.ops)
+}